@@ -52,6 +52,7 @@ fn main() {
 
     copy_files!("client/target/deploy", "res/static",
                 "index.html",
+                "login.html",
                 "bug-graph-client.js",
                 "bug-graph-client.wasm",
                 "bulma.min.css");
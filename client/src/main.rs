@@ -16,17 +16,17 @@
 #![recursion_limit="128"]
 
 extern crate failure;
-extern crate stdweb;
 #[macro_use]
-extern crate serde_derive;
+extern crate stdweb;
 #[macro_use]
 extern crate yew;
 extern crate uuid;
+extern crate bug_graph_protocol as protocol;
 
-mod protocol;
 mod search;
 
 use std::fmt;
+use std::collections::HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -34,17 +34,48 @@ use failure::Error;
 use stdweb::web;
 use yew::prelude::*;
 use yew::virtual_dom::{VNode, VList};
-use yew::format::Json;
+use yew::format::{Json, Text, Binary};
 use yew::services::websocket::{WebSocketService, WebSocketTask, WebSocketStatus};
 use uuid::Uuid;
 
-use protocol::{Notice, ClientServer, ServerClient, ResultMatrix, ResultInMatrix};
+use protocol::{PROTOCOL_VERSION, Notice, ClientServer, ServerClient, ClientMsg, ServerMsg,
+               RequestId, ErrorKind, Role, Topic, Event, ResultMatrix, ResultInMatrix, FlakyTest,
+               RevisionDiff, VertInfo, HistoryEntry, FailureCluster, AuditEntry, AuditAction,
+               LogLevel, LogRecord};
+use protocol::wire::{Wire, Encoding};
 use search::Search;
 
+/// The encoding asked for unless the page's URL says otherwise, e.g.
+/// ?encoding=json&deflate=0 makes the messages readable in the browser tools.
+const PREFERRED_WIRE: Wire = Wire { encoding: Encoding::MsgPack, deflate: true };
+/// How many of the server's log records to show
+const JOURNAL_LEN: usize = 1000;
+
+/// A websocket message before it is decoded
+struct Frame(Result<Vec<u8>, Error>);
+
+impl From<Text> for Frame {
+    fn from(text: Text) -> Frame {
+        Frame(text.map(|t| t.into_bytes()))
+    }
+}
+
+impl From<Binary> for Frame {
+    fn from(bin: Binary) -> Frame {
+        Frame(bin)
+    }
+}
+
 #[derive(Clone,Copy,PartialEq,Eq)]
 enum AppTab {
     Explore,
     Compare,
+    Flaky,
+    Regressions,
+    History,
+    Clusters,
+    Audit,
+    Log,
 }
 
 impl fmt::Display for AppTab {
@@ -54,6 +85,55 @@ impl fmt::Display for AppTab {
         match self {
             Explore => write!(f, "Explore"),
             Compare => write!(f, "Compare"),
+            Flaky => write!(f, "Flaky"),
+            Regressions => write!(f, "Regressions"),
+            History => write!(f, "History"),
+            Clusters => write!(f, "Clusters"),
+            Audit => write!(f, "Audit"),
+            Log => write!(f, "Server log"),
+        }
+    }
+}
+
+/// Where a reply is shown, only the reply to the latest request for each
+/// slot is used.
+#[derive(Clone,Copy,PartialEq,Eq,Hash)]
+enum Slot {
+    SetList,
+    CmpSearch,
+    DiffSearch,
+    Matrix,
+    Flaky,
+    Diff,
+    History,
+    Clusters,
+    Audit,
+    Journal,
+    Triage,
+    Hello,
+    Subscription,
+    /// Changes only admins may make, like deleting a run
+    Admin,
+}
+
+impl<'a> From<&'a ClientServer> for Slot {
+    fn from(query: &'a ClientServer) -> Slot {
+        match query {
+            ClientServer::SetQuery(_) => Slot::SetList,
+            ClientServer::Search(_) => Slot::CmpSearch,
+            ClientServer::ResultMatrix(_) => Slot::Matrix,
+            ClientServer::FlakyTests { .. } => Slot::Flaky,
+            ClientServer::RevisionDiff(..) => Slot::Diff,
+            ClientServer::TestHistory(_) => Slot::History,
+            ClientServer::FailureClusters(_) => Slot::Clusters,
+            ClientServer::AuditTrail => Slot::Audit,
+            ClientServer::GetJournal { .. } => Slot::Journal,
+            ClientServer::Hello { .. } => Slot::Hello,
+            ClientServer::Subscribe(_) | ClientServer::Unsubscribe(_) => Slot::Subscription,
+            ClientServer::LinkBug { .. }
+            | ClientServer::UnlinkBug { .. }
+            | ClientServer::RevertAutoLinks { .. } => Slot::Triage,
+            ClientServer::DeleteRun(_) => Slot::Admin,
         }
     }
 }
@@ -62,21 +142,52 @@ struct Model {
     link: ComponentLink<Model>,
     wss: WebSocketService,
     ws: Option<WebSocketTask>,
+    wire: Wire,
     notices: Vec<Notice>,
+    /// What the server says we may do, only the permitted controls are shown
+    role: Role,
+    next_id: RequestId,
+    latest: HashMap<Slot, RequestId>,
+    /// Descriptions of the requests which have not been answered
+    pending: HashMap<RequestId, String>,
     sets: Option<Vec<(String, Uuid)>>,
     tab: AppTab,
     cmp_term: Rc<RefCell<String>>,
     cmp_completions: Rc<Vec<(String, Uuid)>>,
-    cmp_matrix: Option<ResultMatrix>,
-    cmp_selected: Uuid,
+    cmp_matrix: Option<(Uuid, ResultMatrix)>,
+    /// The set shown in the matrix, which we are subscribed to
+    cmp_selected: Option<Uuid>,
+    flaky_tests: Option<Vec<FlakyTest>>,
+    diff_term: Rc<RefCell<String>>,
+    diff_completions: Rc<Vec<(String, Uuid)>>,
+    diff_from: Option<Uuid>,
+    diff_to: Option<Uuid>,
+    diff: Option<RevisionDiff>,
+    history: Option<Vec<HistoryEntry>>,
+    clusters: Option<Vec<FailureCluster>>,
+    bug_term: String,
+    audit: Option<Vec<AuditEntry>>,
+    /// The directory, file and result counts of the latest import
+    import: Option<(String, u32, u32, bool)>,
+    /// The server's log, None until the log tab is first opened
+    journal: Option<Vec<LogRecord>>,
+    journal_level: LogLevel,
 }
 
 enum Msg {
-    Recv(Result<ServerClient, Error>),
+    Recv(Result<Vec<u8>, Error>),
     Stat(WebSocketStatus),
     Send(ClientServer),
+    SendFor(Slot, ClientServer),
     DelNotice(usize),
     ToTab(AppTab),
+    DiffFrom(Uuid),
+    DiffTo(Uuid),
+    BugTerm(String),
+    LinkCluster(usize),
+    /// Delete a run, once the user has confirmed it
+    DeleteRun(String, Uuid),
+    JournalLevel(LogLevel),
 }
 
 impl Component for Model
@@ -85,8 +196,9 @@ impl Component for Model
     type Properties = ();
 
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
-        let url = ws_url();
-        let cb = link.send_back(|Json(msg)| Msg::Recv(msg));
+        let wire = page_wire();
+        let url = ws_url(&wire);
+        let cb = link.send_back(|Frame(data)| Msg::Recv(data));
         let evt = link.send_back(|status| Msg::Stat(status));
         let mut wss = WebSocketService::new();
         let ws = wss.connect(&url, cb, evt);
@@ -95,13 +207,31 @@ impl Component for Model
             link,
             wss,
             ws: Some(ws),
+            wire,
             notices: Vec::default(),
+            role: Role::Viewer,
+            next_id: 0,
+            latest: HashMap::default(),
+            pending: HashMap::default(),
             sets: None,
             tab: AppTab::Explore,
             cmp_term: Rc::new(RefCell::new("".to_string())),
             cmp_completions: Rc::new(Vec::default()),
-            cmp_selected: Uuid::default(),
             cmp_matrix: None,
+            cmp_selected: None,
+            flaky_tests: None,
+            diff_term: Rc::new(RefCell::new("".to_string())),
+            diff_completions: Rc::new(Vec::default()),
+            diff_from: None,
+            diff_to: None,
+            diff: None,
+            history: None,
+            clusters: None,
+            bug_term: String::new(),
+            audit: None,
+            import: None,
+            journal: None,
+            journal_level: LogLevel::Info,
         }
     }
 
@@ -110,6 +240,10 @@ impl Component for Model
             Msg::Stat(s) => { match s {
                 WebSocketStatus::Opened => {
                     self.notices.push(Notice::succ("Opened websocket"));
+                    self.send(Slot::Hello, ClientServer::Hello { version: PROTOCOL_VERSION });
+                    self.send(Slot::Subscription, ClientServer::Subscribe(Topic::ImportProgress));
+                    self.send(Slot::Subscription, ClientServer::Subscribe(Topic::BugLinks));
+                    self.send(Slot::Subscription, ClientServer::Subscribe(Topic::Restarts));
                 },
                 WebSocketStatus::Closed => {
                     self.notices.push(Notice::info("Closed websocket"));
@@ -119,38 +253,70 @@ impl Component for Model
                     self.notices.push(Notice::error("Error on websocket"));
                 },
             } true },
-            Msg::Recv(res) => match res {
-                Ok(ServerClient::Notify(n)) => { self.notices.push(n); true },
-                Ok(ServerClient::SetList(l)) => { self.sets = Some(l); true },
-                Ok(ServerClient::Search(t, r)) => if t == *self.cmp_term.borrow() {
-                    self.cmp_completions = Rc::new(r);
-                    true
-                } else {
-                    false
-                },
-                Ok(ServerClient::ResultMatrix(uuid, m)) => if uuid == self.cmp_selected {
-                    self.cmp_matrix = Some(m);
-                    true
-                } else {
-                    false
+            Msg::Recv(res) => match res.and_then(|bin| Ok(self.wire.decode::<ServerMsg>(&bin)?)) {
+                Ok(ServerMsg { id, reply }) => {
+                    let request = id.and_then(|id| self.pending.remove(&id));
+                    self.handle_reply(id, request, reply)
                 },
                 Err(e) => {
-                    self.notices.push(
-                        Notice::error(format!("Could not parse message from server: {}", e))
-                    );
+                    self.notices.push(Notice::error(format!(
+                        "Could not parse message from server, try reloading the page: {}", e
+                    )));
                     true
                 },
             },
             Msg::Send(m) => {
-                if let ClientServer::ResultMatrix(t) = m {
-                    self.cmp_selected = t;
+                let slot = Slot::from(&m);
+
+                if let ClientServer::ResultMatrix(set) = m {
+                    self.watch_matrix(set);
                 }
-                self.ws.as_mut().unwrap().send_binary(Json(&m));
+                self.update(Msg::SendFor(slot, m))
+            },
+            Msg::SendFor(slot, m) => {
                 self.notices.push(Notice::info(format!("Requesting set {}", &m)));
+                self.send(slot, m);
                 true
             },
             Msg::DelNotice(i) => { self.notices.remove(i); true },
-            Msg::ToTab(t) => { self.tab = t; true },
+            Msg::ToTab(t) => {
+                if t == AppTab::Log && self.journal.is_none() {
+                    self.journal = Some(Vec::new());
+                    self.request_journal();
+                    self.send(Slot::Subscription, ClientServer::Subscribe(Topic::Journal));
+                }
+                self.tab = t;
+                true
+            },
+            Msg::DiffFrom(uuid) => { self.diff_from = Some(uuid); self.request_diff() },
+            Msg::DiffTo(uuid) => { self.diff_to = Some(uuid); self.request_diff() },
+            Msg::BugTerm(bug) => { self.bug_term = bug; false },
+            Msg::LinkCluster(i) => {
+                let results = self.clusters.as_ref()
+                    .map(|c| c[i].results.iter().map(|r| r.result).collect())
+                    .unwrap_or_default();
+                let bug = self.bug_term.trim().to_string();
+
+                if bug.is_empty() {
+                    self.notices.push(Notice::warn("Type in a bug to link to first"));
+                    true
+                } else {
+                    self.update(Msg::Send(ClientServer::LinkBug { bug, results }))
+                }
+            },
+            Msg::DeleteRun(name, run) => {
+                if confirm(&format!("Delete {} and all its results?", name)) {
+                    self.update(Msg::Send(ClientServer::DeleteRun(run)))
+                } else {
+                    false
+                }
+            },
+            Msg::JournalLevel(level) => {
+                self.journal_level = level;
+                self.journal = Some(Vec::new());
+                self.request_journal();
+                true
+            },
         }
     }
 }
@@ -168,6 +334,8 @@ impl Renderable<Model> for Model
                 <h2 class="subtitle",>{
                     "Connecting bugs and test results"
                 }</h2>
+                <a class=("button","is-small","is-primary","is-inverted","is-outlined"),
+                   href="/logout",>{ "Log out" }</a>
               </div>
             </div>
             <div class="hero-foot",>
@@ -207,10 +375,86 @@ impl Renderable<Model> for Model
                        <div class=("container","is-fluid"),>{ self.render_matrix() }</div>
                        </>
                    },
+                   AppTab::Flaky => html! {
+                       <>
+                       <div class="container",>
+                        <Search: term=Rc::clone(&self.cmp_term),
+                                 completions=Some(Rc::clone(&self.cmp_completions)),
+                                 onneed_more=|t| Msg::Send(ClientServer::Search(t)),
+                                 onmatch=|t| Msg::Send(ClientServer::FlakyTests { scope: t }),/>
+                       </div>
+                       <div class=("container","is-fluid"),>{ self.render_flaky() }</div>
+                       </>
+                   },
+                   AppTab::Regressions => html! {
+                       <>
+                       <div class="columns",>
+                        <div class="column",>
+                         <Search: term=Rc::clone(&self.cmp_term),
+                                  completions=Some(Rc::clone(&self.cmp_completions)),
+                                  onneed_more=|t| Msg::Send(ClientServer::Search(t)),
+                                  onmatch=|t| Msg::DiffFrom(t),/>
+                        </div>
+                        <div class="column",>
+                         <Search: term=Rc::clone(&self.diff_term),
+                                  completions=Some(Rc::clone(&self.diff_completions)),
+                                  onneed_more=|t| {
+                                      Msg::SendFor(Slot::DiffSearch, ClientServer::Search(t))
+                                  },
+                                  onmatch=|t| Msg::DiffTo(t),/>
+                        </div>
+                       </div>
+                       <div class=("container","is-fluid"),>{ self.render_diff() }</div>
+                       </>
+                   },
+                   AppTab::History => html! {
+                       <>
+                       <div class="container",>
+                        <Search: term=Rc::clone(&self.cmp_term),
+                                 completions=Some(Rc::clone(&self.cmp_completions)),
+                                 onneed_more=|t| Msg::Send(ClientServer::Search(t)),
+                                 onmatch=|t| Msg::Send(ClientServer::TestHistory(t)),/>
+                       </div>
+                       <div class=("container","is-fluid"),>{ self.render_history() }</div>
+                       </>
+                   },
+                   AppTab::Clusters => html! {
+                       <>
+                       <div class="container",>
+                        <Search: term=Rc::clone(&self.cmp_term),
+                                 completions=Some(Rc::clone(&self.cmp_completions)),
+                                 onneed_more=|t| Msg::Send(ClientServer::Search(t)),
+                                 onmatch=|t| Msg::Send(ClientServer::FailureClusters(t)),/>
+                       </div>
+                       { self.render_bug_input() }
+                       <div class=("container","is-fluid"),>{ self.render_clusters() }</div>
+                       </>
+                   },
+                   AppTab::Audit => html! {
+                       <>
+                       <div class="buttons",>
+                        <button class="button",
+                                onclick=|_| Msg::Send(ClientServer::AuditTrail),>{
+                            "Refresh"
+                        }</button>
+                        { self.render_revert() }
+                       </div>
+                       <div class=("container","is-fluid"),>{ self.render_audit() }</div>
+                       </>
+                   },
+                   AppTab::Log => html! {
+                       <>
+                       <div class="buttons",>{
+                           for [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug]
+                               .iter().map(|l| self.render_level_button(*l))
+                       }</div>
+                       <div class=("container","is-fluid"),>{ self.render_journal() }</div>
+                       </>
+                   },
                }
            }</div>
           </section>
-          <footer class="footer",><div class="container",>{
+          <footer class="footer",><div class="container",>{ self.render_import() }{
              for self.notices.iter().enumerate().map(|(i, m)| render_notice(i, m))
           }</div></footer>
         </>}
@@ -219,6 +463,167 @@ impl Renderable<Model> for Model
 
 impl Model {
 
+    /// Send a request without telling the user
+    fn send(&mut self, slot: Slot, query: ClientServer) {
+        let id = self.next_id;
+
+        self.next_id = self.next_id.wrapping_add(1);
+        self.latest.insert(slot, id);
+        self.pending.insert(id, query.to_string());
+        self.ws.as_mut().unwrap().send_binary(Json(&ClientMsg { id, query }));
+    }
+
+    /// Subscribe to new results in the matrix's set, instead of the last one
+    fn watch_matrix(&mut self, set: Uuid) {
+        if self.cmp_selected == Some(set) {
+            return;
+        }
+
+        if let Some(old) = self.cmp_selected.take() {
+            self.send(Slot::Subscription, ClientServer::Unsubscribe(Topic::SetResults(old)));
+        }
+        self.send(Slot::Subscription, ClientServer::Subscribe(Topic::SetResults(set)));
+        self.cmp_selected = Some(set);
+    }
+
+    fn handle_event(&mut self, event: Event) -> ShouldRender {
+        match event {
+            Event::NewResults { set, .. } => {
+                if self.cmp_selected == Some(set) {
+                    self.send(Slot::Matrix, ClientServer::ResultMatrix(set));
+                }
+                false
+            },
+            Event::ImportProgress { dir, files, results, done } => {
+                self.import = Some((dir, files, results, done));
+                true
+            },
+            Event::BugLink(entry) => match self.audit {
+                Some(ref mut audit) => { audit.push(entry); true },
+                None => false,
+            },
+            Event::Restart { actor, reason } => {
+                self.notices.push(Notice::error(format!(
+                    "The server's {} crashed and was restarted ({}), try again", actor, reason
+                )));
+                true
+            },
+            Event::Log(record) => {
+                let level = self.journal_level;
+
+                match self.journal {
+                    Some(ref mut journal) if record.level <= level => {
+                        if journal.last().map_or(true, |last| last.seq < record.seq) {
+                            journal.push(record);
+                        }
+                        let excess = journal.len().saturating_sub(JOURNAL_LEN);
+                        journal.drain(.. excess);
+                        self.tab == AppTab::Log
+                    },
+                    _ => false,
+                }
+            },
+        }
+    }
+
+    /// Get the kept log records, those which arrive later are streamed
+    fn request_journal(&mut self) {
+        let level = self.journal_level;
+
+        self.send(Slot::Journal, ClientServer::GetJournal { since: None, level });
+    }
+
+    fn is_latest(&self, slot: Slot, id: Option<RequestId>) -> bool {
+        id.is_some() && self.latest.get(&slot) == id.as_ref()
+    }
+
+    fn handle_reply(&mut self, id: Option<RequestId>, request: Option<String>,
+                    reply: ServerClient) -> ShouldRender {
+        match reply {
+            ServerClient::Hello { version, user, role } => {
+                self.notices.push(Notice::info(format!("Signed in as {} ({})", user, role)));
+                self.role = role;
+                if version != PROTOCOL_VERSION {
+                    self.notices.push(Notice::error(format!(
+                        "The server speaks protocol version {} but this page speaks version {}, \
+                         please reload the page", version, PROTOCOL_VERSION
+                    )));
+                }
+                true
+            },
+            ServerClient::Notify(n) => { self.notices.push(n); true },
+            ServerClient::Event(e) => self.handle_event(e),
+            // Only answered so that the requests are not left pending
+            ServerClient::Subscribed(_) | ServerClient::Unsubscribed(_) => false,
+            ServerClient::SetList(l) => { self.sets = Some(l); true },
+            ServerClient::Search(_, r) => if self.is_latest(Slot::CmpSearch, id) {
+                self.cmp_completions = Rc::new(r);
+                true
+            } else if self.is_latest(Slot::DiffSearch, id) {
+                self.diff_completions = Rc::new(r);
+                true
+            } else {
+                false
+            },
+            ServerClient::ResultMatrix(set, m) => if self.is_latest(Slot::Matrix, id) {
+                self.cmp_matrix = Some((set, m));
+                true
+            } else {
+                false
+            },
+            ServerClient::FlakyTests { tests, .. } => if self.is_latest(Slot::Flaky, id) {
+                self.flaky_tests = Some(tests);
+                true
+            } else {
+                false
+            },
+            ServerClient::TestHistory(_, h) => if self.is_latest(Slot::History, id) {
+                self.history = Some(h);
+                true
+            } else {
+                false
+            },
+            ServerClient::FailureClusters(_, c) => if self.is_latest(Slot::Clusters, id) {
+                self.clusters = Some(c);
+                true
+            } else {
+                false
+            },
+            ServerClient::AuditTrail(a) => { self.audit = Some(a); true },
+            ServerClient::Journal(mut records) => if self.is_latest(Slot::Journal, id) {
+                // Keep anything streamed to us after the server answered
+                let last = records.last().map(|r| r.seq);
+                if let Some(ref streamed) = self.journal {
+                    records.extend(streamed.iter()
+                                   .filter(|r| last.map_or(true, |l| r.seq > l))
+                                   .cloned());
+                }
+                self.journal = Some(records);
+                true
+            } else {
+                false
+            },
+            ServerClient::RevisionDiff(_, _, d) => if self.is_latest(Slot::Diff, id) {
+                self.diff = Some(d);
+                true
+            } else {
+                false
+            },
+            ServerClient::Error { kind, msg, .. } => {
+                let msg = match request {
+                    Some(request) => format!("{} failed: {}", request, msg),
+                    None => msg,
+                };
+
+                self.notices.push(match kind {
+                    ErrorKind::BadRequest | ErrorKind::Forbidden => Notice::warn(msg),
+                    ErrorKind::Internal => Notice::error(msg),
+                });
+                true
+            },
+        }
+    }
+
     fn render_matrix_cells(&self, results: &[ResultInMatrix], test_count: usize) -> Html<Model> {
         let mut html = VList::new();
         let mut i = 0;
@@ -226,7 +631,11 @@ impl Model {
         for result in results {
             let td = if result.test_case == i {
                 let score = format!("{}/{}", result.passes, result.fails);
-                if result.fails == 0 {
+                if result.flaky {
+                    html! {
+                        <td class="has-background-info", title="Flaky",>{ score }</td>
+                    }
+                } else if result.fails == 0 {
                     html! {
                         <td class="has-background-success",>{ score }</td>
                     }
@@ -275,8 +684,15 @@ impl Model {
     }
 
     fn render_matrix(&self) -> Html<Model> {
-        if let Some(ref matrix) = self.cmp_matrix {
+        if let Some((ref set, ref matrix)) = self.cmp_matrix {
+            let url = |format: &str| format!("/api/v1/matrix/{}/{}", set, format);
+
             html! {
+                <>
+                <div class="buttons",>
+                 <a class="button", href=url("csv"), download="",>{ "Export CSV" }</a>
+                 <a class="button", href=url("xlsx"), download="",>{ "Export XLSX" }</a>
+                </div>
                 <table class=("table","is-narrow"),>
                  <thead><tr>
                   <th>{ "" }</th>
@@ -290,19 +706,278 @@ impl Model {
                       self.render_matrix_rows(matrix)
                   }
                 </table>
+                </>
             }
         } else {
             html! { <p>{ "Type in a fully qualified test name in the box above" }</p> }
         }
     }
 
+    fn render_flaky(&self) -> Html<Model> {
+        if let Some(ref tests) = self.flaky_tests {
+            html! {
+                <table class=("table","is-narrow"),>
+                 <thead><tr>
+                  <th>{ "Test" }</th>
+                  <th><abbr title="Status changes per run",>{ "Score" }</abbr></th>
+                  <th><abbr title="Status changes/runs in each environment",>{
+                      "Environments"
+                  }</abbr></th>
+                 </tr></thead>
+                 <tbody>{
+                     for tests.iter().map(|t| html! {
+                         <tr>
+                          <td>{ &t.test.0 }</td>
+                          <td>{ format!("{:.0}%", t.score * 100.0) }</td>
+                          <td>{
+                              for t.envs.iter().map(|e| html! {
+                                  <p>{ format!("{}: {}/{}", e.env, e.flips, e.runs) }</p>
+                              })
+                          }</td>
+                         </tr>
+                     })
+                 }</tbody>
+                </table>
+            }
+        } else {
+            html! { <p>{ "Type in a test or set name to find its flakiest tests" }</p> }
+        }
+    }
+
+    fn request_diff(&mut self) -> ShouldRender {
+        if let (Some(from), Some(to)) = (self.diff_from, self.diff_to) {
+            self.update(Msg::Send(ClientServer::RevisionDiff(from, to)))
+        } else {
+            false
+        }
+    }
+
+    fn render_diff(&self) -> Html<Model> {
+        if let Some(ref diff) = self.diff {
+            html! {
+                <div class="columns",>
+                 { render_test_list("Newly failing", "has-text-danger", &diff.newly_failing) }
+                 { render_test_list("Newly passing", "has-text-success", &diff.newly_passing) }
+                 { render_test_list("Still failing", "has-text-warning", &diff.still_failing) }
+                 { render_test_list("Missing", "has-text-grey", &diff.missing) }
+                </div>
+            }
+        } else {
+            html! { <p>{ "Choose an older and a newer product revision above" }</p> }
+        }
+    }
+
+    fn render_history(&self) -> Html<Model> {
+        if let Some(ref history) = self.history {
+            html! {
+                <>
+                <p class="sparkline",>{
+                    for history.iter().map(|h| {
+                        let class = if h.passed {
+                            "has-background-success"
+                        } else {
+                            "has-background-danger"
+                        };
+                        let title = format!("{} {}", format_time(h.timestamp), h.run.0);
+
+                        html! { <span class=("tag",class), title=title,>{ " " }</span> }
+                    })
+                }</p>
+                <table class=("table","is-narrow"),>
+                 <thead><tr>
+                  <th>{ "Time" }</th>
+                  <th>{ "Run" }</th>
+                  <th>{ "Status" }</th>
+                  <th>{ "Environment" }</th>
+                 </tr></thead>
+                 <tbody>{
+                     for history.iter().rev().map(|h| html! {
+                         <tr>
+                          <td>{ format_time(h.timestamp) }</td>
+                          <td>{ &h.run.0 }{ self.render_delete_run(&h.run) }</td>
+                          <td>{ if h.passed { "pass" } else { "fail" } }</td>
+                          <td>{
+                              for h.environment.iter().map(|e| html! { <p>{ &e.0 }</p> })
+                          }</td>
+                         </tr>
+                     })
+                 }</tbody>
+                </table>
+                </>
+            }
+        } else {
+            html! { <p>{ "Type in a fully qualified test name to see its history" }</p> }
+        }
+    }
+
+    fn may(&self, role: Role) -> bool {
+        self.role >= role
+    }
+
+    fn render_bug_input(&self) -> Html<Model> {
+        if !self.may(Role::Triager) {
+            return html! { <></> };
+        }
+
+        html! {
+            <div class="container",>
+             <input class="input", type="text", placeholder="Bug to link, e.g. bsc#1234",
+                    value=&self.bug_term,
+                    oninput=|e| Msg::BugTerm(e.value),/>
+            </div>
+        }
+    }
+
+    fn render_link_cluster(&self, i: usize) -> Html<Model> {
+        if !self.may(Role::Triager) {
+            return html! { <></> };
+        }
+
+        html! {
+            <button class=("button","is-small"), onclick=|_| Msg::LinkCluster(i),>{
+                "Link all to bug"
+            }</button>
+        }
+    }
+
+    fn render_revert(&self) -> Html<Model> {
+        if !self.may(Role::Admin) {
+            return html! { <></> };
+        }
+
+        html! {
+            <button class=("button","is-danger"),
+                    onclick=|_| Msg::Send(ClientServer::RevertAutoLinks { rule: None }),>{
+                "Revert automatic links"
+            }</button>
+        }
+    }
+
+    fn render_delete_run(&self, run: &VertInfo) -> Html<Model> {
+        if !self.may(Role::Admin) {
+            return html! { <></> };
+        }
+
+        let VertInfo(name, id) = run.clone();
+        html! {
+            <button class=("button","is-small","is-danger"),
+                    onclick=|_| Msg::DeleteRun(name.clone(), id),>{
+                "Delete run"
+            }</button>
+        }
+    }
+
+    fn render_clusters(&self) -> Html<Model> {
+        if let Some(ref clusters) = self.clusters {
+            html! {
+                <>{
+                    for clusters.iter().enumerate().map(|(i, c)| html! {
+                        <div class="box",>
+                         <h3 class=("title","is-6"),>{
+                             format!("{} ({} failures)", c.signature, c.results.len())
+                         }</h3>
+                         { self.render_link_cluster(i) }
+                         <pre>{ &c.sample }</pre>
+                         <ul>{
+                             for c.results.iter().map(|r| html! {
+                                 <li title=r.result.to_string(),>{ &r.test.0 }</li>
+                             })
+                         }</ul>
+                        </div>
+                    })
+                }</>
+            }
+        } else {
+            html! { <p>{ "Type in a test or set name to group its failures by log output" }</p> }
+        }
+    }
+
+    fn render_audit(&self) -> Html<Model> {
+        if let Some(ref audit) = self.audit {
+            html! {
+                <table class=("table","is-narrow"),>
+                 <thead><tr>
+                  <th>{ "Time" }</th>
+                  <th>{ "User" }</th>
+                  <th>{ "Action" }</th>
+                 </tr></thead>
+                 <tbody>{
+                     for audit.iter().rev().map(|a| html! {
+                         <tr>
+                          <td>{ format_time(a.time) }</td>
+                          <td>{ &a.user }</td>
+                          <td>{ describe_action(&a.action) }</td>
+                         </tr>
+                     })
+                 }</tbody>
+                </table>
+            }
+        } else {
+            html! { <p>{ "Press refresh to see recent changes" }</p> }
+        }
+    }
+
+    fn render_level_button(&self, level: LogLevel) -> Html<Model> {
+        if level == self.journal_level {
+            html! {
+                <button class=("button","is-selected","is-info"),>{ level }</button>
+            }
+        } else {
+            html! {
+                <button class="button", onclick=|_| Msg::JournalLevel(level),>{ level }</button>
+            }
+        }
+    }
+
+    fn render_journal(&self) -> Html<Model> {
+        match self.journal {
+            Some(ref journal) if !journal.is_empty() => html! {
+                <table class=("table","is-narrow"),>
+                 <thead><tr>
+                  <th>{ "Time" }</th>
+                  <th>{ "Level" }</th>
+                  <th>{ "Actor" }</th>
+                  <th>{ "Module" }</th>
+                  <th>{ "Message" }</th>
+                 </tr></thead>
+                 <tbody>{
+                     for journal.iter().rev().map(|r| html! {
+                         <tr>
+                          <td>{ format_time(r.time / 1000) }</td>
+                          <td>{ r.level }</td>
+                          <td>{ &r.actor }</td>
+                          <td>{ &r.module }</td>
+                          <td>{ &r.msg }</td>
+                         </tr>
+                     })
+                 }</tbody>
+                </table>
+            },
+            _ => html! { <p>{ "Nothing has been logged at this level" }</p> },
+        }
+    }
+
+    fn render_import(&self) -> Html<Model> {
+        match self.import {
+            Some((ref dir, files, results, done)) => html! {
+                <p class="help",>{
+                    format!("{} {}: {} files, {} results",
+                            if done { "Imported" } else { "Importing" }, dir, files, results)
+                }</p>
+            },
+            None => VNode::from(VList::new()),
+        }
+    }
+
     fn render_tabs(&self) -> impl Iterator<Item=Html<Model>>
     {
         use AppTab::*;
 
         let cur = self.tab;
+        let admin = self.may(Role::Admin);
+        let tabs = &[Explore, Compare, Flaky, Regressions, History, Clusters, Audit, Log];
 
-        (&[Explore, Compare]).iter().map(move |tab| {
+        tabs.iter().filter(move |tab| **tab != Log || admin).map(move |tab| {
             if cur == *tab {
                 html! {
                     <li class="is-active",><a>{ *tab }</a></li>
@@ -357,6 +1032,18 @@ impl Model {
     }
 }
 
+fn render_test_list(heading: &str, class: &'static str, tests: &[VertInfo]) -> Html<Model>
+{
+    html! {
+        <div class="column",>
+         <h3 class=("title","is-5",class),>{ format!("{} ({})", heading, tests.len()) }</h3>
+         <ul>{
+             for tests.iter().map(|t| html! { <li>{ &t.0 }</li> })
+         }</ul>
+        </div>
+    }
+}
+
 fn render_notice(i: usize, notice: &Notice) -> Html<Model>
 {
     html! {
@@ -367,7 +1054,49 @@ fn render_notice(i: usize, notice: &Notice) -> Html<Model>
     }
 }
 
-fn ws_url() -> String {
+fn describe_action(action: &AuditAction) -> String {
+    match action {
+        AuditAction::Link { result, bug, auto: Some(auto) } => {
+            format!("Linked {} to {} by rule {} ({:.0}% confidence, copied from {})",
+                    result, bug.0, auto.rule, auto.confidence * 100.0, auto.source)
+        },
+        AuditAction::Link { result, bug, auto: None } => format!("Linked {} to {}", result, bug.0),
+        AuditAction::Unlink { result, bug } => format!("Unlinked {} from {}", result, bug.0),
+        AuditAction::DeleteRun { run, results } => {
+            format!("Deleted {} and its {} results", run.0, results)
+        },
+        AuditAction::Import { run, results } => {
+            format!("Imported {} with {} results", run.0, results)
+        },
+    }
+}
+
+/// Ask the user to confirm something with the browser's dialog
+fn confirm(question: &str) -> bool {
+    js! { return confirm(@{question}); }.as_bool().unwrap_or(false)
+}
+
+/// Format seconds since the epoch with the browser's locale
+fn format_time(timestamp: u64) -> String {
+    let millis = timestamp as f64 * 1000.0;
+
+    js! { return new Date(@{millis}).toLocaleString(); }.into_string().unwrap_or_default()
+}
+
+/// The message encoding set in the page's URL or our preferred one
+fn page_wire() -> Wire {
+    let query = web::window().location()
+        .and_then(|loc| loc.search().ok())
+        .unwrap_or_default();
+
+    if query.contains("encoding=") {
+        Wire::from_query(&query).unwrap_or(PREFERRED_WIRE)
+    } else {
+        PREFERRED_WIRE
+    }
+}
+
+fn ws_url(wire: &Wire) -> String {
     let loc = web::window().location().expect("Getting host URL");
     let proto = if "https:" == loc.protocol().expect("Getting connection protocol") {
         "wss:"
@@ -376,7 +1105,7 @@ fn ws_url() -> String {
     };
     let host = loc.host().expect("Getting host");
 
-    format!("{}//{}/ws/", proto, host)
+    format!("{}//{}/ws/?{}", proto, host, wire.query())
 }
 
 fn main() {
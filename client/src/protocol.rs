@@ -1 +0,0 @@
-../../src/protocol.rs
\ No newline at end of file
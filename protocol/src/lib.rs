@@ -0,0 +1,448 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The messages exchanged by the server and the WASM client
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate flate2;
+extern crate uuid;
+
+pub mod wire;
+
+use std::fmt;
+
+use uuid::Uuid;
+
+/// Incremented when a change to the messages means that old clients can no
+/// longer talk to the server.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+#[derive(Serialize, Deserialize)]
+pub enum Flavor {
+    Error,
+    Warn,
+    Info,
+    Success,
+}
+
+/// For the user's information
+#[derive(Serialize, Deserialize)]
+pub struct Notice {
+    pub msg: String,
+    pub flavor: Flavor,
+}
+
+macro_rules! make_notices {
+    ( $( $mname:ident, $tname:ident );+ ) => { $(
+        #[allow(dead_code)]
+        pub fn $mname<S: Into<String>>(msg: S) -> Notice {
+            Notice {
+                msg: msg.into(),
+                flavor: Flavor::$tname,
+            }
+        }
+    )+ }
+}
+
+impl Notice {
+    #[allow(dead_code)]
+    pub fn css_class(&self) -> &'static str {
+        match self.flavor {
+            Flavor::Error => "is-error",
+            Flavor::Warn => "is-warning",
+            Flavor::Info => "is-info",
+            Flavor::Success => "is-success",
+        }
+    }
+
+    make_notices! {
+        error, Error;
+        warn, Warn;
+        info, Info;
+        succ, Success
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VertInfo(pub String, pub Uuid);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResultInMatrix {
+    pub test_case: u32,
+    pub passes: u32,
+    pub fails: u32,
+    pub flaky: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResultMatrix {
+    pub test_cases: Vec<VertInfo>,
+    pub results: Vec<(VertInfo, Vec<ResultInMatrix>)>,
+}
+
+/// Status changes of a test in one environment
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FlakyEnv {
+    pub env: String,
+    pub runs: u32,
+    pub flips: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FlakyTest {
+    pub test: VertInfo,
+    pub score: f32,
+    pub envs: Vec<FlakyEnv>,
+}
+
+/// How the status of tests changed from one set of results to another, such as
+/// from one product revision to the next.
+#[derive(Default, Serialize, Deserialize)]
+pub struct RevisionDiff {
+    pub newly_failing: Vec<VertInfo>,
+    pub newly_passing: Vec<VertInfo>,
+    pub still_failing: Vec<VertInfo>,
+    pub missing: Vec<VertInfo>,
+}
+
+/// A test's results in one set, such as a product revision
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TestOutcome {
+    pub test: VertInfo,
+    pub passes: u32,
+    pub fails: u32,
+    pub flaky: bool,
+}
+
+/// A single result of a test, in run order when part of a history
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub run: VertInfo,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub passed: bool,
+    pub environment: Vec<VertInfo>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClusteredResult {
+    pub test: VertInfo,
+    pub result: Uuid,
+}
+
+/// Failed results with similar logs, which probably have the same cause
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FailureCluster {
+    pub signature: String,
+    /// The normalised failure lines of one of the logs
+    pub sample: String,
+    pub results: Vec<ClusteredResult>,
+}
+
+/// Why a bug link was made automatically
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AutoLinkRule {
+    pub rule: String,
+    pub confidence: f32,
+    /// The result, linked by hand, which the link was copied from
+    pub source: Uuid,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AuditAction {
+    Link { result: Uuid, bug: VertInfo, auto: Option<AutoLinkRule> },
+    Unlink { result: Uuid, bug: VertInfo },
+    /// A run and its results were deleted
+    DeleteRun { run: VertInfo, results: u32 },
+    /// A results file was imported as a run
+    Import { run: VertInfo, results: u32 },
+}
+
+/// A change made to the repository, kept so that it can be reviewed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub user: String,
+    pub action: AuditAction,
+}
+
+/// What a user is allowed to do, each role can do everything the ones before
+/// it can.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can look at everything
+    Viewer,
+    /// Can link and unlink bugs
+    Triager,
+    /// Can import and delete runs and revert automatic links
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Triager => write!(f, "triager"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// How serious a log record is, the most serious first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "ERROR"),
+            LogLevel::Warn => write!(f, "WARN"),
+            LogLevel::Info => write!(f, "INFO"),
+            LogLevel::Debug => write!(f, "DEBUG"),
+            LogLevel::Trace => write!(f, "TRACE"),
+        }
+    }
+}
+
+/// One of the server's log messages
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Increases by one with each record, so clients can ask for what they missed
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch
+    pub time: u64,
+    pub level: LogLevel,
+    pub module: String,
+    /// The arbiter the record came from, e.g. repository or web
+    pub actor: String,
+    pub msg: String,
+}
+
+/// Something a client can subscribe to, to be told when it changes
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Topic {
+    /// New results which are in a set, or in one of the sets inside it
+    SetResults(Uuid),
+    ImportProgress,
+    /// Bugs being linked or unlinked
+    BugLinks,
+    /// The server's log records, whatever their level
+    Journal,
+    /// Parts of the server crashing and being restarted
+    Restarts,
+}
+
+/// Pushed to clients which are subscribed to its topic
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Event {
+    /// Count of results added to a set since the last event
+    NewResults { set: Uuid, count: u32 },
+    /// Running totals for an import
+    ImportProgress { dir: String, files: u32, results: u32, done: bool },
+    BugLink(AuditEntry),
+    Log(LogRecord),
+    /// An actor panicked and was restarted, the request it was handling failed
+    Restart { actor: String, reason: String },
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::NewResults { set, .. } => Topic::SetResults(*set),
+            Event::ImportProgress { .. } => Topic::ImportProgress,
+            Event::BugLink(_) => Topic::BugLinks,
+            Event::Log(_) => Topic::Journal,
+            Event::Restart { .. } => Topic::Restarts,
+        }
+    }
+}
+
+/// Chosen by the client and returned with the reply to a request
+pub type RequestId = u32;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The request could not be parsed or refers to something which is missing
+    BadRequest,
+    /// The server failed while answering the request
+    Internal,
+    /// The user's role does not allow the request
+    Forbidden,
+}
+
+/// Server to Client message
+#[derive(Serialize, Deserialize)]
+pub enum ServerClient {
+    /// The first message sent on a new connection
+    Hello { version: u32, user: String, role: Role },
+    Notify(Notice),
+    SetList(Vec<(String, Uuid)>),
+    Search(String, Vec<(String, Uuid)>),
+    ResultMatrix(Uuid, ResultMatrix),
+    FlakyTests { scope: Uuid, tests: Vec<FlakyTest> },
+    RevisionDiff(Uuid, Uuid, RevisionDiff),
+    TestHistory(Uuid, Vec<HistoryEntry>),
+    FailureClusters(Uuid, Vec<FailureCluster>),
+    AuditTrail(Vec<AuditEntry>),
+    Journal(Vec<LogRecord>),
+    Event(Event),
+    /// The answers to Subscribe and Unsubscribe
+    Subscribed(Topic),
+    Unsubscribed(Topic),
+    /// A request failed, id is None if the request could not be read
+    Error { id: Option<RequestId>, kind: ErrorKind, msg: String },
+}
+
+impl ServerClient {
+    pub fn info_notice<S: Into<String>>(msg: S) -> ServerClient {
+        ServerClient::Notify(Notice {
+            msg: msg.into(),
+            flavor: Flavor::Info,
+        })
+    }
+}
+
+/// Client to Server message
+#[derive(Serialize, Deserialize)]
+pub enum ClientServer {
+    /// The first message sent once the connection is open
+    Hello { version: u32 },
+    SetQuery(Option<Uuid>),
+    Search(String),
+    ResultMatrix(Uuid),
+    FlakyTests { scope: Uuid },
+    RevisionDiff(Uuid, Uuid),
+    TestHistory(Uuid),
+    FailureClusters(Uuid),
+    LinkBug { bug: String, results: Vec<Uuid> },
+    UnlinkBug { bug: String, results: Vec<Uuid> },
+    /// Remove automatic links, optionally only those made by one rule
+    RevertAutoLinks { rule: Option<String> },
+    AuditTrail,
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    DeleteRun(Uuid),
+    /// The recent log records after `since`, at `level` or more serious
+    GetJournal { since: Option<u64>, level: LogLevel },
+}
+
+impl ClientServer {
+    /// The least role a user needs to make the request
+    pub fn required_role(&self) -> Role {
+        use self::ClientServer::*;
+
+        match self {
+            LinkBug { .. } | UnlinkBug { .. } => Role::Triager,
+            RevertAutoLinks { .. } | DeleteRun(_) | GetJournal { .. } => Role::Admin,
+            Subscribe(Topic::Journal) => Role::Admin,
+            _ => Role::Viewer,
+        }
+    }
+
+    /// The variant's name, e.g. for labelling metrics
+    pub fn name(&self) -> &'static str {
+        use self::ClientServer::*;
+
+        match self {
+            Hello { .. } => "Hello",
+            SetQuery(_) => "SetQuery",
+            Search(_) => "Search",
+            ResultMatrix(_) => "ResultMatrix",
+            FlakyTests { .. } => "FlakyTests",
+            RevisionDiff(..) => "RevisionDiff",
+            TestHistory(_) => "TestHistory",
+            FailureClusters(_) => "FailureClusters",
+            LinkBug { .. } => "LinkBug",
+            UnlinkBug { .. } => "UnlinkBug",
+            RevertAutoLinks { .. } => "RevertAutoLinks",
+            AuditTrail => "AuditTrail",
+            Subscribe(_) => "Subscribe",
+            Unsubscribe(_) => "Unsubscribe",
+            DeleteRun(_) => "DeleteRun",
+            GetJournal { .. } => "GetJournal",
+        }
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Topic::SetResults(uuid) => write!(f, "SetResults({})", uuid),
+            Topic::ImportProgress => write!(f, "ImportProgress"),
+            Topic::BugLinks => write!(f, "BugLinks"),
+            Topic::Journal => write!(f, "Journal"),
+            Topic::Restarts => write!(f, "Restarts"),
+        }
+    }
+}
+
+/// Envelope for a reply, id is None for messages the client did not ask for
+#[derive(Serialize, Deserialize)]
+pub struct ServerMsg {
+    pub id: Option<RequestId>,
+    pub reply: ServerClient,
+}
+
+/// Envelope for a request
+#[derive(Serialize, Deserialize)]
+pub struct ClientMsg {
+    pub id: RequestId,
+    pub query: ClientServer,
+}
+
+impl fmt::Display for ClientServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ClientServer::*;
+
+        match self {
+            Hello { version } => write!(f, "Hello(v{})", version),
+            SetQuery(Some(uuid)) => write!(f, "SetQuery({})", uuid),
+            SetQuery(None) => write!(f, "SetQuery(All)"),
+            Search(term) => write!(f, "Search({})", term),
+            ResultMatrix(uuid) => write!(f, "ResultMatrix({})", uuid),
+            FlakyTests { scope } => write!(f, "FlakyTests({})", scope),
+            RevisionDiff(from, to) => write!(f, "RevisionDiff({}, {})", from, to),
+            TestHistory(uuid) => write!(f, "TestHistory({})", uuid),
+            FailureClusters(uuid) => write!(f, "FailureClusters({})", uuid),
+            LinkBug { bug, results } => write!(f, "LinkBug({}, {} results)", bug, results.len()),
+            UnlinkBug { bug, results } => {
+                write!(f, "UnlinkBug({}, {} results)", bug, results.len())
+            },
+            RevertAutoLinks { rule: Some(rule) } => write!(f, "RevertAutoLinks({})", rule),
+            RevertAutoLinks { rule: None } => write!(f, "RevertAutoLinks(All)"),
+            AuditTrail => write!(f, "AuditTrail"),
+            Subscribe(topic) => write!(f, "Subscribe({})", topic),
+            Unsubscribe(topic) => write!(f, "Unsubscribe({})", topic),
+            DeleteRun(uuid) => write!(f, "DeleteRun({})", uuid),
+            GetJournal { since: Some(seq), level } => {
+                write!(f, "GetJournal({} after {})", level, seq)
+            },
+            GetJournal { since: None, level } => write!(f, "GetJournal({})", level),
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! How messages are turned into websocket frames. JSON is the default, but
+//! the client can ask for a binary encoding and compression when it connects,
+//! which makes a big difference to large result matrices.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use rmp_serde;
+use serde_cbor;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MsgPack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MsgPack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    Json(serde_json::Error),
+    MsgPackEncode(rmp_serde::encode::Error),
+    MsgPackDecode(rmp_serde::decode::Error),
+    Cbor(serde_cbor::Error),
+    Deflate(io::Error),
+    /// The connection's query string asked for something we do not have
+    Negotiation(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Json(e) => write!(f, "JSON: {}", e),
+            WireError::MsgPackEncode(e) => write!(f, "MessagePack: {}", e),
+            WireError::MsgPackDecode(e) => write!(f, "MessagePack: {}", e),
+            WireError::Cbor(e) => write!(f, "CBOR: {}", e),
+            WireError::Deflate(e) => write!(f, "Deflate: {}", e),
+            WireError::Negotiation(e) => write!(f, "Negotiation: {}", e),
+        }
+    }
+}
+
+impl error::Error for WireError {
+    fn description(&self) -> &str {
+        "Could not encode or decode a message"
+    }
+}
+
+/// The encoding and compression of the messages sent to a client
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Wire {
+    pub encoding: Encoding,
+    pub deflate: bool,
+}
+
+impl Default for Wire {
+    fn default() -> Self {
+        Wire {
+            encoding: Encoding::Json,
+            deflate: false,
+        }
+    }
+}
+
+impl Wire {
+    /// Parse the settings from a URL query string, e.g. encoding=cbor&deflate=1.
+    /// Anything missing is left as the default and unrelated keys are ignored.
+    pub fn from_query(query: &str) -> Result<Wire, WireError> {
+        let mut wire = Wire::default();
+
+        for pair in query.trim_left_matches('?').split('&').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let (key, val) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+
+            match key {
+                "encoding" => {
+                    wire.encoding = Encoding::from_name(val).ok_or_else(|| {
+                        WireError::Negotiation(format!("Unknown encoding: {}", val))
+                    })?;
+                },
+                "deflate" => wire.deflate = val == "1" || val == "true",
+                _ => (),
+            }
+        }
+
+        Ok(wire)
+    }
+
+    pub fn query(&self) -> String {
+        format!("encoding={}&deflate={}", self.encoding.name(), if self.deflate { 1 } else { 0 })
+    }
+
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Result<Vec<u8>, WireError> {
+        let bytes = match self.encoding {
+            Encoding::Json => serde_json::to_vec(msg).map_err(WireError::Json)?,
+            Encoding::MsgPack => rmp_serde::to_vec_named(msg).map_err(WireError::MsgPackEncode)?,
+            Encoding::Cbor => serde_cbor::to_vec(msg).map_err(WireError::Cbor)?,
+        };
+
+        if !self.deflate {
+            return Ok(bytes);
+        }
+
+        let buf = Vec::with_capacity(bytes.len() / 4);
+        let mut enc = DeflateEncoder::new(buf, Compression::fast());
+        enc.write_all(&bytes).map_err(WireError::Deflate)?;
+        enc.finish().map_err(WireError::Deflate)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        let inflated;
+        let bytes = if self.deflate {
+            let mut buf = Vec::with_capacity(bytes.len() * 4);
+
+            DeflateDecoder::new(bytes).read_to_end(&mut buf).map_err(WireError::Deflate)?;
+            inflated = buf;
+            &inflated[..]
+        } else {
+            bytes
+        };
+
+        match self.encoding {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(WireError::Json),
+            Encoding::MsgPack => rmp_serde::from_read_ref(bytes).map_err(WireError::MsgPackDecode),
+            Encoding::Cbor => serde_cbor::from_slice(bytes).map_err(WireError::Cbor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use {ServerClient, ClientServer, Notice, VertInfo, ResultMatrix, ResultInMatrix, FlakyTest,
+         FlakyEnv, RevisionDiff, HistoryEntry, FailureCluster, ClusteredResult, AuditEntry,
+         AuditAction, AutoLinkRule, LogRecord, LogLevel, Event, Topic, ErrorKind, Role,
+         PROTOCOL_VERSION};
+    use super::*;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Msg {
+        id: Option<u32>,
+        names: Vec<String>,
+    }
+
+    #[test]
+    fn from_query_defaults_to_json() {
+        assert_eq!(Wire::from_query("").unwrap(), Wire::default());
+        assert_eq!(Wire::from_query("?user=bob&").unwrap(), Wire::default());
+    }
+
+    #[test]
+    fn from_query_reads_encoding_and_deflate() {
+        let wire = Wire::from_query("?encoding=cbor&deflate=1").unwrap();
+
+        assert_eq!(wire, Wire { encoding: Encoding::Cbor, deflate: true });
+        assert!(Wire::from_query("deflate=true").unwrap().deflate);
+        assert!(!Wire::from_query("deflate=yes").unwrap().deflate);
+        assert!(Wire::from_query("encoding=xml").is_err());
+    }
+
+    #[test]
+    fn query_round_trips() {
+        let wire = Wire { encoding: Encoding::MsgPack, deflate: true };
+
+        assert_eq!(Wire::from_query(&wire.query()).unwrap(), wire);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let msg = Msg { id: Some(7), names: vec!["test:ltp:mmap01".to_string(); 50] };
+
+        for &encoding in &[Encoding::Json, Encoding::MsgPack, Encoding::Cbor] {
+            for &deflate in &[false, true] {
+                let wire = Wire { encoding, deflate };
+                let bytes = wire.encode(&msg).unwrap();
+
+                assert_eq!(wire.decode::<Msg>(&bytes).unwrap(), msg);
+            }
+        }
+    }
+
+    fn id(n: u8) -> Uuid {
+        Uuid::from_bytes(&[n; 16]).unwrap()
+    }
+
+    fn vert(name: &str, n: u8) -> VertInfo {
+        VertInfo(name.to_string(), id(n))
+    }
+
+    fn server_messages() -> Vec<ServerClient> {
+        let entry = |action| AuditEntry { time: 1_530_000_000, user: "alice".to_string(), action };
+        let log = LogRecord {
+            seq: 9,
+            time: 1_530_000_000_123,
+            level: LogLevel::Warn,
+            module: "bug_graph::imp".to_string(),
+            actor: "importer".to_string(),
+            msg: "Skipped r\u{e9}sultat.json".to_string(),
+        };
+
+        vec![
+            ServerClient::Hello { version: PROTOCOL_VERSION, user: "alice".to_string(),
+                                  role: Role::Triager },
+            ServerClient::Notify(Notice::error("Could not find it")),
+            ServerClient::SetList(vec![("environment:arch:x86_64".to_string(), id(1))]),
+            ServerClient::Search("ltp".to_string(), vec![]),
+            ServerClient::ResultMatrix(id(2), ResultMatrix {
+                test_cases: vec![vert("test:ltp:mmap01", 3)],
+                results: vec![(vert("environment:arch:s390x", 4),
+                               vec![ResultInMatrix { test_case: 0, passes: 3, fails: 1,
+                                                     flaky: true }])],
+            }),
+            ServerClient::FlakyTests { scope: id(2), tests: vec![FlakyTest {
+                test: vert("test:ltp:mmap01", 3),
+                score: 0.3,
+                envs: vec![FlakyEnv { env: "x86_64".to_string(), runs: 10, flips: 3 }],
+            }] },
+            ServerClient::RevisionDiff(id(5), id(6), RevisionDiff {
+                newly_failing: vec![vert("test:a", 7)],
+                missing: vec![vert("test:b", 8)],
+                ..RevisionDiff::default()
+            }),
+            ServerClient::TestHistory(id(3), vec![HistoryEntry {
+                run: vert("run:1", 9),
+                timestamp: 1_530_000_000,
+                passed: false,
+                environment: vec![vert("environment:arch:x86_64", 1)],
+            }]),
+            ServerClient::FailureClusters(id(2), vec![FailureCluster {
+                signature: "4f2a".to_string(),
+                sample: "mmap01 TFAIL: N".to_string(),
+                results: vec![ClusteredResult { test: vert("test:ltp:mmap01", 3),
+                                                result: id(10) }],
+            }]),
+            ServerClient::AuditTrail(vec![
+                entry(AuditAction::Link { result: id(10), bug: vert("bug:1", 11), auto: None }),
+                entry(AuditAction::Link { result: id(12), bug: vert("bug:1", 11),
+                                          auto: Some(AutoLinkRule {
+                                              rule: "same-signature".to_string(),
+                                              confidence: 0.75,
+                                              source: id(10),
+                                          }) }),
+                entry(AuditAction::Unlink { result: id(12), bug: vert("bug:1", 11) }),
+                entry(AuditAction::DeleteRun { run: vert("run:1", 9), results: 40 }),
+                entry(AuditAction::Import { run: vert("run:2", 13), results: 41 }),
+            ]),
+            ServerClient::Journal(vec![log.clone()]),
+            ServerClient::Event(Event::NewResults { set: id(1), count: 5 }),
+            ServerClient::Event(Event::ImportProgress { dir: "/results".to_string(), files: 2,
+                                                        results: 80, done: true }),
+            ServerClient::Event(Event::Log(log)),
+            ServerClient::Event(Event::Restart { actor: "repository".to_string(),
+                                                 reason: "NewResult panicked".to_string() }),
+            ServerClient::Subscribed(Topic::SetResults(id(1))),
+            ServerClient::Unsubscribed(Topic::Journal),
+            ServerClient::Error { id: Some(7), kind: ErrorKind::Forbidden,
+                                  msg: "alice does not have the admin role".to_string() },
+            ServerClient::Error { id: None, kind: ErrorKind::BadRequest, msg: String::new() },
+        ]
+    }
+
+    fn client_messages() -> Vec<ClientServer> {
+        vec![
+            ClientServer::Hello { version: PROTOCOL_VERSION },
+            ClientServer::SetQuery(None),
+            ClientServer::SetQuery(Some(id(1))),
+            ClientServer::Search("ltp".to_string()),
+            ClientServer::ResultMatrix(id(2)),
+            ClientServer::FlakyTests { scope: id(2) },
+            ClientServer::RevisionDiff(id(5), id(6)),
+            ClientServer::TestHistory(id(3)),
+            ClientServer::FailureClusters(id(2)),
+            ClientServer::LinkBug { bug: "bug:1".to_string(), results: vec![id(10), id(12)] },
+            ClientServer::UnlinkBug { bug: "bug:1".to_string(), results: vec![id(12)] },
+            ClientServer::RevertAutoLinks { rule: None },
+            ClientServer::RevertAutoLinks { rule: Some("same-signature".to_string()) },
+            ClientServer::AuditTrail,
+            ClientServer::Subscribe(Topic::ImportProgress),
+            ClientServer::Unsubscribe(Topic::BugLinks),
+            ClientServer::Subscribe(Topic::Restarts),
+            ClientServer::DeleteRun(id(9)),
+            ClientServer::GetJournal { since: Some(9), level: LogLevel::Debug },
+        ]
+    }
+
+    /// Round trip each message with every encoding, comparing them as JSON
+    /// because the messages can not be compared themselves
+    fn round_trip<T: Serialize + DeserializeOwned>(msgs: Vec<T>) {
+        for &encoding in &[Encoding::Json, Encoding::MsgPack, Encoding::Cbor] {
+            for &deflate in &[false, true] {
+                let wire = Wire { encoding, deflate };
+
+                for msg in &msgs {
+                    let expected = serde_json::to_value(msg).unwrap();
+                    let bytes = wire.encode(msg).unwrap();
+                    let decoded = wire.decode::<T>(&bytes).unwrap_or_else(|e| {
+                        panic!("{:?} could not decode {}: {}", wire, expected, e)
+                    });
+
+                    assert_eq!(serde_json::to_value(&decoded).unwrap(), expected, "{:?}", wire);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        round_trip(server_messages());
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        round_trip(client_messages());
+    }
+}
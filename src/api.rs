@@ -0,0 +1,249 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! REST routes which answer the same queries as the websocket, with the same
+//! protocol types as JSON.
+
+use serde::Serialize;
+use futures::{future, Future};
+use actix::prelude::*;
+use actix_web::{error, App, AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest,
+                HttpResponse, Path, Query, State};
+use actix_web::http::Method;
+use uuid::Uuid;
+
+use repo::{Repo, GetSetVerts, Search, GetResultMatrix, GetTestHistory, DeleteRun, GetGraph};
+use imp::ImportFile;
+use export::ExportFormat;
+use sheet::{self, SheetFormat};
+use protocol::Role;
+use web::AppState;
+use auth::{self, User};
+
+/// The largest results file which can be uploaded
+const IMPORT_MAX: usize = 64 * 1024 * 1024;
+/// How many edges away from the root an export goes by default
+const EXPORT_DEPTH: u32 = 2;
+/// How many edges away from the root anyone but an admin may export
+const EXPORT_DEPTH_MAX: u32 = 4;
+
+#[derive(Deserialize)]
+struct VerticesQuery {
+    id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// Only export the vertices near this one
+    root: Option<String>,
+    depth: Option<u32>,
+    /// Include the results' logs
+    logs: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// What the run is called, usually the name of the results file
+    name: String,
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(id).map_err(|e| error::ErrorBadRequest(format!("Bad UUID {}: {}", id, e)))
+}
+
+fn repo_json<Q>(repo: &Addr<Repo>, query: Q) -> FutureResponse<HttpResponse>
+where
+    Q: Message + Send + 'static,
+    Q::Result: Send + Serialize,
+    Repo: Handler<Q>
+{
+    repo.send(query)
+        .from_err()
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+        .responder()
+}
+
+fn vertices((query, state): (Query<VerticesQuery>, State<AppState>))
+            -> FutureResponse<HttpResponse> {
+    match query.id.as_ref().map(|id| parse_uuid(id)) {
+        None => repo_json(&state.repo, GetSetVerts(None)),
+        Some(Ok(uuid)) => repo_json(&state.repo, GetSetVerts(Some(uuid))),
+        Some(Err(e)) => Box::new(future::err(e)),
+    }
+}
+
+fn search((query, state): (Query<SearchQuery>, State<AppState>))
+          -> FutureResponse<HttpResponse> {
+    repo_json(&state.repo, Search(query.into_inner().q))
+}
+
+fn matrix((id, state): (Path<String>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    match parse_uuid(&id) {
+        Ok(uuid) => repo_json(&state.repo, GetResultMatrix(uuid)),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+/// Download a result matrix as a spreadsheet
+fn matrix_sheet((path, state): (Path<(String, String)>, State<AppState>))
+                -> FutureResponse<HttpResponse> {
+    let (id, format) = path.into_inner();
+    let format: SheetFormat = match format.parse() {
+        Ok(format) => format,
+        Err(e) => return Box::new(future::err(error::ErrorNotFound(e))),
+    };
+    let uuid = match parse_uuid(&id) {
+        Ok(uuid) => uuid,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    state.repo.send(GetResultMatrix(uuid))
+        .from_err()
+        .and_then(move |matrix| {
+            let body = sheet::write(&matrix, format).map_err(error::ErrorInternalServerError)?;
+
+            Ok(HttpResponse::Ok()
+               .content_type(format.content_type())
+               .header("Content-Disposition",
+                       format!("attachment; filename=\"matrix-{}.{}\"", uuid, format.extension()))
+               .body(body))
+        })
+        .responder()
+}
+
+fn results((id, state): (Path<String>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    match parse_uuid(&id) {
+        Ok(uuid) => repo_json(&state.repo, GetTestHistory(uuid)),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+/// Download the part of the graph around a vertex in a graph format. The
+/// repository only reads the vertices within `depth` edges of the root, and
+/// leaves out the logs unless they are asked for, but that can still be most
+/// of the graph. So only admins may download all of it, or go further than
+/// EXPORT_DEPTH_MAX edges.
+fn export((format, query, req): (Path<String>, Query<ExportQuery>, HttpRequest<AppState>))
+          -> FutureResponse<HttpResponse> {
+    let format: ExportFormat = match format.parse() {
+        Ok(format) => format,
+        Err(e) => return Box::new(future::err(error::ErrorNotFound(e))),
+    };
+    let root = match query.root.as_ref().map(|id| parse_uuid(id)) {
+        None => None,
+        Some(Ok(uuid)) => Some(uuid),
+        Some(Err(e)) => return Box::new(future::err(e)),
+    };
+    let depth = query.depth.unwrap_or(EXPORT_DEPTH);
+    let logs = query.logs.unwrap_or(false);
+
+    if root.is_none() || depth > EXPORT_DEPTH_MAX {
+        if let Err(e) = auth::require(&req, Role::Admin) {
+            return Box::new(future::err(e));
+        }
+    }
+
+    req.state().repo.send(GetGraph { root, depth, logs })
+        .from_err()
+        .and_then(move |graph| {
+            let mut body = Vec::new();
+
+            graph.map_err(error::ErrorInternalServerError)?
+                .write(format, &mut body)
+                .map_err(error::ErrorInternalServerError)?;
+
+            Ok(HttpResponse::Ok()
+               .content_type(format.content_type())
+               .header("Content-Disposition",
+                       format!("attachment; filename=\"bug-graph.{}\"", format.extension()))
+               .body(body))
+        })
+        .responder()
+}
+
+/// The user making a change, if they have the role. Changes sent by pages on
+/// other sites are refused, so that they can not use the login cookie.
+fn require_change<S>(req: &HttpRequest<S>, role: Role) -> Result<User, Error> {
+    if !auth::same_origin(req) {
+        return Err(error::ErrorForbidden("Changes can not be made from other sites"));
+    }
+    auth::require(req, role)
+}
+
+/// Import a results file sent as the request body
+fn import((query, req): (Query<ImportQuery>, HttpRequest<AppState>))
+          -> FutureResponse<HttpResponse> {
+    // A form on another site can not send JSON without the browser asking us
+    // first, unlike text/plain
+    if req.content_type() != "application/json" {
+        return Box::new(future::err(error::ErrorBadRequest(
+            "Results must be sent with Content-Type: application/json"
+        )));
+    }
+
+    let user = match require_change(&req, Role::Admin) {
+        Ok(user) => user,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let importer = req.state().importer.clone();
+    let name = query.into_inner().name;
+
+    req.body()
+        .limit(IMPORT_MAX)
+        .from_err()
+        .and_then(|body| String::from_utf8(body.to_vec()).map_err(error::ErrorBadRequest))
+        .and_then(move |json| {
+            importer.send(ImportFile { name, json, user: user.name }).from_err()
+        })
+        .and_then(|res| match res {
+            Ok(results) => Ok(HttpResponse::Ok().json(results)),
+            Err(e) => Err(error::ErrorBadRequest(e)),
+        })
+        .responder()
+}
+
+fn delete_run((id, req): (Path<String>, HttpRequest<AppState>)) -> FutureResponse<HttpResponse> {
+    let query = require_change(&req, Role::Admin).and_then(|user| {
+        Ok(DeleteRun { run: parse_uuid(&id)?, user: user.name })
+    });
+
+    match query {
+        Ok(query) => req.state().repo.send(query)
+            .from_err()
+            .and_then(|res| match res {
+                Some(results) => Ok(HttpResponse::Ok().json(results)),
+                None => Err(error::ErrorNotFound("No such run")),
+            })
+            .responder(),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+/// Add the version 1 API routes to the app
+pub fn register(app: App<AppState>) -> App<AppState> {
+    app.resource("/api/v1/vertices", |r| r.method(Method::GET).with(vertices))
+        .resource("/api/v1/search", |r| r.method(Method::GET).with(search))
+        .resource("/api/v1/matrix/{uuid}", |r| r.method(Method::GET).with(matrix))
+        .resource("/api/v1/matrix/{uuid}/{format}", |r| r.method(Method::GET).with(matrix_sheet))
+        .resource("/api/v1/results/{uuid}", |r| r.method(Method::GET).with(results))
+        .resource("/api/v1/export/{format}", |r| r.method(Method::GET).with(export))
+        .resource("/api/v1/runs", |r| r.method(Method::POST).with(import))
+        .resource("/api/v1/runs/{uuid}", |r| r.method(Method::DELETE).with(delete_run))
+}
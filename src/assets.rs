@@ -0,0 +1,112 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+
+use ring::digest;
+
+/// Include the files build.rs copied out of the client's deploy folder
+macro_rules! embed {
+    ($( $file:expr ),+) => (
+        &[$(
+            ($file, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/res/static/", $file))
+             as &'static [u8])
+        ),+]
+    )
+}
+
+static EMBEDDED: &[(&str, &[u8])] = embed!("index.html",
+                                           "login.html",
+                                           "bug-graph-client.js",
+                                           "bug-graph-client.wasm",
+                                           "bulma.min.css");
+
+#[derive(Clone)]
+pub struct Asset {
+    pub body: Cow<'static, [u8]>,
+    pub content_type: &'static str,
+    pub etag: String,
+}
+
+impl Asset {
+    fn new(name: &str, body: Cow<'static, [u8]>) -> Asset {
+        let hash = digest::digest(&digest::SHA256, &body);
+        let etag = hash.as_ref()[.. 16].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        Asset {
+            body,
+            content_type: content_type(name),
+            etag: format!("\"{}\"", etag),
+        }
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The web client's files, either compiled into the binary or read from a
+/// directory on each request so that a rebuilt client shows up immediately
+pub enum Assets {
+    Embedded(HashMap<&'static str, Asset>),
+    Dir(PathBuf),
+}
+
+impl Assets {
+    pub fn embedded() -> Assets {
+        Assets::Embedded(EMBEDDED.iter().map(|&(name, body)| {
+            (name, Asset::new(name, Cow::Borrowed(body)))
+        }).collect())
+    }
+
+    pub fn dir(path: PathBuf) -> Assets {
+        Assets::Dir(path)
+    }
+
+    pub fn get(&self, name: &str) -> io::Result<Option<Asset>> {
+        match self {
+            Assets::Embedded(assets) => Ok(assets.get(name).cloned()),
+            Assets::Dir(dir) => {
+                let mut body = Vec::new();
+
+                match File::open(dir.join(name)) {
+                    Ok(mut file) => file.read_to_end(&mut body)?,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                Ok(Some(Asset::new(name, Cow::Owned(body))))
+            },
+        }
+    }
+
+    /// The client's file names do not change between builds, so browsers
+    /// must always check their copy is current. It is cheap with the ETag.
+    pub fn cache_control(&self) -> &'static str {
+        match self {
+            Assets::Embedded(_) => "public, no-cache",
+            Assets::Dir(_) => "no-store",
+        }
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Local user accounts. Users log in with a password to get a session cookie
+//! or send an API token as a bearer token. If no users file is given then
+//! authentication is disabled and everyone is anonymous.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use actix_web::{error, HttpRequest, HttpResponse, Result};
+use actix_web::http::header;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::middleware::identity::RequestIdentity;
+use failure::Error;
+use ring::{digest, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json;
+
+use protocol::Role;
+use web::AppState;
+
+/// Who actions are recorded against when authentication is disabled, they
+/// can do anything.
+pub const ANONYMOUS: &str = "anonymous";
+
+const SCHEME: &str = "pbkdf2-sha256";
+static PBKDF2_ALG: &digest::Algorithm = &digest::SHA256;
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const TOKEN_LEN: usize = 32;
+const COOKIE_KEY_LEN: usize = 32;
+
+/// Paths which need a user, the rest are static files and the login page
+const PROTECTED: [&str; 5] = ["/", "/ws/", "/api/", "/graphql", "/graphiql"];
+
+/// An entry in the users file
+#[derive(Deserialize)]
+struct UserConfig {
+    name: String,
+    /// Created with the hash-password command
+    password: Option<String>,
+    /// SHA-256 hashes of the user's API tokens, created with the new-token
+    /// command
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default = "default_role")]
+    role: Role,
+}
+
+fn default_role() -> Role {
+    Role::Viewer
+}
+
+/// The user a request was made by, added to the request by RequireUser
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+}
+
+impl User {
+    fn anonymous() -> User {
+        User { name: ANONYMOUS.to_string(), role: Role::Admin }
+    }
+
+    pub fn may(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+struct Account {
+    password: Option<String>,
+    role: Role,
+}
+
+pub struct Auth {
+    /// None if authentication is disabled
+    accounts: Option<HashMap<String, Account>>,
+    tokens: HashMap<String, String>,
+    cookie_key: Vec<u8>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0 .. hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i .. i + 2], 16).ok())
+        .collect()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+
+    SystemRandom::new().fill(&mut bytes).expect("Getting random bytes");
+    bytes
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+/// Hash a password for the users file
+pub fn hash_password(password: &str) -> String {
+    let salt = random_bytes(SALT_LEN);
+    let mut hash = [0; HASH_LEN];
+
+    pbkdf2::derive(PBKDF2_ALG, ITERATIONS, &salt, password.as_bytes(), &mut hash);
+    format!("{}${}${}${}", SCHEME, ITERATIONS, to_hex(&salt), to_hex(&hash))
+}
+
+fn verify_password(stored: &str, password: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+
+    if parts.len() != 4 || parts[0] != SCHEME {
+        warn!("Unrecognised password hash format");
+        return false;
+    }
+
+    match (parts[1].parse::<u32>(), from_hex(parts[2]), from_hex(parts[3])) {
+        (Ok(iterations), Some(salt), Some(hash)) => {
+            pbkdf2::verify(PBKDF2_ALG, iterations, &salt, password.as_bytes(), &hash).is_ok()
+        },
+        _ => {
+            warn!("Corrupt password hash");
+            false
+        },
+    }
+}
+
+/// Create a random API token, returns the token and the hash for the users file
+pub fn new_token() -> (String, String) {
+    let token = to_hex(&random_bytes(TOKEN_LEN));
+    let hash = hash_token(&token);
+
+    (token, hash)
+}
+
+impl Auth {
+    pub fn disabled() -> Auth {
+        Auth {
+            accounts: None,
+            tokens: HashMap::default(),
+            cookie_key: random_bytes(COOKIE_KEY_LEN),
+        }
+    }
+
+    /// Read a JSON array of users, e.g.
+    /// [{"name": "rpalethorpe", "password": "pbkdf2-sha256$...", "tokens": [],
+    ///   "role": "triager"}]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Auth, Error> {
+        let users: Vec<UserConfig> = serde_json::from_reader(File::open(path)?)?;
+        let mut auth = Auth::disabled();
+        let mut accounts = HashMap::new();
+
+        for user in users.into_iter() {
+            for token in user.tokens.into_iter() {
+                auth.tokens.insert(token, user.name.clone());
+            }
+            accounts.insert(user.name, Account { password: user.password, role: user.role });
+        }
+        auth.accounts = Some(accounts);
+
+        Ok(auth)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.accounts.is_some()
+    }
+
+    pub fn cookie_key(&self) -> &[u8] {
+        &self.cookie_key
+    }
+
+    pub fn login(&self, name: &str, password: &str) -> bool {
+        match self.account(name).and_then(|a| a.password.as_ref()) {
+            Some(stored) => verify_password(stored, password),
+            None => false,
+        }
+    }
+
+    fn account(&self, name: &str) -> Option<&Account> {
+        self.accounts.as_ref().and_then(|a| a.get(name))
+    }
+
+    fn user(&self, name: &str) -> Option<User> {
+        self.account(name).map(|a| User { name: name.to_string(), role: a.role })
+    }
+
+    fn token_user(&self, token: &str) -> Option<&str> {
+        self.tokens.get(&hash_token(token)).map(|name| name.as_str())
+    }
+
+    /// Find who made a request from its session cookie or bearer token
+    pub fn identify<S>(&self, req: &HttpRequest<S>) -> Option<User> {
+        if !self.enabled() {
+            return Some(User::anonymous());
+        }
+
+        if let Some(user) = req.identity().and_then(|name| self.user(&name)) {
+            return Some(user);
+        }
+
+        req.headers().get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| if h.starts_with("Bearer ") { Some(&h[7 ..]) } else { None })
+            .and_then(|token| self.token_user(token.trim()))
+            .and_then(|name| self.user(name))
+    }
+}
+
+/// The user added to the request by RequireUser. Requests for paths which
+/// are not protected have an anonymous viewer.
+pub fn user<S>(req: &HttpRequest<S>) -> User {
+    req.extensions().get::<User>().cloned().unwrap_or_else(|| {
+        User { name: ANONYMOUS.to_string(), role: Role::Viewer }
+    })
+}
+
+/// The request's user if they have the role, otherwise a forbidden error
+pub fn require<S>(req: &HttpRequest<S>, role: Role) -> Result<User> {
+    let user = user(req);
+
+    if user.may(role) {
+        Ok(user)
+    } else {
+        Err(error::ErrorForbidden(format!("{} does not have the {} role", user.name, role)))
+    }
+}
+
+/// Whether a request came from one of our own pages, or from something which
+/// is not a browser. Browsers send Origin with websocket upgrades and
+/// cross-site POSTs, so a page on another site can not use the login cookie
+/// to act as the user.
+pub fn same_origin<S>(req: &HttpRequest<S>) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.to_str().unwrap_or(""),
+        None => return true,
+    };
+    let host = req.headers().get(header::HOST).and_then(|h| h.to_str().ok());
+
+    match (origin.splitn(2, "://").nth(1), host) {
+        (Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// Turn away requests for protected paths which are not from a known user
+pub struct RequireUser;
+
+fn protected(path: &str) -> bool {
+    PROTECTED.iter().any(|p| if *p == "/" { path == "/" } else { path.starts_with(p) })
+}
+
+impl Middleware<AppState> for RequireUser {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        if !protected(req.path()) {
+            return Ok(Started::Done);
+        }
+
+        if let Some(user) = req.state().auth.identify(req) {
+            req.extensions_mut().insert(user);
+            return Ok(Started::Done);
+        }
+
+        Ok(Started::Response(if req.path() == "/" {
+            HttpResponse::SeeOther().header(header::LOCATION, "/login.html").finish()
+        } else {
+            HttpResponse::Unauthorized().body("Log in or send an API token")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn checks_passwords() {
+        let stored = hash_password("hunter2");
+
+        assert!(stored.starts_with("pbkdf2-sha256$100000$"));
+        assert!(verify_password(&stored, "hunter2"));
+        assert!(!verify_password(&stored, "hunter3"));
+        assert!(!verify_password(&stored, ""));
+        assert!(!verify_password("hunter2", "hunter2"));
+        assert!(!verify_password(&stored.replace("pbkdf2-sha256", "md5"), "hunter2"));
+    }
+
+    #[test]
+    fn reads_hex() {
+        assert_eq!(from_hex("00ff7f"), Some(vec![0, 255, 127]));
+        assert_eq!(from_hex("00FF"), Some(vec![0, 255]));
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+1"), None);
+        assert_eq!(from_hex("\u{e9}"), None);
+        assert_eq!(from_hex("a\u{e9}b"), None);
+        assert_eq!(from_hex(&to_hex(b"bug-graph")), Some(b"bug-graph".to_vec()));
+    }
+
+    #[test]
+    fn only_accepts_our_origin() {
+        let req = |origin: Option<&str>, host: &str| {
+            let mut req = TestRequest::with_header("Host", host);
+            if let Some(origin) = origin {
+                req = req.header("Origin", origin);
+            }
+            same_origin(&req.finish())
+        };
+
+        assert!(req(None, "bugs.example.com"));
+        assert!(req(Some("https://bugs.example.com"), "bugs.example.com"));
+        assert!(req(Some("http://Bugs.Example.com:8080"), "bugs.example.com:8080"));
+        assert!(!req(Some("https://evil.example.com"), "bugs.example.com"));
+        assert!(!req(Some("https://bugs.example.com:8080"), "bugs.example.com"));
+        assert!(!req(Some("null"), "bugs.example.com"));
+    }
+
+    fn auth_with_token(token: &str) -> Auth {
+        let mut auth = Auth::disabled();
+        let mut accounts = HashMap::new();
+
+        accounts.insert("alice".to_string(), Account { password: None, role: Role::Triager });
+        auth.accounts = Some(accounts);
+        auth.tokens.insert(hash_token(token), "alice".to_string());
+        auth
+    }
+
+    #[test]
+    fn finds_users_by_bearer_token() {
+        let (token, _) = new_token();
+        let auth = auth_with_token(&token);
+        let identify = |value: &str| {
+            auth.identify(&TestRequest::with_header("Authorization", value).finish())
+                .map(|user| (user.name, user.role))
+        };
+
+        assert_eq!(identify(&format!("Bearer {}", token)),
+                   Some(("alice".to_string(), Role::Triager)));
+        assert_eq!(identify(&format!("Bearer  {} ", token)),
+                   Some(("alice".to_string(), Role::Triager)));
+        assert_eq!(identify(&format!("Basic {}", token)), None);
+        assert_eq!(identify(&token), None);
+        assert_eq!(identify("Bearer 00"), None);
+        assert!(auth.identify(&TestRequest::default().finish()).is_none());
+    }
+
+    #[test]
+    fn disabled_auth_lets_everyone_in() {
+        let user = Auth::disabled().identify(&TestRequest::default().finish()).unwrap();
+
+        assert_eq!(user.name, ANONYMOUS);
+        assert!(user.may(Role::Admin));
+    }
+
+    #[test]
+    fn protects_the_app_but_not_its_files() {
+        for path in &["/", "/ws/", "/api/v1/sets", "/graphql", "/graphiql"] {
+            assert!(protected(path), "{} should be protected", path);
+        }
+        for path in &["/login.html", "/login", "/bug-graph.js", "/apis"] {
+            assert!(!protected(path), "{} should not be protected", path);
+        }
+    }
+}
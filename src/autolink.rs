@@ -0,0 +1,99 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeSet;
+
+use protocol::VertInfo;
+use flaky;
+
+/// A failure of the same test with the same log signature as one which was
+/// linked by hand.
+pub const SAME_SIGNATURE: &str = "same-signature";
+
+/// Links with a lower confidence than this are not made
+pub const THRESHOLD: f32 = 0.5;
+
+/// The user recorded against automatically made links
+pub const AUTO_USER: &str = "auto";
+
+/// The environment properties of a result which are compared when deciding
+/// whether to copy a link. Product revisions are dropped, because the point
+/// is to link failures in new builds.
+pub fn env_features(props: &[VertInfo]) -> BTreeSet<&str> {
+    props.iter()
+        .map(|VertInfo(name, _)| name.as_str())
+        .filter(|name| name.starts_with("environment:"))
+        .map(|name| flaky::env_name(name).unwrap_or(name))
+        .collect()
+}
+
+/// How sure we are that two failures with the same signature have the same
+/// cause, which is how much their environments overlap.
+pub fn confidence(a: &BTreeSet<&str>, b: &BTreeSet<&str>) -> f32 {
+    let union = a.union(b).count();
+
+    if union == 0 {
+        1.0
+    } else {
+        a.intersection(b).count() as f32 / union as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn props(names: &[&str]) -> Vec<VertInfo> {
+        names.iter().map(|name| VertInfo(name.to_string(), Uuid::nil())).collect()
+    }
+
+    #[test]
+    fn compares_environments_without_revisions() {
+        let props = props(&["environment:arch:x86_64", "environment:product:sle:15-sp1",
+                            "environment:kernel:4.12", "test:ltp", "run:42"]);
+        let features: Vec<&str> = env_features(&props).into_iter().collect();
+
+        assert_eq!(features, vec!["environment:arch:x86_64", "environment:kernel:4.12",
+                                  "environment:product:sle"]);
+    }
+
+    #[test]
+    fn confidence_is_the_jaccard_index() {
+        let set = |names: &[&'static str]| names.iter().cloned().collect::<BTreeSet<&str>>();
+        let (a, b, c) = (set(&["x", "y", "z"]), set(&["x", "y", "w"]), set(&["v"]));
+        let near = |x: f32, y: f32| (x - y).abs() < 1e-6;
+
+        assert!(near(confidence(&a, &a), 1.0));
+        assert!(near(confidence(&a, &b), 0.5));
+        assert!(near(confidence(&b, &a), 0.5));
+        assert!(near(confidence(&a, &set(&["x"])), 1.0 / 3.0));
+        assert!(near(confidence(&a, &c), 0.0));
+        assert!(near(confidence(&set(&[]), &set(&[])), 1.0));
+        assert!(near(confidence(&set(&[]), &c), 0.0));
+    }
+
+    #[test]
+    fn half_the_environment_is_enough() {
+        let failed = props(&["environment:arch:x86_64", "environment:product:sle:15"]);
+        let same_arch = props(&["environment:arch:x86_64"]);
+        let other_arch = props(&["environment:arch:s390x", "environment:product:sle:12"]);
+        let env = env_features(&failed);
+
+        assert!(confidence(&env, &env_features(&same_arch)) >= THRESHOLD);
+        assert!(confidence(&env, &env_features(&other_arch)) < THRESHOLD);
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backups are JSON lines: a header saying which version of the format it
+//! is, one record per line and a trailer with the number of records, so a
+//! backup which was cut short is not mistaken for a whole one. Unlike the
+//! data directory's snapshot they can be written and read a record at a
+//! time and do not depend on how the datastore keeps things.
+
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{self, Value as JsonValue};
+use uuid::Uuid;
+
+use protocol::AuditEntry;
+use store::SnapEdge;
+
+const FORMAT: &str = "bug-graph-backup";
+/// Increased when a backup could not be read by older versions
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    /// Seconds since the Unix epoch
+    created: u64,
+}
+
+/// The last line of a backup
+#[derive(Serialize, Deserialize)]
+struct Trailer {
+    /// How many records came before it
+    end: u64,
+}
+
+/// One line of a backup. A backup has all the vertices before the edges and
+/// metadata which refer to them.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Record {
+    /// ID and type
+    Vertex(Uuid, String),
+    Edge(SnapEdge),
+    /// Metadata name, vertex and value
+    VertexMetadata(String, Uuid, JsonValue),
+    EdgeMetadata(String, SnapEdge, JsonValue),
+    /// An entry in the name index
+    Name(String, Uuid),
+    Audit(AuditEntry),
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn write_line<W: Write, T: Serialize>(out: &mut W, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value).map_err(invalid)?;
+    writeln!(out)
+}
+
+/// Writes a backup a record at a time
+pub struct Writer<W: Write> {
+    out: W,
+    count: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a backup by writing its header
+    pub fn new(mut out: W) -> io::Result<Writer<W>> {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+
+        write_line(&mut out, &Header { format: FORMAT.to_string(), version: VERSION, created })?;
+        Ok(Writer { out, count: 0 })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        write_line(&mut self.out, record)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// End the backup with its trailer, flush it and return the number of
+    /// records written
+    pub fn finish(mut self) -> io::Result<u64> {
+        write_line(&mut self.out, &Trailer { end: self.count })?;
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Read a backup, passing each record to `apply` as soon as it is read, and
+/// return the number of records
+pub fn read<R, F>(input: R, mut apply: F) -> io::Result<u64>
+where
+    R: BufRead,
+    F: FnMut(Record) -> io::Result<()>,
+{
+    let mut lines = input.lines();
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)
+            .map_err(|_| invalid("Not a bug-graph backup, the first line is not a header"))?,
+        None => return Err(invalid("The backup is empty")),
+    };
+
+    if header.format != FORMAT {
+        return Err(invalid(format!("Not a bug-graph backup, it is a {}", header.format)));
+    }
+    if header.version != VERSION {
+        return Err(invalid(format!("The backup is version {}, only {} can be read",
+                                   header.version, VERSION)));
+    }
+
+    let mut count = 0;
+    let mut end = None;
+
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+        if end.is_some() {
+            return Err(invalid(format!("Line {}: There is more after the end of the backup",
+                                       i + 2)));
+        }
+
+        let record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => match serde_json::from_str::<Trailer>(&line) {
+                Ok(trailer) => {
+                    end = Some(trailer.end);
+                    continue;
+                },
+                Err(_) => return Err(invalid(format!("Line {}: {}", i + 2, e))),
+            },
+        };
+
+        apply(record).map_err(|e| io::Error::new(e.kind(), format!("Line {}: {}", i + 2, e)))?;
+        count += 1;
+    }
+
+    match end {
+        Some(end) if end == count => Ok(count),
+        Some(end) => Err(invalid(format!("The backup should have {} records, but it has {}",
+                                         end, count))),
+        None => Err(invalid(format!("The backup is incomplete, it ends after {} records",
+                                    count))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let edge = (a, "link".to_string(), b);
+
+        vec![Record::Vertex(a, "test".to_string()),
+             Record::Vertex(b, "bug".to_string()),
+             Record::Edge(edge.clone()),
+             Record::VertexMetadata("name".to_string(), a, JsonValue::from("test:foo")),
+             Record::EdgeMetadata("link".to_string(), edge, JsonValue::from("auto")),
+             Record::Name("test:foo".to_string(), a)]
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let written = records();
+        let mut backup = Vec::new();
+        {
+            let mut out = Writer::new(&mut backup).unwrap();
+            for record in &written {
+                out.write(record).unwrap();
+            }
+            assert_eq!(out.finish().unwrap(), 6);
+        }
+
+        let mut read = Vec::new();
+        assert_eq!(super::read(&backup[..], |r| { read.push(r); Ok(()) }).unwrap(), 6);
+        assert_eq!(serde_json::to_value(&read).unwrap(), serde_json::to_value(&written).unwrap());
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let apply = |_| Ok(());
+
+        assert!(super::read(&b""[..], apply).is_err());
+        assert!(super::read(&b"{\"results\": []}\n"[..], apply).is_err());
+        assert!(super::read(&b"{\"format\": \"bug-graph-backup\", \"version\": 99, \
+                               \"created\": 0}\n"[..], apply).is_err());
+    }
+
+    #[test]
+    fn says_which_line_is_bad() {
+        let mut backup = Vec::new();
+        Writer::new(&mut backup).unwrap();
+        backup.extend_from_slice(b"\n{\"vertex\": 1}\n");
+
+        let e = super::read(&backup[..], |_| Ok(())).unwrap_err();
+        assert!(e.to_string().starts_with("Line 3:"));
+    }
+
+    #[test]
+    fn refuses_truncated_backups() {
+        let mut backup = Vec::new();
+        {
+            let mut out = Writer::new(&mut backup).unwrap();
+            for record in &records() {
+                out.write(record).unwrap();
+            }
+            out.finish().unwrap();
+        }
+        let whole = backup.len();
+        let apply = |_| Ok(());
+
+        assert!(super::read(&backup[..], apply).is_ok());
+
+        let trailer = backup[..whole - 1].iter().rposition(|&c| c == b'\n').unwrap() + 1;
+        let e = super::read(&backup[..trailer], apply).unwrap_err();
+        assert!(e.to_string().starts_with("The backup is incomplete"));
+
+        let last = backup[..trailer - 1].iter().rposition(|&c| c == b'\n').unwrap() + 1;
+        let mut missing = backup[..last].to_vec();
+        missing.extend_from_slice(&backup[trailer..]);
+        assert!(super::read(&missing[..], apply).is_err());
+
+        backup.extend_from_slice(b"{\"name\": [\"test:bar\", \"\"]}\n");
+        assert!(super::read(&backup[..], apply).is_err());
+    }
+}
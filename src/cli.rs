@@ -0,0 +1,328 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The subcommands which run without the web server, for scripts. Results
+//! are printed to stdout as a table, JSON or CSV; logs still go to stderr.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use futures::{future, stream, Future, Stream};
+use futures::future::Either;
+use actix::prelude::*;
+use serde::Serialize;
+use serde_json;
+use uuid::Uuid;
+
+use repo::{Repo, GetVertId, GetRevisionDiff, GetResultMatrix, GetSetReport, GetGraph, Search,
+           Save, Backup, Restore};
+use imp::{Importer, ImportFile, ScanDir};
+use export::{ExportFormat, Graph};
+use sheet::csv_field;
+use protocol::VertInfo;
+
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    /// Aligned columns for people
+    Table,
+    /// The same structures the websocket API sends
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn new(headers: Vec<&'static str>) -> Table {
+        Table { headers, rows: Vec::new() }
+    }
+
+    fn row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    fn print_columns(&self) {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+
+        for row in &self.rows {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+
+        let line = |cells: Vec<&str>| {
+            let padded: Vec<String> = cells.iter().zip(&widths)
+                .map(|(c, &w)| format!("{:1$}", c, w))
+                .collect();
+
+            println!("{}", padded.join("  ").trim_right());
+        };
+
+        line(self.headers.clone());
+        for row in &self.rows {
+            line(row.iter().map(|c| c.as_str()).collect());
+        }
+    }
+
+    fn print_csv(&self) {
+        println!("{}", self.headers.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","));
+        for row in &self.rows {
+            println!("{}", row.iter().map(|c| csv_field(c)).collect::<Vec<_>>().join(","));
+        }
+    }
+}
+
+/// Print `value` as JSON, or as the table `table` makes of it
+fn print<T, F>(format: OutputFormat, value: &T, table: F) -> Result<(), ()>
+where
+    T: Serialize,
+    F: FnOnce(&T) -> Table,
+{
+    match format {
+        OutputFormat::Table => table(value).print_columns(),
+        OutputFormat::Csv => table(value).print_csv(),
+        OutputFormat::Json => {
+            let json = serde_json::to_string_pretty(value)
+                .map_err(|e| eprintln!("Could not serialise the answer: {}", e))?;
+
+            println!("{}", json);
+        },
+    }
+    Ok(())
+}
+
+fn ask<M>(repo: &Addr<Repo>, msg: M) -> impl Future<Item = M::Result, Error = ()>
+where
+    M: Message + Send + 'static,
+    M::Result: Send,
+    Repo: Handler<M>,
+{
+    repo.send(msg).map_err(|e| eprintln!("Could not query the repository: {}", e))
+}
+
+/// Look up a vertex by its UUID or name
+fn find_vert(repo: &Addr<Repo>, name: String) -> impl Future<Item = Uuid, Error = ()> {
+    match Uuid::parse_str(&name) {
+        Ok(id) => Either::A(future::ok(id)),
+        Err(_) => Either::B(ask(repo, GetVertId(name.clone()))
+                            .and_then(move |vert| vert.ok_or_else(|| {
+                                eprintln!("Nothing is called {}", name)
+                            }))),
+    }
+}
+
+/// Look up a product revision's set vertex, the environment:product: prefix
+/// may be left off.
+fn find_revision(repo: &Addr<Repo>, rev: String) -> impl Future<Item = Uuid, Error = ()> {
+    if rev.starts_with("environment:product:") || Uuid::parse_str(&rev).is_ok() {
+        find_vert(repo, rev)
+    } else {
+        find_vert(repo, format!("environment:product:{}", rev))
+    }
+}
+
+/// Import results files, and the results files in directories, then save
+/// the repository
+pub fn import(importer: Addr<Importer>, repo: Addr<Repo>, paths: Vec<String>, user: String)
+              -> impl Future<Item = (), Error = ()> {
+    stream::iter_ok(paths)
+        .fold(true, move |ok, path| {
+            let user = user.clone();
+
+            if Path::new(&path).is_dir() {
+                return Either::A(importer.send(ScanDir { dir: path.clone(), ext: "json".into(),
+                                                         user })
+                                 .map(move |failed| if failed > 0 {
+                                     eprintln!("Could not import {} files from {}", failed, path);
+                                     false
+                                 } else {
+                                     ok
+                                 })
+                                 .map_err(|e| eprintln!("Could not scan directory: {}", e)));
+            }
+
+            let json = match fs::read_to_string(&path) {
+                Ok(json) => json,
+                Err(e) => {
+                    eprintln!("Could not read {}: {}", path, e);
+                    return Either::B(Either::A(future::ok(false)));
+                },
+            };
+
+            Either::B(Either::B(importer.send(ImportFile { name: path.clone(), json, user })
+                                .map_err(|e| eprintln!("Could not import: {}", e))
+                                .map(move |res| match res {
+                                    Ok(count) => {
+                                        info!("Imported {} results from {}", count, path);
+                                        ok
+                                    },
+                                    Err(e) => {
+                                        eprintln!("Could not import {}: {}", path, e);
+                                        false
+                                    },
+                                })))
+        })
+        .and_then(move |ok| {
+            ask(&repo, Save).and_then(move |res| match res {
+                Ok(()) if ok => Ok(()),
+                Ok(()) => Err(()),
+                Err(e) => Err(eprintln!("Could not save the repository: {}", e)),
+            })
+        })
+}
+
+/// Write everything in the repository to a backup file
+pub fn backup(repo: Addr<Repo>, path: String) -> impl Future<Item = (), Error = ()> {
+    ask(&repo, Backup(PathBuf::from(path)))
+        .and_then(|res| match res {
+            Ok(records) => {
+                info!("Wrote {} records", records);
+                Ok(())
+            },
+            Err(e) => Err(eprintln!("Could not write the backup {}", e)),
+        })
+}
+
+/// Replace the repository with a backup file's contents, then save it
+pub fn restore(repo: Addr<Repo>, path: String) -> impl Future<Item = (), Error = ()> {
+    ask(&repo, Restore(PathBuf::from(path)))
+        .and_then(|res| res.map_err(|e| eprintln!("Could not restore the backup {}", e)))
+        .and_then(move |_| ask(&repo, Save))
+        .and_then(|res| res.map_err(|e| eprintln!("Could not save the repository: {}", e)))
+}
+
+/// Every test's results in a set, or a test's results in every set
+pub fn matrix(repo: Addr<Repo>, name: String, format: OutputFormat)
+              -> impl Future<Item = (), Error = ()> {
+    find_vert(&repo, name)
+        .and_then(move |id| ask(&repo, GetResultMatrix(id)))
+        .and_then(move |matrix| print(format, &matrix, |matrix| {
+            let mut table = Table::new(vec!["Set", "Test", "Passes", "Fails", "Flaky"]);
+
+            for (VertInfo(set, _), results) in &matrix.results {
+                for r in results {
+                    table.row(vec![set.clone(),
+                                   matrix.test_cases[r.test_case as usize].0.clone(),
+                                   r.passes.to_string(),
+                                   r.fails.to_string(),
+                                   r.flaky.to_string()]);
+                }
+            }
+            table.rows.sort();
+            table
+        }))
+}
+
+pub fn search(repo: Addr<Repo>, term: String, format: OutputFormat)
+              -> impl Future<Item = (), Error = ()> {
+    ask(&repo, Search(term))
+        .and_then(move |found| print(format, &found, |found| {
+            let mut table = Table::new(vec!["Name", "ID"]);
+
+            for (name, id) in found {
+                table.row(vec![name.clone(), id.to_string()]);
+            }
+            table
+        }))
+}
+
+/// Whether each test passed or failed in a product revision
+pub fn report(repo: Addr<Repo>, rev: String, format: OutputFormat)
+              -> impl Future<Item = (), Error = ()> {
+    find_revision(&repo, rev)
+        .and_then(move |id| ask(&repo, GetSetReport(id)))
+        .and_then(move |report| print(format, &report, |report| {
+            let mut table = Table::new(vec!["Test", "Status", "Passes", "Fails", "Flaky"]);
+
+            for o in report {
+                let status = if o.fails == 0 { "pass" } else { "fail" };
+
+                table.row(vec![o.test.0.clone(), status.to_string(), o.passes.to_string(),
+                               o.fails.to_string(), o.flaky.to_string()]);
+            }
+            table.rows.sort();
+            table
+        }))
+}
+
+/// The tests whose status changed between two product revisions
+pub fn diff(repo: Addr<Repo>, from: String, to: String, format: OutputFormat)
+            -> impl Future<Item = (), Error = ()> {
+    let repo2 = repo.clone();
+
+    find_revision(&repo, from)
+        .join(find_revision(&repo, to))
+        .and_then(move |(from, to)| ask(&repo2, GetRevisionDiff { from, to }))
+        .and_then(move |diff| print(format, &diff, |diff| {
+            let mut table = Table::new(vec!["Change", "Test"]);
+            let changes = [("newly failing", &diff.newly_failing),
+                           ("newly passing", &diff.newly_passing),
+                           ("still failing", &diff.still_failing),
+                           ("missing", &diff.missing)];
+
+            for (change, tests) in changes.iter() {
+                for VertInfo(test, _) in tests.iter() {
+                    table.row(vec![change.to_string(), test.clone()]);
+                }
+            }
+            table
+        }))
+}
+
+fn write_graph<W: Write>(graph: &Graph, format: ExportFormat, out: W) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+
+    graph.write(format, &mut out)?;
+    out.flush()
+}
+
+/// Write the graph, or the part of it around `root`, to a file or stdout
+pub fn export(repo: Addr<Repo>, root: Option<String>, depth: u32, logs: bool,
+              format: ExportFormat, output: Option<String>)
+              -> impl Future<Item = (), Error = ()> {
+    let root = match root {
+        Some(name) => Either::A(find_vert(&repo, name).map(Some)),
+        None => Either::B(future::ok(None)),
+    };
+
+    root.and_then(move |root| ask(&repo, GetGraph { root, depth, logs }))
+        .and_then(|graph| graph.map_err(|e| eprintln!("Could not export the graph: {}", e)))
+        .and_then(move |graph| {
+            let res = match output {
+                Some(path) => File::create(path).and_then(|f| write_graph(&graph, format, f)),
+                None => write_graph(&graph, format, io::stdout().lock()),
+            };
+
+            res.map_err(|e| eprintln!("Could not write the graph: {}", e))
+        })
+}
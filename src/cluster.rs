@@ -0,0 +1,266 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+
+use uuid::Uuid;
+
+use protocol::{VertInfo, ClusteredResult, FailureCluster};
+
+/// Lines containing these are what make a failure, the rest is usually noise
+const FAIL_MARKERS: [&str; 3] = ["TFAIL", "TBROK", "TWARN"];
+/// Minimum Jaccard index of two logs' words for them to share a cluster
+const SIMILARITY: f32 = 0.7;
+/// Lines of the normalised log shown as a cluster's sample
+const SAMPLE_LINES: usize = 20;
+
+/// A failed test result and its log
+pub struct Failure {
+    pub test: VertInfo,
+    pub result: Uuid,
+    pub log: String,
+}
+
+/// Replace numbers, hex addresses and hashes in a word with '#'. This takes
+/// care of PIDs, timestamps, addresses and line numbers.
+fn normalise_word(word: &str) -> Cow<str> {
+    let trimmed = word.trim_matches(|c: char| !c.is_alphanumeric());
+
+    if !word.chars().any(|c| c.is_digit(10)) {
+        return Cow::Borrowed(word);
+    }
+
+    if trimmed.len() >= 8 && trimmed.chars().all(|c| c.is_digit(16)) {
+        return Cow::Borrowed("#");
+    }
+
+    let mut norm = String::with_capacity(word.len());
+    let mut chars = word.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let hex = c == '0' && {
+            let mut ahead = chars.clone();
+
+            ahead.next() == Some('x') && ahead.next().map_or(false, |c| c.is_digit(16))
+        };
+
+        if hex {
+            chars.next();
+            while chars.peek().map_or(false, |c| c.is_digit(16)) {
+                chars.next();
+            }
+            norm.push('#');
+        } else if c.is_digit(10) {
+            while chars.peek().map_or(false, |c| c.is_digit(10)) {
+                chars.next();
+            }
+            norm.push('#');
+        } else {
+            norm.push(c);
+        }
+    }
+
+    Cow::Owned(norm)
+}
+
+/// Strip the parts of a log which change between runs of the same failure
+/// and collapse white space. Empty lines are removed.
+fn normalise(log: &str) -> String {
+    let mut norm = String::with_capacity(log.len());
+
+    for line in log.lines() {
+        let mut words = line.split_whitespace().map(normalise_word).peekable();
+
+        if words.peek().is_none() {
+            continue;
+        }
+
+        for (i, word) in words.enumerate() {
+            if i > 0 {
+                norm.push(' ');
+            }
+            norm.push_str(&word);
+        }
+        norm.push('\n');
+    }
+
+    norm
+}
+
+/// The lines of a normalised log which describe the failure. If none of the
+/// lines are marked as failures then all of them are returned.
+fn failure_lines(norm: &str) -> Vec<&str> {
+    let marked: Vec<&str> = norm.lines()
+        .filter(|l| FAIL_MARKERS.iter().any(|m| l.contains(m)))
+        .collect();
+
+    if marked.len() > 0 {
+        marked
+    } else {
+        norm.lines().collect()
+    }
+}
+
+/// A stable hash (64bit FNV-1a) of a failure's lines, printed as hex
+fn signature(lines: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for line in lines {
+        for b in line.bytes().chain(Some(b'\n')) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    format!("{:016x}", hash)
+}
+
+/// Normalise a log and find its failure signature
+pub fn log_signature(log: &str) -> String {
+    signature(&failure_lines(&normalise(log)))
+}
+
+fn words<'a>(lines: &[&'a str]) -> HashSet<&'a str> {
+    lines.iter().flat_map(|l| l.split(' ')).collect()
+}
+
+fn similarity(a: &HashSet<&str>, b: &HashSet<&str>) -> f32 {
+    let union = a.union(b).count();
+
+    if union == 0 {
+        1.0
+    } else {
+        a.intersection(b).count() as f32 / union as f32
+    }
+}
+
+struct Group {
+    signature: String,
+    lines: Vec<String>,
+    results: Vec<ClusteredResult>,
+}
+
+/// Group failures with the same signature, then merge groups which have
+/// similar logs. The biggest clusters are first.
+pub fn cluster(failures: Vec<Failure>) -> Vec<FailureCluster> {
+    let mut groups = BTreeMap::<String, Group>::new();
+
+    for failure in failures.into_iter() {
+        let norm = normalise(&failure.log);
+        let lines = failure_lines(&norm);
+        let sig = signature(&lines);
+
+        groups.entry(sig.clone())
+            .or_insert_with(|| Group {
+                signature: sig,
+                lines: lines.iter().map(|l| l.to_string()).collect(),
+                results: Vec::new(),
+            })
+            .results.push(ClusteredResult {
+                test: failure.test,
+                result: failure.result,
+            });
+    }
+
+    let mut groups: Vec<Group> = groups.into_iter().map(|(_, g)| g).collect();
+    groups.sort_by(|a, b| b.results.len().cmp(&a.results.len()));
+
+    let mut clusters: Vec<Group> = Vec::new();
+    for group in groups.into_iter() {
+        let found = {
+            let lines: Vec<&str> = group.lines.iter().map(|l| l.as_str()).collect();
+            let group_words = words(&lines);
+
+            clusters.iter().position(|c| {
+                let lines: Vec<&str> = c.lines.iter().map(|l| l.as_str()).collect();
+                similarity(&words(&lines), &group_words) >= SIMILARITY
+            })
+        };
+
+        match found {
+            Some(i) => clusters[i].results.extend(group.results),
+            None => clusters.push(group),
+        }
+    }
+
+    clusters.sort_by(|a, b| b.results.len().cmp(&a.results.len()));
+    clusters.into_iter().map(|c| FailureCluster {
+        signature: c.signature,
+        sample: c.lines.iter().take(SAMPLE_LINES).fold(String::new(), |mut s, l| {
+            s.push_str(l);
+            s.push('\n');
+            s
+        }),
+        results: c.results,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(test: &str, log: &str) -> Failure {
+        Failure {
+            test: VertInfo(test.to_string(), Uuid::new_v4()),
+            result: Uuid::new_v4(),
+            log: log.to_string(),
+        }
+    }
+
+    #[test]
+    fn normalises_numbers_and_addresses() {
+        assert_eq!(normalise_word("pid=1234,"), "pid=#,");
+        assert_eq!(normalise_word("0xdeadbeef"), "#");
+        assert_eq!(normalise_word("at:0x7ffd12"), "at:#");
+        assert_eq!(normalise_word("c0ffee42"), "#");
+        assert_eq!(normalise_word("TFAIL"), "TFAIL");
+    }
+
+    #[test]
+    fn zero_x_without_hex_is_not_an_address() {
+        assert_eq!(normalise_word("0xyz"), "#xyz");
+        assert_eq!(normalise_word("0x"), "#x");
+    }
+
+    #[test]
+    fn normalise_collapses_white_space_and_drops_empty_lines() {
+        assert_eq!(normalise("  foo.c:12:   TFAIL  bar\n\n\tbaz 7\n"),
+                   "foo.c:#: TFAIL bar\nbaz #\n");
+    }
+
+    #[test]
+    fn signature_only_uses_failure_lines() {
+        let a = "INFO: pid 100\nfoo.c:10: TFAIL: read() failed\n";
+        let b = "INFO: pid 200 took 3s\nfoo.c:11: TFAIL: read() failed\n";
+
+        assert_eq!(log_signature(a), log_signature(b));
+        assert_ne!(log_signature(a), log_signature("foo.c:10: TBROK: oops\n"));
+    }
+
+    #[test]
+    fn clusters_similar_failures() {
+        let clusters = cluster(vec![
+            failure("a", "a.c:1: TFAIL: mmap failed: ENOMEM (12)"),
+            failure("b", "b.c:2: TFAIL: mmap failed: ENOMEM (12)"),
+            failure("c", "c.c:3: TFAIL: mmap failed: ENOMEM (12)"),
+            failure("d", "d.c:4: TBROK: fork() failed: EAGAIN"),
+        ]);
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].results.len(), 3);
+        assert_eq!(clusters[1].results.len(), 1);
+    }
+}
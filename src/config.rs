@@ -0,0 +1,306 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The TOML config file. The command line flags take precedence over it,
+//! each key is set by the flag
+//!
+//! | Key                | Flag              |
+//! |--------------------|-------------------|
+//! | `data`             | `--data`          |
+//! | `web.listen`       | `--web`           |
+//! | `web.users`        | `--users`         |
+//! | `web.tls-cert`     | `--tls-cert`      |
+//! | `web.tls-key`      | `--tls-key`       |
+//! | `web.static-dir`   | `--static-dir`    |
+//! | `import.watch`     | `--watch`         |
+//! | `import.interval`  | none              |
+//! | `log.level`        | `--log`           |
+//! | `log.format`       | `--log-format`    |
+//! | `log.file`         | `--log-file`      |
+//! | `log.file-size`    | `--log-file-size` |
+//! | `log.file-keep`    | `--log-file-keep` |
+//! | `log.keep`         | `--log-keep`      |
+//!
+//! The log levels and watched directories are read again on SIGHUP.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix::actors::signal::{self, ProcessSignals, SignalType};
+use toml;
+
+use imp::{Importer, WatchDirs};
+use journal::{self, Filter, Format};
+
+const DEFAULT_LISTEN: &str = "localhost:8080";
+const DEFAULT_LOG_FILE_SIZE: u64 = 10;
+const DEFAULT_LOG_FILE_KEEP: usize = 5;
+const DEFAULT_LOG_KEEP: usize = 1000;
+const DEFAULT_WATCH_INTERVAL: u64 = 60;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Where the repository's snapshot is kept
+    pub data: Option<String>,
+    pub web: WebConfig,
+    pub import: ImportConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WebConfig {
+    pub listen: Option<String>,
+    pub users: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub static_dir: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ImportConfig {
+    /// Directories scanned for new results files while serving
+    pub watch: Vec<String>,
+    /// Seconds between scans of the watched directories
+    pub interval: Option<u64>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    pub level: Option<String>,
+    pub format: Option<String>,
+    pub file: Option<String>,
+    /// MiB
+    pub file_size: Option<u64>,
+    pub file_keep: Option<usize>,
+    pub keep: Option<usize>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// These settings, with any which are missing taken from `file`
+    pub fn or(self, file: Config) -> Config {
+        Config {
+            data: self.data.or(file.data),
+            web: WebConfig {
+                listen: self.web.listen.or(file.web.listen),
+                users: self.web.users.or(file.web.users),
+                tls_cert: self.web.tls_cert.or(file.web.tls_cert),
+                tls_key: self.web.tls_key.or(file.web.tls_key),
+                static_dir: self.web.static_dir.or(file.web.static_dir),
+            },
+            import: ImportConfig {
+                watch: if self.import.watch.is_empty() {
+                    file.import.watch
+                } else {
+                    self.import.watch
+                },
+                interval: self.import.interval.or(file.import.interval),
+            },
+            log: LogConfig {
+                level: self.log.level.or(file.log.level),
+                format: self.log.format.or(file.log.format),
+                file: self.log.file.or(file.log.file),
+                file_size: self.log.file_size.or(file.log.file_size),
+                file_keep: self.log.file_keep.or(file.log.file_keep),
+                keep: self.log.keep.or(file.log.keep),
+            },
+        }
+    }
+
+    /// Find everything wrong with the settings, one problem per line
+    pub fn check(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        if let Some(ref level) = self.log.level {
+            if let Err(e) = level.parse::<Filter>() {
+                problems.push(format!("log.level: {}", e));
+            }
+        }
+        if let Some(ref format) = self.log.format {
+            if let Err(e) = format.parse::<Format>() {
+                problems.push(format!("log.format: {}", e));
+            }
+        }
+        if self.log.file_size == Some(0) {
+            problems.push("log.file-size: must be at least 1 MiB".to_string());
+        }
+        match (&self.web.tls_cert, &self.web.tls_key) {
+            (Some(_), None) => problems.push("web.tls-cert: needs web.tls-key".to_string()),
+            (None, Some(_)) => problems.push("web.tls-key: needs web.tls-cert".to_string()),
+            _ => (),
+        }
+        for dir in &self.import.watch {
+            if !Path::new(dir).is_dir() {
+                problems.push(format!("import.watch: {} is not a directory", dir));
+            }
+        }
+        if self.import.interval == Some(0) {
+            problems.push("import.interval: must be at least 1 second".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
+    pub fn listen(&self) -> String {
+        self.web.listen.clone().unwrap_or_else(|| DEFAULT_LISTEN.to_string())
+    }
+
+    pub fn tls(&self) -> Option<(String, String)> {
+        match (&self.web.tls_cert, &self.web.tls_key) {
+            (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn watch_interval(&self) -> Duration {
+        Duration::from_secs(self.import.interval.unwrap_or(DEFAULT_WATCH_INTERVAL))
+    }
+
+    pub fn log_filter(&self) -> Filter {
+        self.log.level.as_ref().and_then(|l| l.parse().ok()).unwrap_or_default()
+    }
+
+    pub fn log_format(&self) -> Format {
+        self.log.format.as_ref().and_then(|f| f.parse().ok()).unwrap_or(Format::Text)
+    }
+
+    /// The log file's path, size limit in bytes and how many old ones to keep
+    pub fn log_file(&self) -> Option<(String, u64, usize)> {
+        self.log.file.clone().map(|path| {
+            (path,
+             self.log.file_size.unwrap_or(DEFAULT_LOG_FILE_SIZE) * 1024 * 1024,
+             self.log.file_keep.unwrap_or(DEFAULT_LOG_FILE_KEEP))
+        })
+    }
+
+    pub fn log_keep(&self) -> usize {
+        self.log.keep.unwrap_or(DEFAULT_LOG_KEEP)
+    }
+}
+
+/// Reads the config file again on SIGHUP and applies the settings which can
+/// change while running, a file with mistakes in is ignored
+pub struct Reloader {
+    pub path: String,
+    /// The command line's settings, which still take precedence
+    pub flags: Config,
+    pub importer: Addr<Importer>,
+    /// Who watched files are recorded as imported by
+    pub user: String,
+}
+
+impl Reloader {
+    fn reload(&self) -> Result<(), String> {
+        let config = self.flags.clone().or(Config::load(&self.path)?);
+
+        config.check()?;
+        journal::set_filter(config.log_filter());
+        self.importer.do_send(WatchDirs {
+            dirs: config.import.watch.clone(),
+            every: config.watch_interval(),
+            user: self.user.clone(),
+        });
+        Ok(())
+    }
+}
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        System::current().registry().get::<ProcessSignals>()
+            .do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, sig: signal::Signal, _ctx: &mut Self::Context) {
+        if let SignalType::Hup = sig.0 {
+            match self.reload() {
+                Ok(()) => info!("Reloaded {}", self.path),
+                Err(e) => error!("Not reloading {}:\n{}", self.path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let mut flags = Config::default();
+        flags.data = Some("flags".to_string());
+        let file: Config = toml::from_str(r#"
+            data = "file"
+            [web]
+            listen = "0.0.0.0:80"
+            [import]
+            watch = ["/srv/results"]
+        "#).unwrap();
+        let config = flags.clone().or(file.clone());
+
+        assert_eq!(config.data, Some("flags".to_string()));
+        assert_eq!(config.listen(), "0.0.0.0:80");
+        assert_eq!(config.import.watch, vec!["/srv/results".to_string()]);
+
+        flags.import.watch = vec!["/tmp".to_string()];
+        assert_eq!(flags.or(file).import.watch, vec!["/tmp".to_string()]);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(toml::from_str::<Config>("[web]\nport = 80").is_err());
+    }
+
+    #[test]
+    fn check_finds_every_problem() {
+        assert!(Config::default().check().is_ok());
+
+        let config: Config = toml::from_str(r#"
+            [web]
+            tls-cert = "cert.pem"
+            [import]
+            watch = ["/does/not/exist"]
+            interval = 0
+            [log]
+            level = "loud"
+            file-size = 0
+        "#).unwrap();
+        let problems = config.check().unwrap_err();
+
+        assert_eq!(problems.lines().count(), 5);
+        assert!(problems.contains("web.tls-cert"));
+        assert!(problems.contains("/does/not/exist"));
+    }
+}
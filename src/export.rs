@@ -0,0 +1,362 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Writes the graph, or the part of it around one vertex, as GraphML, DOT or
+//! JSON Graph Format so that it can be looked at with other tools.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use serde_json::{self, Value as JsonValue};
+use uuid::Uuid;
+
+use repo::NAME_MD;
+use store::{Snapshot, SnapEdge};
+
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    GraphMl,
+    Dot,
+    /// JSON Graph Format, http://jsongraphformat.info
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s {
+            "graphml" => Ok(ExportFormat::GraphMl),
+            "dot" => Ok(ExportFormat::Dot),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("Unknown graph format: {}", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::GraphMl => "application/graphml+xml",
+            ExportFormat::Dot => "text/vnd.graphviz",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::GraphMl => "graphml",
+            ExportFormat::Dot => "dot",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+pub struct Node {
+    pub id: Uuid,
+    pub t: String,
+    pub name: Option<String>,
+    pub properties: BTreeMap<String, JsonValue>,
+}
+
+pub struct Edge {
+    pub source: Uuid,
+    pub t: String,
+    pub target: Uuid,
+    pub properties: BTreeMap<String, JsonValue>,
+}
+
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Make a graph from a snapshot of part of the repository, `name_of`
+    /// gives the vertices' names. Edges to vertices which are not in the
+    /// snapshot are left out.
+    pub fn new<F>(snap: Snapshot, name_of: F) -> Graph
+    where
+        F: Fn(&Uuid) -> Option<String>,
+    {
+        let kept: HashSet<Uuid> = snap.vertices.iter().map(|&(id, _)| id).collect();
+
+        let mut vertex_props = HashMap::<Uuid, BTreeMap<String, JsonValue>>::new();
+        for (name, id, value) in snap.vertex_metadata {
+            if name != NAME_MD {
+                vertex_props.entry(id).or_insert_with(BTreeMap::new).insert(name, value);
+            }
+        }
+
+        let mut edge_props = HashMap::<SnapEdge, BTreeMap<String, JsonValue>>::new();
+        for (name, edge, value) in snap.edge_metadata {
+            edge_props.entry(edge).or_insert_with(BTreeMap::new).insert(name, value);
+        }
+
+        let nodes = snap.vertices.into_iter()
+            .map(|(id, t)| Node {
+                id,
+                t,
+                name: name_of(&id),
+                properties: vertex_props.remove(&id).unwrap_or_default(),
+            })
+            .collect();
+        let edges = snap.edges.into_iter()
+            .filter(|&(outbound, _, inbound)| kept.contains(&outbound) && kept.contains(&inbound))
+            .map(|edge| Edge {
+                properties: edge_props.remove(&edge).unwrap_or_default(),
+                source: edge.0,
+                t: edge.1,
+                target: edge.2,
+            })
+            .collect();
+
+        Graph { nodes, edges }
+    }
+
+    pub fn write<W: Write>(&self, format: ExportFormat, out: &mut W) -> io::Result<()> {
+        match format {
+            ExportFormat::GraphMl => self.write_graphml(out),
+            ExportFormat::Dot => self.write_dot(out),
+            ExportFormat::Json => self.write_json(out),
+        }
+    }
+
+    fn write_graphml<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let node_keys: BTreeSet<&String> = self.nodes.iter()
+            .flat_map(|n| n.properties.keys())
+            .collect();
+        let edge_keys: BTreeSet<&String> = self.edges.iter()
+            .flat_map(|e| e.properties.keys())
+            .collect();
+
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(out, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+        writeln!(out, "  <key id=\"type\" for=\"all\" attr.name=\"type\" \
+                       attr.type=\"string\"/>")?;
+        writeln!(out, "  <key id=\"name\" for=\"node\" attr.name=\"name\" \
+                       attr.type=\"string\"/>")?;
+        for key in &node_keys {
+            writeln!(out, "  <key id=\"node.{0}\" for=\"node\" attr.name=\"{0}\" \
+                           attr.type=\"string\"/>", xml_escape(key))?;
+        }
+        for key in &edge_keys {
+            writeln!(out, "  <key id=\"edge.{0}\" for=\"edge\" attr.name=\"{0}\" \
+                           attr.type=\"string\"/>", xml_escape(key))?;
+        }
+
+        writeln!(out, "  <graph id=\"bug-graph\" edgedefault=\"directed\">")?;
+        for node in &self.nodes {
+            writeln!(out, "    <node id=\"{}\">", node.id)?;
+            writeln!(out, "      <data key=\"type\">{}</data>", xml_escape(&node.t))?;
+            if let Some(ref name) = node.name {
+                writeln!(out, "      <data key=\"name\">{}</data>", xml_escape(name))?;
+            }
+            for (key, value) in &node.properties {
+                writeln!(out, "      <data key=\"node.{}\">{}</data>",
+                         xml_escape(key), xml_escape(&property(value)))?;
+            }
+            writeln!(out, "    </node>")?;
+        }
+        for edge in &self.edges {
+            writeln!(out, "    <edge source=\"{}\" target=\"{}\">", edge.source, edge.target)?;
+            writeln!(out, "      <data key=\"type\">{}</data>", xml_escape(&edge.t))?;
+            for (key, value) in &edge.properties {
+                writeln!(out, "      <data key=\"edge.{}\">{}</data>",
+                         xml_escape(key), xml_escape(&property(value)))?;
+            }
+            writeln!(out, "    </edge>")?;
+        }
+        writeln!(out, "  </graph>")?;
+        writeln!(out, "</graphml>")
+    }
+
+    fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph \"bug-graph\" {{")?;
+        for node in &self.nodes {
+            let label = node.name.as_ref().unwrap_or(&node.t);
+
+            write!(out, "  \"{}\" [label={}, type={}", node.id, dot_string(label),
+                   dot_string(&node.t))?;
+            for (key, value) in &node.properties {
+                write!(out, ", {}={}", dot_string(key), dot_string(&property(value)))?;
+            }
+            writeln!(out, "];")?;
+        }
+        for edge in &self.edges {
+            write!(out, "  \"{}\" -> \"{}\" [label={}", edge.source, edge.target,
+                   dot_string(&edge.t))?;
+            for (key, value) in &edge.properties {
+                write!(out, ", {}={}", dot_string(key), dot_string(&property(value)))?;
+            }
+            writeln!(out, "];")?;
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        #[derive(Serialize)]
+        struct JgfNode<'a> {
+            id: Uuid,
+            label: &'a str,
+            metadata: JgfMetadata<'a>,
+        }
+
+        #[derive(Serialize)]
+        struct JgfEdge<'a> {
+            source: Uuid,
+            target: Uuid,
+            relation: &'a str,
+            metadata: &'a BTreeMap<String, JsonValue>,
+        }
+
+        #[derive(Serialize)]
+        struct JgfMetadata<'a> {
+            #[serde(rename = "type")]
+            t: &'a str,
+            #[serde(flatten)]
+            properties: &'a BTreeMap<String, JsonValue>,
+        }
+
+        #[derive(Serialize)]
+        struct JgfGraph<'a> {
+            directed: bool,
+            nodes: Vec<JgfNode<'a>>,
+            edges: Vec<JgfEdge<'a>>,
+        }
+
+        #[derive(Serialize)]
+        struct Jgf<'a> {
+            graph: JgfGraph<'a>,
+        }
+
+        let graph = JgfGraph {
+            directed: true,
+            nodes: self.nodes.iter().map(|n| JgfNode {
+                id: n.id,
+                label: n.name.as_ref().unwrap_or(&n.t),
+                metadata: JgfMetadata { t: &n.t, properties: &n.properties },
+            }).collect(),
+            edges: self.edges.iter().map(|e| JgfEdge {
+                source: e.source,
+                target: e.target,
+                relation: &e.t,
+                metadata: &e.properties,
+            }).collect(),
+        };
+
+        serde_json::to_writer(&mut *out, &Jgf { graph })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        writeln!(out)
+    }
+}
+
+/// Strings are written as they are, anything else as JSON
+fn property(value: &JsonValue) -> String {
+    match *value {
+        JsonValue::String(ref s) => s.clone(),
+        ref v => v.to_string(),
+    }
+}
+
+/// Escape text for XML 1.0. Most control characters, such as the escape
+/// codes in test logs, may not appear at all, even as character references,
+/// so they are replaced with U+FFFD.
+pub fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{0}' ..= '\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// A quoted DOT ID
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> (Snapshot, Uuid) {
+        let (result, test, outside) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let snap = Snapshot {
+            vertices: vec![(result, "result".to_string()), (test, "test".to_string())],
+            edges: vec![(result, "isin".to_string(), test),
+                        (result, "isin".to_string(), outside)],
+            vertex_metadata: vec![
+                (NAME_MD.to_string(), test, JsonValue::from("test:foo")),
+                ("log".to_string(), result, JsonValue::from("\u{1b}[31mFAIL\u{1b}[0m")),
+            ],
+            ..Snapshot::default()
+        };
+
+        (snap, result)
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(xml_escape("<a href=\"x\">'&'</a>"),
+                   "&lt;a href=&quot;x&quot;&gt;&apos;&amp;&apos;&lt;/a&gt;");
+        assert_eq!(xml_escape("tab\tline\r\n"), "tab\tline\r\n");
+    }
+
+    #[test]
+    fn replaces_characters_xml_forbids() {
+        assert_eq!(xml_escape("\u{1b}[0m\u{0}\u{ffff}ok"), "\u{fffd}[0m\u{fffd}\u{fffd}ok");
+    }
+
+    #[test]
+    fn escapes_dot_strings() {
+        assert_eq!(dot_string("a \"b\"\\\nc"), "\"a \\\"b\\\"\\\\\\nc\"");
+    }
+
+    #[test]
+    fn leaves_out_names_and_edges_to_missing_vertices() {
+        let (snap, _) = snapshot();
+        let graph = Graph::new(snap, |_| None);
+
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(graph.edges.len(), 1);
+        assert!(graph.nodes.iter().all(|n| !n.properties.contains_key(NAME_MD)));
+    }
+
+    #[test]
+    fn graphml_is_valid_xml_with_escape_codes() {
+        let (snap, result) = snapshot();
+        let name = Some("a<b".to_string());
+        let graph = Graph::new(snap, |id| if *id == result { name.clone() } else { None });
+        let mut out = Vec::new();
+
+        graph.write(ExportFormat::GraphMl, &mut out).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<data key=\"name\">a&lt;b</data>"));
+        assert!(xml.contains("\u{fffd}[31mFAIL"));
+        assert!(!xml.contains('\u{1b}'));
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use protocol::{VertInfo, FlakyEnv, FlakyTest};
+
+/// How many of the latest runs in each environment are considered
+pub const WINDOW: usize = 20;
+/// How many of the worst offenders are returned by a query
+pub const WORST: usize = 25;
+
+const MIN_FLIPS: u32 = 2;
+const MIN_RATE: f32 = 0.25;
+
+/// Result properties which identify the environment a test ran in. The
+/// others, like the kernel string, are different for nearly every run.
+const ENV_PROPS: [&str; 2] = ["environment:arch:", "environment:product:"];
+
+/// Count how many times the status changes, the statuses must be in run order
+pub fn count_flips<I: IntoIterator<Item = bool>>(statuses: I) -> u32 {
+    let mut last = None;
+    let mut flips = 0;
+
+    for status in statuses {
+        if last.map_or(false, |l| l != status) {
+            flips += 1;
+        }
+        last = Some(status);
+    }
+
+    flips
+}
+
+pub fn flip_rate(runs: u32, flips: u32) -> f32 {
+    if runs < 2 {
+        0.0
+    } else {
+        flips as f32 / (runs - 1) as f32
+    }
+}
+
+pub fn is_flaky(runs: u32, flips: u32) -> bool {
+    flips >= MIN_FLIPS && flip_rate(runs, flips) >= MIN_RATE
+}
+
+/// The statuses from the latest WINDOW runs, which are the only ones
+/// considered. The statuses are (run, passed) pairs in run order, so the
+/// results of a run are next to each other.
+fn latest(statuses: &[(Uuid, bool)]) -> Vec<bool> {
+    let mut runs = 0;
+    let mut last = None;
+    let start = statuses.iter().rposition(|&(run, _)| {
+        if last != Some(run) {
+            runs += 1;
+            last = Some(run);
+        }
+        runs > WINDOW
+    }).map_or(0, |i| i + 1);
+
+    statuses[start ..].iter().map(|&(_, passed)| passed).collect()
+}
+
+/// Whether the latest statuses, (run, passed) pairs in run order, flip often
+/// enough to be flaky
+pub fn is_flaky_recently(statuses: &[(Uuid, bool)]) -> bool {
+    let latest = latest(statuses);
+
+    is_flaky(latest.len() as u32, count_flips(latest))
+}
+
+/// Map a result property to its part of the environment key, if it has one.
+///
+/// The revision is cut off the product, otherwise every build would be a
+/// new environment.
+pub fn env_name(prop: &str) -> Option<&str> {
+    if prop.starts_with(ENV_PROPS[1]) {
+        prop.rfind(':').map(|i| &prop[0 .. i])
+    } else if prop.starts_with(ENV_PROPS[0]) {
+        Some(prop)
+    } else {
+        None
+    }
+}
+
+/// Score a test by its results, which are (environment, run, passed) triples
+/// in run order. Returns None if the test does not flip in any environment.
+pub fn score_test(test: VertInfo, results: Vec<(String, Uuid, bool)>) -> Option<FlakyTest> {
+    let mut by_env = BTreeMap::<String, Vec<(Uuid, bool)>>::new();
+
+    for (env, run, status) in results.into_iter() {
+        by_env.entry(env).or_insert_with(Vec::new).push((run, status));
+    }
+
+    let mut envs: Vec<FlakyEnv> = by_env.into_iter().map(|(env, statuses)| {
+        let latest = latest(&statuses);
+
+        FlakyEnv {
+            env,
+            runs: latest.len() as u32,
+            flips: count_flips(latest),
+        }
+    }).collect();
+
+    if !envs.iter().any(|e| is_flaky(e.runs, e.flips)) {
+        return None;
+    }
+
+    let (flips, chances) = envs.iter().fold((0, 0), |(f, c), e| {
+        (f + e.flips, c + e.runs.saturating_sub(1))
+    });
+
+    envs.sort_by(|a, b| {
+        flip_rate(b.runs, b.flips).partial_cmp(&flip_rate(a.runs, a.flips))
+            .unwrap_or(Ordering::Equal)
+    });
+
+    Some(FlakyTest {
+        test,
+        score: flips as f32 / chances as f32,
+        envs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn test() -> VertInfo {
+        VertInfo("test:foo".to_string(), Uuid::nil())
+    }
+
+    /// Each status in a run of its own
+    fn in_runs(statuses: &[bool]) -> Vec<(Uuid, bool)> {
+        statuses.iter().map(|&s| (Uuid::new_v4(), s)).collect()
+    }
+
+    #[test]
+    fn counts_flips() {
+        assert_eq!(count_flips(vec![]), 0);
+        assert_eq!(count_flips(vec![true, true, true]), 0);
+        assert_eq!(count_flips(vec![true, false, false, true]), 2);
+        assert_eq!(count_flips(vec![false, true, false, true]), 3);
+    }
+
+    #[test]
+    fn only_the_latest_runs_count() {
+        let flipping: Vec<bool> = (0 .. WINDOW * 2).map(|i| i % 2 == 0).collect();
+        let mut statuses = in_runs(&flipping);
+
+        assert!(is_flaky_recently(&statuses));
+        statuses.extend(in_runs(&vec![true; WINDOW]));
+        assert!(!is_flaky_recently(&statuses));
+    }
+
+    #[test]
+    fn the_window_is_runs_not_results() {
+        // Half the window flips, then each run has the test twice and passes
+        let flipping: Vec<bool> = (0 .. WINDOW / 2).map(|i| i % 2 == 1).collect();
+        let mut statuses = in_runs(&flipping);
+        for _ in 0 .. WINDOW / 2 {
+            let run = Uuid::new_v4();
+            statuses.extend(vec![(run, true), (run, true)]);
+        }
+
+        assert!(is_flaky_recently(&statuses));
+
+        let env = "environment:arch:x86_64".to_string();
+        let results = statuses.into_iter().map(|(run, s)| (env.clone(), run, s)).collect();
+        let flaky = score_test(test(), results).unwrap();
+        assert_eq!(flaky.envs[0].runs as usize, WINDOW / 2 + WINDOW);
+        assert_eq!(flaky.envs[0].flips as usize, WINDOW / 2 - 1);
+    }
+
+    #[test]
+    fn scores_flipping_tests() {
+        let env = "environment:arch:x86_64".to_string();
+        let results = in_runs(&[true, false, true, false, true]).into_iter()
+            .map(|(run, s)| (env.clone(), run, s))
+            .collect();
+        let flaky = score_test(test(), results).unwrap();
+
+        assert!((flaky.score - 1.0).abs() < 1e-6);
+        assert_eq!(flaky.envs.len(), 1);
+        assert_eq!(flaky.envs[0].runs, 5);
+        assert_eq!(flaky.envs[0].flips, 4);
+    }
+
+    #[test]
+    fn stable_tests_have_no_score() {
+        let run = Uuid::new_v4;
+        let results = vec![("a".to_string(), run(), true), ("a".to_string(), run(), true),
+                           ("b".to_string(), run(), false), ("b".to_string(), run(), false)];
+
+        assert!(score_test(test(), results).is_none());
+    }
+
+    #[test]
+    fn product_revision_is_not_part_of_the_environment() {
+        assert_eq!(env_name("environment:product:sle:15"), Some("environment:product:sle"));
+        assert_eq!(env_name("environment:arch:s390x"), Some("environment:arch:s390x"));
+        assert_eq!(env_name("environment:kernel:4.12"), None);
+    }
+}
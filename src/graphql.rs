@@ -0,0 +1,463 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A GraphQL schema over the repository's graph, so that clients can write
+//! their own traversals. Resolvers block on the Repo actor, so the schema is
+//! executed by a pool of sync actors.
+
+use std::cell::Cell;
+use std::cmp;
+use std::sync::Arc;
+
+use futures::Future;
+use actix::prelude::*;
+use actix_web::{error, App, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse,
+                Json, State};
+use actix_web::http::Method;
+use indradb::Type;
+use juniper::{self, FieldResult, EmptyMutation, RootNode, ID};
+use juniper::http::GraphQLRequest;
+use juniper::http::graphiql::graphiql_source;
+use serde_json;
+use uuid::Uuid;
+
+use repo::{Repo, Vert, GetVertId, GetVerts, GetNeighbours, GetMetadata, Search, TIMESTAMP_MD,
+           IMPORTER_MD, LOG_MD, SIGNATURE_MD, PASS_ET, FAIL_ET, ISIN_ET, INRUN_ET, LINK_ET,
+           TEST_VT, TEST_RES_VT, SET_VT, RUN_VT, BUG_VT};
+use web::AppState;
+
+/// The most vertices returned by a single field
+const LIMIT: u32 = 10_000;
+/// How much a whole query may read from the repository, so that nesting
+/// fields can not keep one of the few executor threads busy for long. Each
+/// vertex or value costs one and each lookup costs at least one.
+const QUERY_LIMIT: u32 = 20_000;
+
+pub struct Context {
+    repo: Addr<Repo>,
+    /// What is left of the query's limit
+    budget: Cell<u32>,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    fn new(repo: Addr<Repo>, limit: u32) -> Context {
+        Context { repo, budget: Cell::new(limit) }
+    }
+
+    /// Take the cost of a lookup which found `rows` things from the budget
+    fn spend(&self, rows: usize) -> FieldResult<()> {
+        let cost = cmp::max(rows, 1) as u32;
+
+        if cost > self.budget.get() {
+            self.budget.set(0);
+            return Err("The query reads too much, ask for fewer fields or nest them less".into());
+        }
+        self.budget.set(self.budget.get() - cost);
+        Ok(())
+    }
+
+    fn get(&self, id: Uuid, t: &Type) -> FieldResult<Option<Vert>> {
+        self.spend(1)?;
+        Ok(self.repo.send(GetVerts(vec![id])).wait()?
+           .into_iter()
+           .find(|v| &v.t == t))
+    }
+
+    fn get_by_name(&self, name: String, t: &Type) -> FieldResult<Option<Vert>> {
+        match self.repo.send(GetVertId(name)).wait()? {
+            Some(id) => self.get(id, t),
+            None => Ok(None),
+        }
+    }
+
+    fn neighbours(&self, id: Uuid, edge: &Type, outbound: bool, t: &Type)
+                  -> FieldResult<Vec<Vert>> {
+        // One more than the budget is enough to know it has run out
+        let limit = cmp::min(LIMIT, self.budget.get().saturating_add(1));
+        let query = GetNeighbours { id, edge: edge.clone(), outbound, limit };
+        let verts: Vec<Vert> = self.repo.send(query).wait()?;
+
+        self.spend(verts.len())?;
+        Ok(verts.into_iter().filter(|v| &v.t == t).collect())
+    }
+
+    fn metadata(&self, id: Uuid, name: &'static str) -> FieldResult<Option<serde_json::Value>> {
+        self.spend(1)?;
+        Ok(self.repo.send(GetMetadata { id, name }).wait()?)
+    }
+
+    fn search(&self, term: String) -> FieldResult<Vec<Vert>> {
+        let ids: Vec<Uuid> = self.repo.send(Search(term)).wait()?
+            .into_iter().map(|(_, id)| id).collect();
+
+        self.spend(ids.len())?;
+        Ok(self.repo.send(GetVerts(ids)).wait()?)
+    }
+}
+
+fn parse_id(id: &ID) -> FieldResult<Uuid> {
+    Ok(Uuid::parse_str(id)?)
+}
+
+fn vert_id(v: &Vert) -> ID {
+    ID::from(v.id.to_string())
+}
+
+fn vert_name(v: &Vert) -> String {
+    v.name.clone().unwrap_or_default()
+}
+
+fn wrap<T, F: Fn(Vert) -> T>(verts: FieldResult<Vec<Vert>>, f: F) -> FieldResult<Vec<T>> {
+    verts.map(|verts| verts.into_iter().map(f).collect())
+}
+
+pub struct Test(Vert);
+pub struct TestResult(Vert);
+pub struct Set(Vert);
+pub struct Run(Vert);
+pub struct Bug(Vert);
+/// Any vertex, as returned by a search
+pub struct Vertex(Vert);
+
+graphql_object!(Test: Context |&self| {
+    description: "A test case, its name includes the sets it is in"
+
+    field id() -> ID { vert_id(&self.0) }
+
+    field name() -> String { vert_name(&self.0) }
+
+    field results(&executor, passed: Option<bool>) -> FieldResult<Vec<TestResult>>
+        as "The test's results, optionally only those which passed or failed"
+    {
+        let ctx = executor.context();
+        let mut results = Vec::new();
+
+        if passed != Some(false) {
+            results.extend(ctx.neighbours(self.0.id, &PASS_ET, true, &TEST_RES_VT)?);
+        }
+        if passed != Some(true) {
+            results.extend(ctx.neighbours(self.0.id, &FAIL_ET, true, &TEST_RES_VT)?);
+        }
+
+        Ok(results.into_iter().map(TestResult).collect())
+    }
+
+    field sets(&executor) -> FieldResult<Vec<Set>> {
+        wrap(executor.context().neighbours(self.0.id, &ISIN_ET, true, &SET_VT), Set)
+    }
+});
+
+graphql_object!(TestResult: Context as "Result" |&self| {
+    description: "One execution of a test"
+
+    field id() -> ID { vert_id(&self.0) }
+
+    field passed(&executor) -> FieldResult<bool> {
+        Ok(executor.context().neighbours(self.0.id, &PASS_ET, false, &TEST_VT)?.len() > 0)
+    }
+
+    field test(&executor) -> FieldResult<Option<Test>> {
+        let ctx = executor.context();
+        let mut tests = ctx.neighbours(self.0.id, &PASS_ET, false, &TEST_VT)?;
+
+        tests.extend(ctx.neighbours(self.0.id, &FAIL_ET, false, &TEST_VT)?);
+        Ok(tests.into_iter().next().map(Test))
+    }
+
+    field sets(&executor) -> FieldResult<Vec<Set>>
+        as "The result's properties, such as its environment"
+    {
+        wrap(executor.context().neighbours(self.0.id, &ISIN_ET, true, &SET_VT), Set)
+    }
+
+    field run(&executor) -> FieldResult<Option<Run>> {
+        Ok(executor.context().neighbours(self.0.id, &INRUN_ET, true, &RUN_VT)?
+           .into_iter().next().map(Run))
+    }
+
+    field bugs(&executor) -> FieldResult<Vec<Bug>> {
+        wrap(executor.context().neighbours(self.0.id, &LINK_ET, true, &BUG_VT), Bug)
+    }
+
+    field log(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().metadata(self.0.id, LOG_MD)?
+           .and_then(|log| log.as_str().map(|l| l.to_string())))
+    }
+
+    field signature(&executor) -> FieldResult<Option<String>>
+        as "The failure signature of the log, only failed results have one"
+    {
+        Ok(executor.context().metadata(self.0.id, SIGNATURE_MD)?
+           .and_then(|sig| sig.as_str().map(|s| s.to_string())))
+    }
+});
+
+graphql_object!(Set: Context |&self| {
+    description: "A category or property such as a product revision"
+
+    field id() -> ID { vert_id(&self.0) }
+
+    field name() -> String { vert_name(&self.0) }
+
+    field parent(&executor) -> FieldResult<Option<Set>> {
+        Ok(executor.context().neighbours(self.0.id, &ISIN_ET, true, &SET_VT)?
+           .into_iter().next().map(Set))
+    }
+
+    field sets(&executor) -> FieldResult<Vec<Set>> {
+        wrap(executor.context().neighbours(self.0.id, &ISIN_ET, false, &SET_VT), Set)
+    }
+
+    field tests(&executor) -> FieldResult<Vec<Test>> {
+        wrap(executor.context().neighbours(self.0.id, &ISIN_ET, false, &TEST_VT), Test)
+    }
+
+    field results(&executor) -> FieldResult<Vec<TestResult>> {
+        wrap(executor.context().neighbours(self.0.id, &ISIN_ET, false, &TEST_RES_VT),
+             TestResult)
+    }
+});
+
+graphql_object!(Run: Context |&self| {
+    description: "One execution of a test suite"
+
+    field id() -> ID { vert_id(&self.0) }
+
+    field name() -> String { vert_name(&self.0) }
+
+    field timestamp(&executor) -> FieldResult<Option<f64>>
+        as "Seconds since the Unix epoch"
+    {
+        Ok(executor.context().metadata(self.0.id, TIMESTAMP_MD)?.and_then(|ts| ts.as_f64()))
+    }
+
+    field imported_by(&executor) -> FieldResult<Option<String>> {
+        Ok(executor.context().metadata(self.0.id, IMPORTER_MD)?
+           .and_then(|user| user.as_str().map(|u| u.to_string())))
+    }
+
+    field results(&executor) -> FieldResult<Vec<TestResult>> {
+        wrap(executor.context().neighbours(self.0.id, &INRUN_ET, false, &TEST_RES_VT),
+             TestResult)
+    }
+});
+
+graphql_object!(Bug: Context |&self| {
+    field id() -> ID { vert_id(&self.0) }
+
+    field name() -> String { vert_name(&self.0) }
+
+    field results(&executor) -> FieldResult<Vec<TestResult>> {
+        wrap(executor.context().neighbours(self.0.id, &LINK_ET, false, &TEST_RES_VT),
+             TestResult)
+    }
+});
+
+graphql_object!(Vertex: Context |&self| {
+    field id() -> ID { vert_id(&self.0) }
+
+    field name() -> String { vert_name(&self.0) }
+
+    field kind() -> &str as "test, result, set, run or bug" { &(self.0.t).0 }
+});
+
+pub struct Query;
+
+graphql_object!(Query: Context |&self| {
+    field test(&executor, name: String) -> FieldResult<Option<Test>> {
+        Ok(executor.context().get_by_name(name, &TEST_VT)?.map(Test))
+    }
+
+    field result(&executor, id: ID) -> FieldResult<Option<TestResult>> {
+        Ok(executor.context().get(parse_id(&id)?, &TEST_RES_VT)?.map(TestResult))
+    }
+
+    field set(&executor, name: String) -> FieldResult<Option<Set>> {
+        Ok(executor.context().get_by_name(name, &SET_VT)?.map(Set))
+    }
+
+    field run(&executor, name: String) -> FieldResult<Option<Run>> {
+        Ok(executor.context().get_by_name(name, &RUN_VT)?.map(Run))
+    }
+
+    field bug(&executor, name: String) -> FieldResult<Option<Bug>> {
+        Ok(executor.context().get_by_name(format!("bug:{}", name), &BUG_VT)?.map(Bug))
+    }
+
+    field search(&executor, term: String) -> FieldResult<Vec<Vertex>>
+        as "Named vertices in alphabetical order, starting from the term"
+    {
+        wrap(executor.context().search(term), Vertex)
+    }
+});
+
+pub type Schema = RootNode<'static, Query, EmptyMutation<Context>>;
+
+pub fn create_schema() -> Schema {
+    Schema::new(Query, EmptyMutation::new())
+}
+
+#[derive(Deserialize)]
+pub struct GraphQLData(GraphQLRequest);
+
+impl Message for GraphQLData {
+    type Result = Result<String, Error>;
+}
+
+pub struct GraphQLExecutor {
+    schema: Arc<Schema>,
+    repo: Addr<Repo>,
+}
+
+impl GraphQLExecutor {
+    pub fn new(schema: Arc<Schema>, repo: Addr<Repo>) -> Self {
+        GraphQLExecutor { schema, repo }
+    }
+}
+
+impl Actor for GraphQLExecutor {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<GraphQLData> for GraphQLExecutor {
+    type Result = Result<String, Error>;
+
+    fn handle(&mut self, msg: GraphQLData, _: &mut Self::Context) -> Self::Result {
+        let ctx = Context::new(self.repo.clone(), QUERY_LIMIT);
+        let res = msg.0.execute(&self.schema, &ctx);
+
+        serde_json::to_string(&res).map_err(error::ErrorInternalServerError)
+    }
+}
+
+fn graphql((data, state): (Json<GraphQLData>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    state.graphql.send(data.into_inner())
+        .from_err()
+        .and_then(|res| {
+            let body = res?;
+            Ok(HttpResponse::Ok().content_type("application/json").body(body))
+        })
+        .responder()
+}
+
+fn graphiql(_req: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(graphiql_source("/graphql"))
+}
+
+/// Add the GraphQL endpoint and an in-browser IDE for it
+pub fn register(app: App<AppState>) -> App<AppState> {
+    app.resource("/graphql", |r| r.method(Method::POST).with(graphql))
+        .resource("/graphiql", |r| r.method(Method::GET).f(graphiql))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::SystemRunner;
+    use juniper::http::GraphQLRequest;
+    use serde_json::Value as JsonValue;
+
+    use repo::{NewRun, NewResult, FinishRun, TestStatus};
+    use super::*;
+
+    /// The repository runs in its own arbiter, because the resolvers block
+    /// waiting for it
+    fn repo() -> (SystemRunner, Addr<Repo>) {
+        let sys = System::new("test");
+        let repo = Repo::start_in_arbiter(&Arbiter::new("repository"), |_| Repo::default());
+        let run = repo.send(NewRun {
+            name: "run:1".to_string(),
+            timestamp: 100,
+            user: "tester".to_string(),
+        }).wait().unwrap();
+
+        for &(test, passed) in &[("test:ltp:a", true), ("test:ltp:b", false)] {
+            repo.send(NewResult {
+                test_fqn: test.to_string(),
+                status: if passed { TestStatus::Pass } else { TestStatus::Fail },
+                run,
+                properties: vec!["environment:arch:x86_64".to_string()],
+                log: None,
+            }).wait().unwrap();
+        }
+        repo.send(FinishRun { run, results: 2, user: "tester".to_string() }).wait().unwrap();
+
+        (sys, repo)
+    }
+
+    fn query(repo: &Addr<Repo>, limit: u32, query: &str) -> JsonValue {
+        let ctx = Context::new(repo.clone(), limit);
+        let res = GraphQLRequest::new(query.to_string(), None, None)
+            .execute(&create_schema(), &ctx);
+
+        serde_json::to_value(&res).unwrap()
+    }
+
+    #[test]
+    fn resolves_nested_fields() {
+        let (_sys, repo) = repo();
+        let res = query(&repo, QUERY_LIMIT, r#"{
+            test(name: "test:ltp:a") {
+                name
+                sets { name }
+                results { passed run { name timestamp } sets { name } }
+            }
+        }"#);
+
+        assert!(res.get("errors").is_none(), "{}", res);
+        let test = &res["data"]["test"];
+        assert_eq!(test["name"], "test:ltp:a");
+        assert_eq!(test["sets"][0]["name"], "test:ltp");
+
+        let results = test["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["passed"], true);
+        assert_eq!(results[0]["run"]["name"], "run:1");
+        assert_eq!(results[0]["run"]["timestamp"], 100.0);
+        assert_eq!(results[0]["sets"][0]["name"], "environment:arch:x86_64");
+    }
+
+    #[test]
+    fn searches_from_the_term() {
+        let (_sys, repo) = repo();
+        let res = query(&repo, QUERY_LIMIT, r#"{ search(term: "test:") { name kind } }"#);
+        let mut found: Vec<(&str, &str)> = res["data"]["search"].as_array().unwrap().iter()
+            .map(|v| (v["name"].as_str().unwrap(), v["kind"].as_str().unwrap()))
+            .collect();
+
+        found.sort();
+        assert_eq!(found, vec![("test:ltp", "set"), ("test:ltp:a", "test"),
+                               ("test:ltp:b", "test")]);
+    }
+
+    #[test]
+    fn stops_queries_which_read_too_much() {
+        let (_sys, repo) = repo();
+        let nested = r#"{ set(name: "test:ltp") { tests { name results { passed } } } }"#;
+
+        let res = query(&repo, QUERY_LIMIT, nested);
+        assert!(res.get("errors").is_none(), "{}", res);
+        assert_eq!(res["data"]["set"]["tests"].as_array().unwrap().len(), 2);
+
+        // The set, two tests and a lookup of each status for each test is 7
+        let res = query(&repo, 5, nested);
+        let errors = res["errors"].as_array().unwrap();
+        assert!(errors[0]["message"].as_str().unwrap().contains("reads too much"), "{}", res);
+        assert!(res["data"]["set"].is_null());
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use actix::prelude::*;
+
+use protocol::{Topic, Event};
+
+static NEXT_SESSION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Identifies a subscriber, usually a websocket connection
+pub fn new_session() -> usize {
+    NEXT_SESSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Sent to subscribers of the event's topic
+#[derive(Message, Clone)]
+pub struct Publish(pub Event);
+
+#[derive(Message)]
+pub struct Subscribe {
+    pub session: usize,
+    pub topic: Topic,
+    pub client: Recipient<Publish>,
+}
+
+#[derive(Message)]
+pub struct Unsubscribe {
+    pub session: usize,
+    pub topic: Topic,
+}
+
+/// Remove all of a session's subscriptions
+#[derive(Message)]
+pub struct Disconnect(pub usize);
+
+/// Tells a connection to close because the server is shutting down
+#[derive(Message)]
+pub struct Close;
+
+/// Register a connection so it can be closed on shutdown
+#[derive(Message)]
+pub struct Connect {
+    pub session: usize,
+    pub client: Recipient<Close>,
+}
+
+/// Close every connection
+#[derive(Message)]
+pub struct CloseAll;
+
+/// Passes events from the actors which create them to the subscribers
+#[derive(Default)]
+pub struct Hub {
+    subs: HashMap<Topic, HashMap<usize, Recipient<Publish>>>,
+    conns: HashMap<usize, Recipient<Close>>,
+}
+
+impl Actor for Hub {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Hub {}
+impl SystemService for Hub {}
+
+impl Handler<Subscribe> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.subs.entry(msg.topic)
+            .or_insert_with(HashMap::new)
+            .insert(msg.session, msg.client);
+    }
+}
+
+impl Handler<Unsubscribe> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _ctx: &mut Context<Self>) {
+        let empty = match self.subs.get_mut(&msg.topic) {
+            Some(clients) => {
+                clients.remove(&msg.session);
+                clients.is_empty()
+            },
+            None => false,
+        };
+
+        if empty {
+            self.subs.remove(&msg.topic);
+        }
+    }
+}
+
+impl Handler<Disconnect> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Context<Self>) {
+        for clients in self.subs.values_mut() {
+            clients.remove(&msg.0);
+        }
+        self.subs.retain(|_, clients| !clients.is_empty());
+        self.conns.remove(&msg.0);
+    }
+}
+
+impl Handler<Connect> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Context<Self>) {
+        self.conns.insert(msg.session, msg.client);
+    }
+}
+
+impl Handler<CloseAll> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseAll, _ctx: &mut Context<Self>) {
+        info!("Closing {} connections", self.conns.len());
+        for (_, client) in self.conns.drain() {
+            let _ = client.do_send(Close);
+        }
+    }
+}
+
+impl Handler<Publish> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _ctx: &mut Context<Self>) {
+        if let Some(clients) = self.subs.get_mut(&msg.0.topic()) {
+            // do_send ignores the mailbox capacity, so it only fails if the
+            // subscriber has stopped
+            clients.retain(|session, client| {
+                let sent = client.do_send(msg.clone()).is_ok();
+
+                if !sent {
+                    debug!("Dropping stopped subscriber {}", session);
+                }
+                sent
+            });
+        }
+    }
+}
+
+/// Send an event to whoever is subscribed to it
+pub fn publish(event: Event) {
+    System::current().registry().get::<Hub>().do_send(Publish(event));
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::SystemRunner;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Remembers the counts of the NewResults events it is sent
+    #[derive(Default)]
+    struct Client {
+        counts: Vec<u32>,
+    }
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Publish> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: Publish, _ctx: &mut Context<Self>) {
+            if let Event::NewResults { count, .. } = msg.0 {
+                self.counts.push(count);
+            }
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<u32>")]
+    struct Counts;
+
+    impl Handler<Counts> for Client {
+        type Result = MessageResult<Counts>;
+
+        fn handle(&mut self, _msg: Counts, _ctx: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.counts.clone())
+        }
+    }
+
+    /// Waiting for the hub means it has passed the event on before the
+    /// clients are asked what they got
+    fn publish(sys: &mut SystemRunner, hub: &Addr<Hub>, set: Uuid, count: u32) {
+        sys.block_on(hub.send(Publish(Event::NewResults { set, count }))).unwrap();
+    }
+
+    fn subscribe(hub: &Addr<Hub>, session: usize, set: Uuid, client: &Addr<Client>) {
+        hub.do_send(Subscribe {
+            session,
+            topic: Topic::SetResults(set),
+            client: client.clone().recipient(),
+        });
+    }
+
+    #[test]
+    fn publishes_to_subscribers() {
+        let mut sys = System::new("test");
+        let hub = Hub::default().start();
+        let (a, b) = (Client::default().start(), Client::default().start());
+        let (a_session, b_session) = (new_session(), new_session());
+        let (set1, set2) = (Uuid::new_v4(), Uuid::new_v4());
+
+        subscribe(&hub, a_session, set1, &a);
+        subscribe(&hub, b_session, set1, &b);
+        subscribe(&hub, b_session, set2, &b);
+        publish(&mut sys, &hub, set1, 1);
+        publish(&mut sys, &hub, set2, 2);
+
+        hub.do_send(Unsubscribe { session: b_session, topic: Topic::SetResults(set1) });
+        publish(&mut sys, &hub, set1, 3);
+
+        hub.do_send(Disconnect(a_session));
+        publish(&mut sys, &hub, set1, 4);
+        publish(&mut sys, &hub, set2, 5);
+
+        assert_eq!(sys.block_on(a.send(Counts)).unwrap(), vec![1, 3]);
+        assert_eq!(sys.block_on(b.send(Counts)).unwrap(), vec![1, 2, 5]);
+    }
+}
@@ -13,31 +13,101 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{self, File};
 use std::ffi::OsString;
 use std::io::{self, prelude::*};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use actix::dev::*;
 use futures::Future;
+use serde_json::{self, Value};
+use uuid::Uuid;
 
-use repo::{Repo, NewResult, TestStatus};
+use repo::{Repo, NewRun, NewResult, TestStatus, GetRun, FinishRun, DeleteRun};
+use protocol::Event;
+use hub;
+use supervise::{guard, Guarded};
+use metrics::{self, Imported};
 
-#[derive(Message)] pub struct ScanDir {
+/// Set when the server is shutting down, so no more files are started
+static STOPPING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Finish the file being imported, but do not start any more
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+/// Answered once the importer has finished what it was sent before
+#[derive(Message)]
+pub struct Drain;
+
+/// Import the contents of a single results file, e.g. one uploaded by a user
+#[derive(Message)]
+#[rtype(result = "Result<u32, String>")]
+pub struct ImportFile {
+    pub name: String,
+    pub json: String,
+    pub user: String,
+}
+
+/// Import all the files in a directory, the response is sent after the last
+/// file has been imported and is how many could not be
+#[derive(Message)]
+#[rtype(result = "u32")]
+pub struct ScanDir {
     pub dir: String,
     pub ext: String,
+    /// Who the runs are recorded as imported by
+    pub user: String,
+}
+
+/// Scan these directories for new results files every so often, replacing
+/// any which were watched before
+#[derive(Message)]
+pub struct WatchDirs {
+    pub dirs: Vec<String>,
+    pub every: Duration,
+    pub user: String,
+}
+
+/// Check that a results file has the fields the importer needs
+fn check_results(v: &Value) -> Result<(), String> {
+    let env = v["environment"].as_object().ok_or("No environment object")?;
+
+    for key in &["product", "revision"] {
+        if !env.get(*key).map_or(false, |p| p.is_string()) {
+            return Err(format!("No environment {} string", key));
+        }
+    }
+
+    for r in v["results"].as_array().ok_or("No results array")? {
+        if !r["test"].is_object() || !r["test_fqn"].is_string() {
+            return Err("Result without a test object or test_fqn string".to_string());
+        }
+    }
+
+    Ok(())
 }
-#[derive(Message)] pub struct Import(String);
 
 pub struct Importer {
     repo: Addr<Repo>,
+    watch: Option<WatchDirs>,
+    watch_timer: Option<SpawnHandle>,
+    /// When the files found by scanning directories were last modified, so
+    /// they are not read again until they change
+    scanned: HashMap<PathBuf, SystemTime>,
 }
 
 impl Importer {
     pub fn new(repo: Addr<Repo>) -> Importer {
         Importer {
             repo,
+            watch: None,
+            watch_timer: None,
+            scanned: HashMap::new(),
         }
     }
 
@@ -49,7 +119,9 @@ impl Importer {
         Ok(contents)
     }
 
-    fn read_files(dir: &str, ext: String) -> io::Result<impl Iterator<Item = String>> {
+    /// The directory's files with the extension which are new or have been
+    /// modified since they were last scanned, and when they were modified
+    fn changed_files(&self, dir: &str, ext: String) -> io::Result<Vec<(PathBuf, SystemTime)>> {
         let ext = OsString::from(ext);
 
         fs::read_dir(dir).and_then(|ents| {
@@ -57,56 +129,111 @@ impl Importer {
                 ent.ok()
             }).filter(|ent| {
                 ent.file_type().map(|e| e.is_file()).unwrap_or(false)
-            }).filter_map(move |ent| {
+            }).filter_map(|ent| {
                 let fp = ent.path();
-                if fp.extension().map_or(false, |e| e == ext) {
-                    info!("Reading file: {}", fp.display());
-                    match Importer::read_file(&fp) {
-                        Ok(content) => Some(content),
-                        Err(e) => {
-                            error!("Failed to read {}: {}", fp.display(), e);
-                            None
-                        },
-                    }
-                } else {
-                    info!("Ignoring file: {}", fp.display());
+                if !fp.extension().map_or(false, |e| e == ext) {
+                    debug!("Ignoring file: {}", fp.display());
+                    return None;
+                }
+
+                // Without a modification time the file is always read
+                let modified = ent.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+                if modified != UNIX_EPOCH && self.scanned.get(&fp) == Some(&modified) {
                     None
+                } else {
+                    Some((fp, modified))
                 }
-            }))
+            }).collect())
         })
     }
-}
 
-impl Actor for Importer {
-    type Context = Context<Self>;
-}
+    /// Import one file and record how it went in the metrics
+    fn import(&mut self, path: PathBuf, json: String, user: &str, rescan: bool)
+              -> Result<u32, String> {
+        let start = Instant::now();
+        let res = self.import_results(path, json, user, rescan);
 
-impl Handler<ScanDir> for Importer {
-    type Result = ();
+        metrics::record(Imported {
+            results: *res.as_ref().unwrap_or(&0),
+            took: start.elapsed(),
+            failed: res.is_err(),
+        });
+        res
+    }
 
-    fn handle(&mut self, msg: ScanDir, ctx: &mut Self::Context) {
-        info!("Scanning directory: {}", &msg.dir);
+    /// Import one file's results and return how many there were. A file
+    /// which was already imported is an error, unless this is a `rescan` of
+    /// a directory, when it is skipped. One which was only partly imported,
+    /// or fails now, is removed.
+    fn import_results(&mut self, path: PathBuf, json: String, user: &str, rescan: bool)
+                      -> Result<u32, String> {
+        let name = format!("run:{}", path.display());
 
-        match Importer::read_files(&msg.dir, msg.ext) {
-            Ok(files) => {
-                for json in files {
-                    ctx.notify(Import(json));
-                }
+        match self.repo.send(GetRun(name.clone())).wait() {
+            Ok(Some((_, true))) if rescan => {
+                debug!("Skipping {}, it has already been imported", name);
+                return Ok(0);
             },
-            Err(e) => {
-                error!("Failed to read dir {}: {}", &msg.dir, e);
-            }
+            Ok(Some((_, true))) => {
+                return Err(format!("{} has already been imported", path.display()));
+            },
+            Ok(Some((run, false))) => {
+                warn!("Removing the partial import of {}", name);
+                self.roll_back(run, user);
+            },
+            Ok(None) => (),
+            Err(e) => return Err(format!("Repository returned error: {}", e)),
         }
+
+        let mut v: Value = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        check_results(&v)?;
+
+        // None of the test harnesses write a timestamp yet, so they are
+        // optional and the run gets the import time without one
+        let timestamp = v["timestamp"].as_u64().unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+        });
+        let run = match self.repo.send(NewRun { name, timestamp, user: user.to_string() }).wait() {
+            Ok(run) => run,
+            Err(e) => return Err(format!("Repository returned error: {}", e)),
+        };
+
+        let res = self.add_results(&mut v, run);
+        match res {
+            Ok(results) => self.repo.do_send(FinishRun { run, results, user: user.to_string() }),
+            Err(_) => self.roll_back(run, user),
+        }
+        res
     }
-}
 
-impl Handler<Import> for Importer {
-    type Result = ();
+    fn schedule_watch(&mut self, ctx: &mut Context<Self>) {
+        if let Some(timer) = self.watch_timer.take() {
+            ctx.cancel_future(timer);
+        }
+
+        let every = match self.watch {
+            Some(ref w) if !w.dirs.is_empty() => w.every,
+            _ => return,
+        };
+
+        // The scans go through the ScanDir handler, so a panic is caught
+        self.watch_timer = Some(ctx.run_interval(every, |imp, ctx| {
+            if let Some(ref w) = imp.watch {
+                for dir in &w.dirs {
+                    ctx.notify(ScanDir { dir: dir.clone(), ext: "json".into(),
+                                         user: w.user.clone() });
+                }
+            }
+        }));
+    }
 
-    fn handle(&mut self, msg: Import, _ctx: &mut Self::Context) {
-        use serde_json::{self, *};
+    fn roll_back(&mut self, run: Uuid, user: &str) {
+        if let Err(e) = self.repo.send(DeleteRun { run, user: user.to_string() }).wait() {
+            error!("Could not remove the partial import of run {}: {}", run, e);
+        }
+    }
 
-        let mut v: Value = serde_json::from_str(&msg.0).unwrap();
+    fn add_results(&mut self, v: &mut Value, run: Uuid) -> Result<u32, String> {
         let env_props = {
             let mut env = v["environment"].as_object_mut().unwrap();
             let product = format!("environment:product:{}:{}",
@@ -120,6 +247,7 @@ impl Handler<Import> for Importer {
         };
 
         let mut reqs: VecDeque<Request<Repo, NewResult>> = VecDeque::with_capacity(8);
+        let mut count = 0;
         for r in v["results"].as_array().unwrap() {
             let mut props = env_props.clone();
 
@@ -138,15 +266,134 @@ impl Handler<Import> for Importer {
             reqs.push_back(self.repo.send(NewResult {
                 test_fqn: r["test_fqn"].as_str().unwrap().to_owned(),
                 status,
+                run,
                 properties: props,
+                log: r["test"]["log"].as_str().map(|l| l.to_owned()),
             }));
 
             if reqs.len() > 8 {
                 if let Err(e) = reqs.pop_front().wait() {
-                    error!("Aborting import; Repository returned error: {}", e);
-                    break;
+                    return Err(format!("Repository returned error after {} results: {}",
+                                       count, e));
+                }
+                count += 1;
+            }
+        }
+
+        for req in reqs.into_iter() {
+            if let Err(e) = req.wait() {
+                return Err(format!("Repository returned error after {} results: {}", count, e));
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Actor for Importer {
+    type Context = Context<Self>;
+
+    /// Also called after a restart, which cancels the old timer
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule_watch(ctx);
+    }
+}
+
+/// The importer only holds the repository's address, what it is watching and
+/// which files it has scanned, so nothing needs to be done to restart it. A
+/// file which was being imported when it panicked is not marked as scanned.
+impl Supervised for Importer {}
+
+impl Handler<ScanDir> for Importer {
+    type Result = Guarded<ScanDir>;
+
+    fn handle(&mut self, msg: ScanDir, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "importer", "ScanDir", move || {
+            info!("Scanning directory: {}", &msg.dir);
+
+            let (mut files, mut results, mut failed) = (0, 0, 0);
+
+            match self.changed_files(&msg.dir, msg.ext) {
+                Ok(changed) => {
+                    for (path, modified) in changed {
+                        if STOPPING.load(Ordering::SeqCst) {
+                            info!("Stopping the scan of {} to shut down", &msg.dir);
+                            break;
+                        }
+                        let name = path.display().to_string();
+
+                        info!("Reading file: {}", name);
+                        let res = match Importer::read_file(&path) {
+                            Ok(json) => {
+                                let res = self.import(path.clone(), json, &msg.user, true);
+                                // Even if it failed, it will fail again until it changes
+                                self.scanned.insert(path, modified);
+                                res
+                            },
+                            Err(e) => Err(format!("Failed to read it: {}", e)),
+                        };
+
+                        match res {
+                            Ok(count) => results += count,
+                            Err(e) => {
+                                error!("Aborting import of {}: {}", name, e);
+                                failed += 1;
+                            },
+                        }
+                        files += 1;
+                        hub::publish(Event::ImportProgress {
+                            dir: msg.dir.clone(), files, results, done: false
+                        });
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to read dir {}: {}", &msg.dir, e);
+                    failed += 1;
                 }
             }
+
+            hub::publish(Event::ImportProgress { dir: msg.dir, files, results, done: true });
+            MessageResult(failed)
+        })
+    }
+}
+
+impl Handler<WatchDirs> for Importer {
+    type Result = ();
+
+    fn handle(&mut self, msg: WatchDirs, ctx: &mut Self::Context) {
+        if msg.dirs.is_empty() {
+            info!("Stopped watching directories");
+        } else {
+            info!("Watching {} every {}s", msg.dirs.join(", "), msg.every.as_secs());
         }
+        self.watch = Some(msg);
+        self.schedule_watch(ctx);
     }
 }
+
+impl Handler<ImportFile> for Importer {
+    type Result = Guarded<ImportFile>;
+
+    fn handle(&mut self, msg: ImportFile, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "importer", "ImportFile", move || {
+            info!("Importing {} for {}", &msg.name, &msg.user);
+
+            let res = self.import(PathBuf::from(&msg.name), msg.json, &msg.user, false);
+
+            if let Ok(results) = res {
+                hub::publish(Event::ImportProgress {
+                    dir: msg.name, files: 1, results, done: true
+                });
+            }
+            MessageResult(res)
+        })
+    }
+}
+
+impl Handler<Drain> for Importer {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Drain, _ctx: &mut Self::Context) {}
+}
@@ -13,13 +13,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::RwLock;
 
 use futures::Future;
 use actix::prelude::*;
 use log::{self, Record, Level, Metadata, LevelFilter};
+use serde_json;
+
+use protocol::{Event, LogLevel, LogRecord};
+use hub;
 
 static FACADE: JournalFacade = JournalFacade;
 
@@ -27,20 +36,255 @@ thread_local!(
     static JOURNAL: RefCell<Option<Addr<Journal>>> = RefCell::new(None);
 );
 
+lazy_static! {
+    static ref FILTER: RwLock<Filter> = RwLock::new(Filter::default());
+}
+
+/// Which levels are logged for which modules, parsed from a spec like
+/// `info,bug_graph::web=debug,actix_web=warn`
+#[derive(Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    /// Module path prefixes and their levels, longest first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        let level = |l: &str| {
+            LevelFilter::from_str(l.trim()).map_err(|_| format!("Unknown log level: {}", l))
+        };
+
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut kv = part.splitn(2, '=');
+
+            match (kv.next(), kv.next()) {
+                (Some(module), Some(l)) => filter.modules.push((module.to_string(), level(l)?)),
+                (Some(l), None) => filter.default = level(l)?,
+                _ => unreachable!(),
+            }
+        }
+        filter.modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    fn level(&self, module: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(prefix, _)| {
+                module.starts_with(prefix.as_str())
+                    && module[prefix.len() ..].chars().next().map_or(true, |c| c == ':')
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn enabled(&self, module: &str, level: Level) -> bool {
+        level <= self.level(module)
+    }
+
+    /// The most verbose level any module is logged at
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, l)| l).fold(self.default, |a, b| a.max(b))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// A log file which is moved to `path.1` when it grows too big, the old
+/// `path.1` becomes `path.2` and so on up to `keep`
+pub struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: String, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(RotatingFile { path, max_bytes, keep, file, written })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1 .. self.keep).rev() {
+            let from = format!("{}.{}", self.path, i);
+
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+
+        if self.keep > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+pub enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+/// Change how and where the journal writes log records
+#[derive(Message)]
+pub struct Configure {
+    pub format: Format,
+    pub output: Output,
+    /// How many records to keep in memory for GetJournal
+    pub keep: usize,
+}
+
 #[derive(Message)]
 pub struct Log {
-    pub src: String,
+    pub level: Level,
+    pub module: String,
+    /// The arbiter the record came from, e.g. repository or web
+    pub actor: String,
     pub msg: String,
 }
 
+/// The kept records after `since`, which are at `level` or more serious
+#[derive(Message)]
+#[rtype(result = "Vec<LogRecord>")]
+pub struct GetJournal {
+    pub since: Option<u64>,
+    pub level: LogLevel,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    level: String,
+    module: &'a str,
+    actor: &'a str,
+    msg: &'a str,
+}
+
+fn log_level(level: Level) -> LogLevel {
+    match level {
+        Level::Error => LogLevel::Error,
+        Level::Warn => LogLevel::Warn,
+        Level::Info => LogLevel::Info,
+        Level::Debug => LogLevel::Debug,
+        Level::Trace => LogLevel::Trace,
+    }
+}
+
 pub struct Journal {
     genesis: Instant,
+    format: Format,
+    output: Output,
+    /// The latest records, oldest first
+    records: VecDeque<LogRecord>,
+    keep: usize,
+    next_seq: u64,
 }
 
 impl Default for Journal {
     fn default() -> Self {
         Journal {
             genesis: Instant::now(),
+            format: Format::Text,
+            output: Output::Stderr,
+            records: VecDeque::new(),
+            keep: 1000,
+            next_seq: 0,
+        }
+    }
+}
+
+impl Journal {
+    fn remember(&mut self, log: &Log) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let record = LogRecord {
+            seq: self.next_seq,
+            time: now.as_secs() * 1000 + u64::from(now.subsec_millis()),
+            level: log_level(log.level),
+            module: log.module.clone(),
+            actor: log.actor.clone(),
+            msg: log.msg.clone(),
+        };
+
+        self.next_seq += 1;
+        while self.records.len() >= self.keep.max(1) {
+            self.records.pop_front();
+        }
+        self.records.push_back(record.clone());
+        hub::publish(Event::Log(record));
+    }
+
+    fn format(&self, log: &Log) -> String {
+        match self.format {
+            Format::Text => {
+                let d = self.genesis.elapsed();
+
+                format!("{:>+4}:{:<04}[{:5} {} {}] {}",
+                        d.as_secs(),
+                        d.subsec_nanos() / 100_000,
+                        log.level,
+                        log.actor,
+                        log.module,
+                        log.msg)
+            },
+            Format::Json => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+                serde_json::to_string(&JsonRecord {
+                    timestamp: now.as_secs() as f64 + f64::from(now.subsec_millis()) / 1000.0,
+                    level: log.level.to_string(),
+                    module: &log.module,
+                    actor: &log.actor,
+                    msg: &log.msg,
+                }).unwrap_or_else(|e| format!("{{\"msg\":\"Unserialisable record: {}\"}}", e))
+            },
         }
     }
 }
@@ -52,16 +296,42 @@ impl Actor for Journal {
 impl Supervised for Journal {}
 impl SystemService for Journal {}
 
+impl Handler<Configure> for Journal {
+    type Result = ();
+
+    fn handle(&mut self, conf: Configure, _ctx: &mut Context<Self>) {
+        self.format = conf.format;
+        self.output = conf.output;
+        self.keep = conf.keep;
+    }
+}
+
 impl Handler<Log> for Journal {
     type Result = ();
 
     fn handle(&mut self, log: Log, _ctx: &mut Context<Self>) {
-        let d = self.genesis.elapsed();
-        eprintln!("{:>+4}:{:<04}[{}] {}",
-                  d.as_secs(),
-                  d.subsec_nanos() / 100_000,
-                  log.src,
-                  log.msg);
+        let line = self.format(&log);
+
+        self.remember(&log);
+
+        if let Output::File(ref mut file) = self.output {
+            match file.write_line(&line) {
+                Ok(_) => return,
+                Err(e) => eprintln!("Could not write to log file {}: {}", file.path, e),
+            }
+        }
+        eprintln!("{}", line);
+    }
+}
+
+impl Handler<GetJournal> for Journal {
+    type Result = MessageResult<GetJournal>;
+
+    fn handle(&mut self, msg: GetJournal, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.records.iter()
+                      .filter(|r| msg.since.map_or(true, |s| r.seq > s) && r.level <= msg.level)
+                      .cloned()
+                      .collect())
     }
 }
 
@@ -69,15 +339,20 @@ struct JournalFacade;
 
 impl log::Log for JournalFacade {
 
-    fn enabled(&self, _: &Metadata) -> bool {
-        true
+    fn enabled(&self, m: &Metadata) -> bool {
+        FILTER.read().map(|f| f.enabled(m.target(), m.level())).unwrap_or(true)
     }
 
     fn log(&self, r: &Record) {
+        if !self.enabled(r.metadata()) {
+            return;
+        }
+
         if Arbiter::name() == "Arbiter is not running" {
             eprintln!("<SysDown> [{:5} {}] {}",
                       r.level(), r.module_path().unwrap_or("Unknown"),
                       r.args());
+            return;
         }
         let journal = JOURNAL.with(|cell| {
             if let Some(ref j) = *cell.borrow() {
@@ -88,7 +363,9 @@ impl log::Log for JournalFacade {
             j
         });
         let log = Log {
-            src: format!("{:5} {}", r.level(), r.module_path().unwrap_or("Unknown")),
+            level: r.level(),
+            module: r.module_path().unwrap_or("Unknown").to_string(),
+            actor: Arbiter::name(),
             msg: fmt::format(*r.args()),
         };
 
@@ -101,9 +378,59 @@ impl log::Log for JournalFacade {
     fn flush(&self) {}
 }
 
-pub fn init() {
-    log::set_max_level(LevelFilter::Trace);
+/// Change the log levels, e.g. after the config file is reloaded
+pub fn set_filter(filter: Filter) {
+    log::set_max_level(filter.max());
+    *FILTER.write().unwrap() = filter;
+}
+
+/// Install the journal as the logger, records below the filter's levels are
+/// dropped before they reach the Journal actor
+pub fn init(filter: Filter, format: Format, output: Output, keep: usize) {
+    set_filter(filter);
+
+    System::current().registry().get::<Journal>().do_send(Configure { format, output, keep });
     if let Err(e) = log::set_logger(&FACADE) {
         eprintln!("Init logger failed: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_default_and_module_levels() {
+        let filter: Filter = "warn, bug_graph::web=debug,actix_web=error".parse().unwrap();
+
+        assert_eq!(filter.level("bug_graph::repo"), LevelFilter::Warn);
+        assert_eq!(filter.level("bug_graph::web"), LevelFilter::Debug);
+        assert_eq!(filter.level("actix_web::server::h1"), LevelFilter::Error);
+        assert_eq!(filter.max(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn longest_module_prefix_wins() {
+        let filter: Filter = "bug_graph=warn,bug_graph::web=trace".parse().unwrap();
+
+        assert_eq!(filter.level("bug_graph::web::ws"), LevelFilter::Trace);
+        assert_eq!(filter.level("bug_graph::imp"), LevelFilter::Warn);
+        assert_eq!(filter.level("other"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_modules() {
+        let filter: Filter = "bug_graph::web=debug".parse().unwrap();
+
+        assert_eq!(filter.level("bug_graph::webby"), LevelFilter::Info);
+        assert!(filter.enabled("bug_graph::web", Level::Debug));
+        assert!(!filter.enabled("bug_graph::webby", Level::Debug));
+    }
+
+    #[test]
+    fn unknown_levels_are_errors() {
+        assert!("loud".parse::<Filter>().is_err());
+        assert!("bug_graph=loud".parse::<Filter>().is_err());
+        assert_eq!("".parse::<Filter>().unwrap().level("x"), LevelFilter::Info);
+    }
+}
@@ -26,97 +26,564 @@ extern crate futures;
 #[macro_use]
 extern crate actix;
 extern crate actix_web;
+extern crate cookie;
 #[macro_use]
 extern crate log;
 extern crate failure;
+extern crate bug_graph_protocol as protocol;
+#[macro_use]
+extern crate juniper;
+extern crate ring;
+extern crate rustls;
+extern crate webpki;
+extern crate toml;
+extern crate zip;
+extern crate fs2;
 
 mod repo;
 mod imp;
 mod web;
+mod api;
+mod graphql;
+mod auth;
 mod journal;
-mod protocol;
+mod hub;
+mod flaky;
+mod cluster;
+mod autolink;
+mod tls;
+mod assets;
+mod supervise;
+mod metrics;
+mod store;
+mod shutdown;
+mod cli;
+mod config;
+mod export;
+mod backup;
+mod sheet;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead};
+use std::process::exit;
+use std::sync::Arc;
 
-use futures::Future;
-use actix::{msgs::{Execute, StartActor}, prelude::*};
+use futures::{future, Future};
+use log::Level;
+use futures::future::Either;
+use actix::{msgs::Execute, prelude::*};
 use actix_web::server;
 
 use repo::Repo;
-use imp::{Importer, ScanDir};
-use journal::Journal;
+use imp::{Importer, ScanDir, WatchDirs};
+use shutdown::{Shutdown, Watch, WebServer};
+use journal::{Journal, Output, RotatingFile};
+use auth::Auth;
+use tls::{CertResolver, Reloader};
+use assets::Assets;
+use cli::OutputFormat;
+use export::ExportFormat;
+use config::{Config, WebConfig, ImportConfig, LogConfig};
+
+/// GraphQL queries block while they wait on the repository, so they get
+/// their own threads
+const GRAPHQL_THREADS: usize = 2;
+/// Seconds HTTP requests have to finish in once shut down has started
+const SHUTDOWN_TIMEOUT: u16 = 10;
+
+enum Command {
+    /// Import any results in the directory then serve the web client
+    Serve(Option<String>),
+    Import(Vec<String>),
+    Matrix(String),
+    Search(String),
+    Report(String),
+    Diff(String, String),
+    Export {
+        root: Option<String>,
+        depth: u32,
+        logs: bool,
+        format: ExportFormat,
+        output: Option<String>,
+    },
+    Backup(String),
+    Restore {
+        path: String,
+        force: bool,
+    },
+    HashPassword,
+    NewToken,
+}
+
+impl Command {
+    /// Commands which only look at the data directory, they do not lock it
+    /// so they can be used while the server is running
+    fn read_only(&self) -> bool {
+        match *self {
+            Command::Matrix(_) | Command::Search(_) | Command::Report(_) | Command::Diff(..) |
+            Command::Export { .. } => true,
+            _ => false,
+        }
+    }
+}
 
 struct ProgArgs {
-    json_path: String,
-    web: Option<String>,
+    command: Command,
+    format: OutputFormat,
+    /// The config file, which is read again on SIGHUP
+    config_path: Option<String>,
+    /// The settings given as flags, which override the config file's
+    flags: Config,
+    /// The flags and config file together, checked
+    config: Config,
 }
 
 impl ProgArgs {
     fn parse() -> Self {
-        use clap::{App, Arg};
+        use clap::{App, AppSettings, Arg, SubCommand};
 
         let args = App::new("Bug Graph")
-            .arg(Arg::with_name("JSON_FILE")
-                 .help("Test results")
-                 .required(true)
-                 .index(1))
-            .arg(Arg::with_name("web")
-                 .help("Start the web service")
-                 .long("web")
-                 .value_name("LISTEN_ADDR")
-                 .default_value("localhost:8080"))
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .setting(AppSettings::VersionlessSubcommands)
+            .arg(Arg::with_name("config")
+                 .help("Read settings from this TOML file, the flags override it")
+                 .long("config")
+                 .env("BUG_GRAPH_CONFIG")
+                 .value_name("FILE")
+                 .global(true))
+            .arg(Arg::with_name("data")
+                 .help("Save the repository here on shut down and load it on start up, \
+                        files which are already in it are not imported again")
+                 .long("data")
+                 .value_name("DATA_DIR")
+                 .global(true))
+            .arg(Arg::with_name("format")
+                 .help("How query, search, report and diff print their answer")
+                 .long("format")
+                 .possible_values(&["table", "json", "csv"])
+                 .default_value("table")
+                 .global(true))
+            .arg(Arg::with_name("log")
+                 .help("Log levels, overall and per module, \
+                        e.g. info,bug_graph::web=debug,actix_web=warn [default: info]")
+                 .long("log")
+                 .env("BUG_GRAPH_LOG")
+                 .value_name("SPEC")
+                 .global(true))
+            .arg(Arg::with_name("log-format")
+                 .help("Write log records as text or JSON lines [default: text]")
+                 .long("log-format")
+                 .possible_values(&["text", "json"])
+                 .global(true))
+            .arg(Arg::with_name("log-file")
+                 .help("Write the log to this file instead of stderr")
+                 .long("log-file")
+                 .value_name("FILE")
+                 .global(true))
+            .arg(Arg::with_name("log-file-size")
+                 .help("Rotate the log file when it reaches this many MiB [default: 10]")
+                 .long("log-file-size")
+                 .value_name("MIB")
+                 .global(true))
+            .arg(Arg::with_name("log-file-keep")
+                 .help("How many rotated log files to keep [default: 5]")
+                 .long("log-file-keep")
+                 .value_name("COUNT")
+                 .global(true))
+            .arg(Arg::with_name("log-keep")
+                 .help("How many log records to keep in memory for the web client \
+                        [default: 1000]")
+                 .long("log-keep")
+                 .value_name("COUNT")
+                 .global(true))
+            .subcommand(SubCommand::with_name("serve")
+                        .about("Start the web service")
+                        .arg(Arg::with_name("JSON_DIR")
+                             .help("Import the test results in this directory first")
+                             .index(1))
+                        .arg(Arg::with_name("web")
+                             .help("The address to listen on [default: localhost:8080]")
+                             .long("web")
+                             .value_name("LISTEN_ADDR"))
+                        .arg(Arg::with_name("users")
+                             .help("JSON file of user accounts, without it anyone can do \
+                                    anything")
+                             .long("users")
+                             .value_name("USERS_FILE"))
+                        .arg(Arg::with_name("tls-cert")
+                             .help("Serve HTTPS using this PEM certificate chain, reloaded on \
+                                    SIGHUP")
+                             .long("tls-cert")
+                             .value_name("CERT_FILE")
+                             .requires("tls-key"))
+                        .arg(Arg::with_name("tls-key")
+                             .help("The PEM private key for --tls-cert")
+                             .long("tls-key")
+                             .value_name("KEY_FILE")
+                             .requires("tls-cert"))
+                        .arg(Arg::with_name("static-dir")
+                             .help("Serve the web client from this directory instead of the \
+                                    copy built into the binary, e.g. res/static while \
+                                    developing the client")
+                             .long("static-dir")
+                             .value_name("DIR"))
+                        .arg(Arg::with_name("watch")
+                             .help("Import new results files from this directory while \
+                                    serving, may be given more than once")
+                             .long("watch")
+                             .value_name("DIR")
+                             .multiple(true)
+                             .number_of_values(1)))
+            .subcommand(SubCommand::with_name("import")
+                        .about("Import test results into the data directory then exit")
+                        .arg(Arg::with_name("PATHS")
+                             .help("Results files, or directories of them")
+                             .required(true)
+                             .multiple(true)))
+            .subcommand(SubCommand::with_name("query")
+                        .about("Query the data directory")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(SubCommand::with_name("matrix")
+                                    .about("Print the results of a test in each set, or of \
+                                            each test in a set")
+                                    .arg(Arg::with_name("TEST_OR_SET")
+                                         .help("A vertex's name or UUID")
+                                         .required(true))))
+            .subcommand(SubCommand::with_name("search")
+                        .about("Print the first ten tests and sets in alphabetical order, \
+                                starting from a term")
+                        .arg(Arg::with_name("TERM").required(true)))
+            .subcommand(SubCommand::with_name("report")
+                        .about("Print whether each test passed in a product revision")
+                        .arg(Arg::with_name("REV")
+                             .help("e.g. sle:15:668.1")
+                             .required(true)))
+            .subcommand(SubCommand::with_name("diff")
+                        .about("Print the tests whose status changed between two product \
+                                revisions (e.g. sle:15:668.1)")
+                        .arg(Arg::with_name("REV_A").required(true))
+                        .arg(Arg::with_name("REV_B").required(true)))
+            .subcommand(SubCommand::with_name("export")
+                        .about("Write the graph as GraphML, DOT or JSON Graph Format")
+                        .arg(Arg::with_name("GRAPH_FORMAT")
+                             .possible_values(&["graphml", "dot", "json"])
+                             .required(true))
+                        .arg(Arg::with_name("root")
+                             .help("Only export the vertices near this one, given by name \
+                                    or UUID")
+                             .long("root")
+                             .value_name("VERTEX"))
+                        .arg(Arg::with_name("depth")
+                             .help("How many edges away from the root to go")
+                             .long("depth")
+                             .value_name("EDGES")
+                             .default_value("2"))
+                        .arg(Arg::with_name("logs")
+                             .help("Include the results' logs")
+                             .long("logs"))
+                        .arg(Arg::with_name("output")
+                             .help("Write to this file instead of stdout")
+                             .long("output")
+                             .short("o")
+                             .value_name("FILE")))
+            .subcommand(SubCommand::with_name("backup")
+                        .about("Write everything in the data directory to a backup file")
+                        .arg(Arg::with_name("FILE").required(true)))
+            .subcommand(SubCommand::with_name("restore")
+                        .about("Replace the data directory's contents with a backup file")
+                        .arg(Arg::with_name("FILE").required(true))
+                        .arg(Arg::with_name("force")
+                             .help("Overwrite a data directory which already has a snapshot")
+                             .long("force")))
+            .subcommand(SubCommand::with_name("hash-password")
+                        .about("Read a password from stdin and print its hash for the users \
+                                file"))
+            .subcommand(SubCommand::with_name("new-token")
+                        .about("Print a new API token and its hash for the users file"))
             .get_matches();
 
+        // Global options are propagated down, so read them from the innermost
+        // subcommand which was given
+        let (name, sub) = args.subcommand();
+        let sub = sub.unwrap();
+        let opts = match sub.subcommand() {
+            (_, Some(inner)) => inner,
+            _ => sub,
+        };
+        let value = |opt: &str| opts.value_of(opt).map(|v| v.to_string());
+        let arg = |opt: &str| value(opt).unwrap();
+        let number = |opt: &str| -> Option<u64> {
+            value(opt).map(|n| n.parse().unwrap_or_else(|e| {
+                eprintln!("Bad --{}: {}", opt, e);
+                exit(1);
+            }))
+        };
+
+        let command = match name {
+            "serve" => Command::Serve(value("JSON_DIR")),
+            "import" => Command::Import(sub.values_of("PATHS").unwrap()
+                                        .map(|p| p.to_string()).collect()),
+            "query" => Command::Matrix(arg("TEST_OR_SET")),
+            "search" => Command::Search(arg("TERM")),
+            "report" => Command::Report(arg("REV")),
+            "diff" => Command::Diff(arg("REV_A"), arg("REV_B")),
+            "export" => Command::Export {
+                root: value("root"),
+                depth: number("depth").unwrap() as u32,
+                logs: opts.is_present("logs"),
+                format: arg("GRAPH_FORMAT").parse().unwrap(),
+                output: value("output"),
+            },
+            "backup" => Command::Backup(arg("FILE")),
+            "restore" => Command::Restore { path: arg("FILE"), force: opts.is_present("force") },
+            "hash-password" => Command::HashPassword,
+            "new-token" => Command::NewToken,
+            _ => unreachable!(),
+        };
+
+        let flags = Config {
+            data: value("data"),
+            web: WebConfig {
+                listen: value("web"),
+                users: value("users"),
+                tls_cert: value("tls-cert"),
+                tls_key: value("tls-key"),
+                static_dir: value("static-dir"),
+            },
+            import: ImportConfig {
+                watch: opts.values_of("watch")
+                    .map_or_else(Vec::new, |dirs| dirs.map(|d| d.to_string()).collect()),
+                interval: None,
+            },
+            log: LogConfig {
+                level: value("log"),
+                format: value("log-format"),
+                file: value("log-file"),
+                file_size: number("log-file-size"),
+                file_keep: number("log-file-keep").map(|n| n as usize),
+                keep: number("log-keep").map(|n| n as usize),
+            },
+        };
+        let config_path = value("config");
+        let config = match config_path {
+            Some(ref path) => Config::load(path).unwrap_or_else(|e| {
+                eprintln!("Could not load the config file {}", e);
+                exit(1);
+            }),
+            None => Config::default(),
+        };
+        let config = flags.clone().or(config);
+
+        if let Err(e) = config.check() {
+            eprintln!("Invalid settings:\n{}", e);
+            exit(1);
+        }
+
         Self {
-            json_path: args.value_of("JSON_FILE").unwrap().to_string(),
-            web: args.value_of("web").map(|v| v.to_string()),
+            command,
+            format: arg("format").parse().unwrap(),
+            config_path,
+            flags,
+            config,
         }
     }
 }
 
-fn start_web_server(web_arb: Addr<Arbiter>, repo: Addr<Repo>, url: String) {
+fn start_web_server(web_arb: Addr<Arbiter>, repo: Addr<Repo>, importer: Addr<Importer>,
+                    url: String, auth: Arc<Auth>, tls: Option<Arc<CertResolver>>,
+                    assets: Arc<Assets>) {
     web_arb.do_send(Execute::new(move || -> Result<(), ()> {
-        match server::new(move || web::new(repo.clone())).bind(url.clone()) {
+        let secure = tls.is_some();
+        let schema = Arc::new(graphql::create_schema());
+        let repo2 = repo.clone();
+        let executor = SyncArbiter::start(GRAPHQL_THREADS, move || {
+            graphql::GraphQLExecutor::new(schema.clone(), repo2.clone())
+        });
+
+        let app = move || {
+            web::new(repo.clone(), executor.clone(), importer.clone(), auth.clone(),
+                     assets.clone(), secure)
+        };
+
+        let bound = match tls {
+            Some(resolver) => {
+                Reloader(resolver.clone()).start();
+                server::new(app).bind_rustls(url.clone(), tls::server_config(resolver))
+            },
+            None => server::new(app).bind(url.clone()),
+        };
+
+        match bound {
             Err(e) => error!("Failed to bind web server to {}: {}", url, e),
             Ok(srv) => {
-                srv.start();
+                let srv = srv.disable_signals().shutdown_timeout(SHUTDOWN_TIMEOUT).start();
+                System::current().registry().get::<Shutdown>().do_send(WebServer(srv));
             }
         };
         Ok(())
     }));
 }
 
+/// Who runs imported from the command line are recorded against
+fn local_user() -> String {
+    env::var("USER").unwrap_or_else(|_| "local".to_string())
+}
+
+fn load_auth(users: Option<String>) -> Auth {
+    match users {
+        Some(path) => Auth::load(&path).unwrap_or_else(|e| {
+            eprintln!("Could not load users from {}: {}", path, e);
+            exit(1);
+        }),
+        None => {
+            eprintln!("No users file given, authentication is disabled");
+            Auth::disabled()
+        },
+    }
+}
+
+fn load_tls(tls: Option<(String, String)>) -> Option<Arc<CertResolver>> {
+    tls.map(|(cert, key)| {
+        Arc::new(CertResolver::load(cert.clone(), key).unwrap_or_else(|e| {
+            eprintln!("Could not load TLS certificate {}: {}", cert, e);
+            exit(1);
+        }))
+    })
+}
+
+fn log_output(file: Option<(String, u64, usize)>) -> Output {
+    match file {
+        Some((path, max_bytes, keep)) => {
+            Output::File(RotatingFile::open(path.clone(), max_bytes, keep).unwrap_or_else(|e| {
+                eprintln!("Could not open log file {}: {}", path, e);
+                exit(1);
+            }))
+        },
+        None => Output::Stderr,
+    }
+}
+
+/// Stop the system once a headless command has finished
+fn finish(res: Result<(), ()>) -> Result<(), ()> {
+    System::current().stop_with_code(if res.is_ok() { 0 } else { 1 });
+    Ok(())
+}
+
 fn main() {
+    let pargs = ProgArgs::parse();
+
+    match pargs.command {
+        Command::HashPassword => {
+            let mut password = String::new();
+
+            if let Err(e) = io::stdin().lock().read_line(&mut password) {
+                eprintln!("Could not read password: {}", e);
+                exit(1);
+            }
+            println!("{}", auth::hash_password(password.trim_right_matches(&['\r', '\n'][..])));
+            return;
+        },
+        Command::NewToken => {
+            let (token, hash) = auth::new_token();
+
+            println!("Token: {}\nHash for the users file: {}", token, hash);
+            return;
+        },
+        Command::Serve(_) => (),
+        _ => match pargs.config.data {
+            None => {
+                eprintln!("--data, or data in the config file, is needed for this command");
+                exit(1);
+            },
+            Some(ref dir) => if let Command::Restore { force: false, .. } = pargs.command {
+                if store::snapshot_path(Path::new(dir)).exists() {
+                    eprintln!("{} already has a snapshot, use --force to replace it", dir);
+                    exit(1);
+                }
+            },
+        },
+    }
+
+    let config = pargs.config;
+    let output = log_output(config.log_file());
     let sys = System::new("Bug Graph");
+    journal::init(config.log_filter(), config.log_format(), output, config.log_keep());
     let journal = System::current().registry().get::<Journal>();
-    journal.do_send(journal::Log { src: "main".into(),
+    journal.do_send(journal::Log { level: Level::Info,
+                                   module: module_path!().into(),
+                                   actor: "main".into(),
                                    msg: "Bug Graph 0.1.0".into() });
 
-    let pargs = ProgArgs::parse();
     let repo_arb = Arbiter::new("repository");
     let imp_arb = Arbiter::new("importer");
-    let web_arb = Arbiter::new("web");
-
-    {
-        let json_path = pargs.json_path.clone();
-        let imp_arb = imp_arb.clone();
-
-        Arbiter::spawn(
-            repo_arb
-                .send(StartActor::new(|_| Repo::default()))
-                .then(move |repo| match repo {
-                    Ok(repo) => {
-                        start_web_server(web_arb, repo.clone(), pargs.web.unwrap());
-                        imp_arb.send(StartActor::new(move |_| Importer::new(repo)))
-                    },
-                    Err(e) => panic!("Could not start repository: {}", e),
-                })
-                .then(|imp| match imp {
-                    Ok(imp) => imp.send(ScanDir { dir: json_path, ext: "json".to_string() }),
-                    Err(e) => panic!("Could not start importer: {}", e),
-                })
-                .map_err(|e| error!("Scan directory: {}", e))
-        );
+
+    let data = config.data.clone().map(PathBuf::from);
+    let repo = Repo::open(data, pargs.command.read_only()).unwrap_or_else(|e| {
+        eprintln!("Could not open the repository: {}", e);
+        exit(1);
+    });
+
+    // The supervisors restart the actors if they panic while handling a message
+    let repo = Supervisor::start_in_arbiter(&repo_arb, move |_| repo);
+    let imp = {
+        let repo = repo.clone();
+        Supervisor::start_in_arbiter(&imp_arb, move |_| Importer::new(repo))
+    };
+    System::current().registry().get::<Shutdown>()
+        .do_send(Watch { repo: repo.clone(), importer: imp.clone() });
+
+    let format = pargs.format;
+    match pargs.command {
+        Command::Serve(results) => {
+            let auth = Arc::new(load_auth(config.web.users.clone()));
+            let tls = load_tls(config.tls());
+            let assets = Arc::new(match config.web.static_dir.clone() {
+                Some(dir) => Assets::dir(PathBuf::from(dir)),
+                None => Assets::embedded(),
+            });
+
+            start_web_server(Arbiter::new("web"), repo.clone(), imp.clone(), config.listen(),
+                             auth, tls, assets);
+
+            if !config.import.watch.is_empty() {
+                imp.do_send(WatchDirs {
+                    dirs: config.import.watch.clone(),
+                    every: config.watch_interval(),
+                    user: local_user(),
+                });
+            }
+            if let Some(path) = pargs.config_path {
+                config::Reloader { path, flags: pargs.flags, importer: imp.clone(),
+                                   user: local_user() }.start();
+            }
+
+            let scan = match results {
+                Some(dir) => Either::A(imp.send(ScanDir { dir, ext: "json".into(),
+                                                          user: local_user() })
+                                       .map_err(|e| error!("Scan directory: {}", e))),
+                None => Either::B(future::ok(0)),
+            };
+            // Even a failed scan is finished, waiting would never make us ready
+            Arbiter::spawn(scan.then(|_| {
+                metrics::record(metrics::Ready);
+                Ok(())
+            }));
+        },
+        Command::Import(paths) => Arbiter::spawn(cli::import(imp, repo, paths, local_user())
+                                                 .then(finish)),
+        Command::Matrix(name) => Arbiter::spawn(cli::matrix(repo, name, format).then(finish)),
+        Command::Search(term) => Arbiter::spawn(cli::search(repo, term, format).then(finish)),
+        Command::Report(rev) => Arbiter::spawn(cli::report(repo, rev, format).then(finish)),
+        Command::Diff(from, to) => Arbiter::spawn(cli::diff(repo, from, to, format)
+                                                  .then(finish)),
+        Command::Export { root, depth, logs, format, output } => {
+            Arbiter::spawn(cli::export(repo, root, depth, logs, format, output).then(finish))
+        },
+        Command::Backup(path) => Arbiter::spawn(cli::backup(repo, path).then(finish)),
+        Command::Restore { path, .. } => Arbiter::spawn(cli::restore(repo, path).then(finish)),
+        Command::HashPassword | Command::NewToken => unreachable!(),
     }
 
-    journal::init();
-    sys.run();
+    exit(sys.run());
 }
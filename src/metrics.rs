@@ -0,0 +1,320 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Health checks and Prometheus metrics, these routes do not need a login so
+//! that monitoring can reach them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use futures::Future;
+use actix::prelude::*;
+use actix_web::{App, AsyncResponder, FutureResponse, HttpRequest, HttpResponse};
+use actix_web::http::Method;
+
+use repo::{GetCounts, GraphCounts};
+use web::AppState;
+
+/// Upper bounds of the query latency histogram's buckets in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// One results file has been imported, or failed to be
+#[derive(Message)]
+pub struct Imported {
+    pub results: u32,
+    pub took: Duration,
+    pub failed: bool,
+}
+
+/// A websocket was opened (1) or closed (-1)
+#[derive(Message)]
+pub struct Connections(pub i64);
+
+/// How long a websocket query took to answer
+#[derive(Message)]
+pub struct Answered {
+    pub query: &'static str,
+    pub took: Duration,
+}
+
+/// The initial import has finished, so the server is ready
+#[derive(Message)]
+pub struct Ready;
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsReady;
+
+/// The metrics in Prometheus' text format
+#[derive(Message)]
+#[rtype(result = "String")]
+pub struct Render;
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter_mut()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    ready: bool,
+    imported_files: u64,
+    imported_results: u64,
+    import_errors: u64,
+    import_secs: f64,
+    connections: i64,
+    latency: BTreeMap<&'static str, Histogram>,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE bug_graph_imported_files_total counter").unwrap();
+        writeln!(out, "bug_graph_imported_files_total {}", self.imported_files).unwrap();
+        writeln!(out, "# TYPE bug_graph_imported_results_total counter").unwrap();
+        writeln!(out, "bug_graph_imported_results_total {}", self.imported_results).unwrap();
+        writeln!(out, "# TYPE bug_graph_import_errors_total counter").unwrap();
+        writeln!(out, "bug_graph_import_errors_total {}", self.import_errors).unwrap();
+        writeln!(out, "# TYPE bug_graph_import_seconds_total counter").unwrap();
+        writeln!(out, "bug_graph_import_seconds_total {}", self.import_secs).unwrap();
+        writeln!(out, "# TYPE bug_graph_websocket_connections gauge").unwrap();
+        writeln!(out, "bug_graph_websocket_connections {}", self.connections).unwrap();
+
+        writeln!(out, "# TYPE bug_graph_query_seconds histogram").unwrap();
+        for (query, h) in &self.latency {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&h.buckets) {
+                writeln!(out, "bug_graph_query_seconds_bucket{{query=\"{}\",le=\"{}\"}} {}",
+                         query, bound, count).unwrap();
+            }
+            writeln!(out, "bug_graph_query_seconds_bucket{{query=\"{}\",le=\"+Inf\"}} {}",
+                     query, h.count).unwrap();
+            writeln!(out, "bug_graph_query_seconds_sum{{query=\"{}\"}} {}", query, h.sum).unwrap();
+            writeln!(out, "bug_graph_query_seconds_count{{query=\"{}\"}} {}",
+                     query, h.count).unwrap();
+        }
+
+        out
+    }
+}
+
+impl Actor for Metrics {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Metrics {}
+impl SystemService for Metrics {}
+
+impl Handler<Imported> for Metrics {
+    type Result = ();
+
+    fn handle(&mut self, msg: Imported, _ctx: &mut Context<Self>) {
+        self.imported_files += 1;
+        self.imported_results += u64::from(msg.results);
+        self.import_secs += secs(msg.took);
+        if msg.failed {
+            self.import_errors += 1;
+        }
+    }
+}
+
+impl Handler<Connections> for Metrics {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connections, _ctx: &mut Context<Self>) {
+        self.connections += msg.0;
+    }
+}
+
+impl Handler<Answered> for Metrics {
+    type Result = ();
+
+    fn handle(&mut self, msg: Answered, _ctx: &mut Context<Self>) {
+        self.latency.entry(msg.query).or_insert_with(Histogram::default).observe(secs(msg.took));
+    }
+}
+
+impl Handler<Ready> for Metrics {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Ready, _ctx: &mut Context<Self>) {
+        self.ready = true;
+    }
+}
+
+impl Handler<IsReady> for Metrics {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: IsReady, _ctx: &mut Context<Self>) -> bool {
+        self.ready
+    }
+}
+
+impl Handler<Render> for Metrics {
+    type Result = String;
+
+    fn handle(&mut self, _msg: Render, _ctx: &mut Context<Self>) -> String {
+        self.render()
+    }
+}
+
+/// Send a measurement to the metrics actor
+pub fn record<M>(msg: M)
+where
+    M: Message<Result = ()> + Send + 'static,
+    Metrics: Handler<M>,
+{
+    System::current().registry().get::<Metrics>().do_send(msg);
+}
+
+fn render_counts(counts: &GraphCounts) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "# TYPE bug_graph_vertices gauge").unwrap();
+    for (t, n) in &counts.vertices {
+        writeln!(out, "bug_graph_vertices{{type=\"{}\"}} {}", t, n).unwrap();
+    }
+    writeln!(out, "# TYPE bug_graph_edges gauge").unwrap();
+    for (t, n) in &counts.edges {
+        writeln!(out, "bug_graph_edges{{type=\"{}\"}} {}", t, n).unwrap();
+    }
+
+    out
+}
+
+/// The process is up and the repository has not stopped
+fn healthz(req: &HttpRequest<AppState>) -> HttpResponse {
+    if req.state().repo.connected() {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("repository stopped")
+    }
+}
+
+/// The initial import has finished
+fn readyz(_req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    System::current().registry().get::<Metrics>().send(IsReady)
+        .from_err()
+        .and_then(|ready| Ok(if ready {
+            HttpResponse::Ok().body("ready")
+        } else {
+            HttpResponse::ServiceUnavailable().body("importing")
+        }))
+        .responder()
+}
+
+fn metrics(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let counts = req.state().repo.send(GetCounts);
+    let rendered = System::current().registry().get::<Metrics>().send(Render);
+
+    counts.join(rendered)
+        .from_err()
+        .and_then(|(counts, rendered)| {
+            Ok(HttpResponse::Ok()
+               .content_type("text/plain; version=0.0.4")
+               .body(render_counts(&counts) + &rendered))
+        })
+        .responder()
+}
+
+pub fn register(app: App<AppState>) -> App<AppState> {
+    app.resource("/healthz", |r| r.method(Method::GET).f(healthz))
+        .resource("/readyz", |r| r.method(Method::GET).f(readyz))
+        .resource("/metrics", |r| r.method(Method::GET).f(metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_format() {
+        let mut sys = System::new("test");
+        let metrics = Metrics::default().start();
+
+        metrics.do_send(Imported { results: 40, took: Duration::from_millis(1500),
+                                   failed: false });
+        metrics.do_send(Imported { results: 0, took: Duration::from_millis(500), failed: true });
+        metrics.do_send(Connections(1));
+        metrics.do_send(Connections(1));
+        metrics.do_send(Connections(-1));
+        metrics.do_send(Answered { query: "Search", took: Duration::from_millis(500) });
+        metrics.do_send(Answered { query: "Search", took: Duration::from_secs(2) });
+
+        assert_eq!(sys.block_on(metrics.send(Render)).unwrap(), "\
+# TYPE bug_graph_imported_files_total counter
+bug_graph_imported_files_total 2
+# TYPE bug_graph_imported_results_total counter
+bug_graph_imported_results_total 40
+# TYPE bug_graph_import_errors_total counter
+bug_graph_import_errors_total 1
+# TYPE bug_graph_import_seconds_total counter
+bug_graph_import_seconds_total 2
+# TYPE bug_graph_websocket_connections gauge
+bug_graph_websocket_connections 1
+# TYPE bug_graph_query_seconds histogram
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.001\"} 0
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.005\"} 0
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.01\"} 0
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.05\"} 0
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.1\"} 0
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"0.5\"} 1
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"1\"} 1
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"5\"} 2
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"10\"} 2
+bug_graph_query_seconds_bucket{query=\"Search\",le=\"+Inf\"} 2
+bug_graph_query_seconds_sum{query=\"Search\"} 2.5
+bug_graph_query_seconds_count{query=\"Search\"} 2
+");
+    }
+
+    #[test]
+    fn ready_once_told() {
+        let mut sys = System::new("test");
+        let metrics = Metrics::default().start();
+
+        assert!(!sys.block_on(metrics.send(IsReady)).unwrap());
+        metrics.do_send(Ready);
+        assert!(sys.block_on(metrics.send(IsReady)).unwrap());
+    }
+}
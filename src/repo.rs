@@ -14,14 +14,30 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::convert::Into;
-use std::collections::{BTreeMap, HashMap};
-
-use indradb::{Vertex, Type, EdgeKey, VertexQuery, Datastore, MemoryDatastore, Transaction};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use indradb::{Vertex, Type, EdgeKey, VertexQuery, EdgeQuery, Datastore, MemoryDatastore,
+              Transaction};
 use indradb::Result as IResult;
 use actix::prelude::*;
+use serde_json::{self, Value as JsonValue};
 use uuid::Uuid;
 
-use protocol::{VertInfo, ResultMatrix, ResultInMatrix};
+use protocol::{VertInfo, ResultMatrix, ResultInMatrix, FlakyTest, RevisionDiff, HistoryEntry,
+               FailureCluster, AuditEntry, AuditAction, AutoLinkRule, Event, TestOutcome};
+use flaky;
+use cluster::{self, Failure};
+use autolink;
+use hub;
+use supervise::{guard, Guarded};
+use store::{self, Snapshot, SnapEdge};
+use export::Graph;
+use backup::{self, Record};
 
 macro_rules! itype {
     ($vert_name:ident) => (
@@ -33,12 +49,51 @@ lazy_static! {
     pub static ref PASS_ET: Type = itype!(passed);
     pub static ref FAIL_ET: Type = itype!(failed);
     pub static ref ISIN_ET: Type = itype!(is_in);
+    pub static ref INRUN_ET: Type = itype!(in_run);
+    pub static ref LINK_ET: Type = itype!(linked);
 
     pub static ref TEST_VT: Type = itype!(test);
     pub static ref TEST_RES_VT: Type = itype!(result);
     pub static ref SET_VT: Type = itype!(set);
+    pub static ref RUN_VT: Type = itype!(run);
+    pub static ref BUG_VT: Type = itype!(bug);
 }
 
+/// Vertex metadata holding a named vertex's name, so the name index can be
+/// rebuilt
+pub const NAME_MD: &str = "name";
+/// Vertex metadata holding a run's start time in seconds since the epoch
+pub const TIMESTAMP_MD: &str = "timestamp";
+/// Vertex metadata holding the user who imported a run
+pub const IMPORTER_MD: &str = "imported_by";
+/// Vertex metadata holding a result's log output
+pub const LOG_MD: &str = "log";
+/// Vertex metadata holding a failed result's log signature
+pub const SIGNATURE_MD: &str = "signature";
+/// Vertex metadata set once all of a run's results have been imported
+const COMPLETE_MD: &str = "complete";
+/// Edge metadata holding a bug link's LinkInfo
+const LINK_MD: &str = "link";
+/// All the vertex metadata, which is what gets saved in a snapshot
+const VERTEX_MDS: &[&str] = &[NAME_MD, TIMESTAMP_MD, IMPORTER_MD, LOG_MD, SIGNATURE_MD,
+                              COMPLETE_MD];
+
+/// Upper limit on the links which can be reverted at once
+const LINKS_MAX: u32 = 1_000_000;
+/// The most audit entries returned by a query
+const AUDIT_TAIL: usize = 500;
+
+/// How often subscribers are told about new results, so that an import
+/// does not cause an event per result
+const PUBLISH_INTERVAL_SECS: u64 = 1;
+
+/// How long the vertex and edge counts are reused for, counting them means
+/// going through the whole graph
+const COUNTS_MAX_AGE_SECS: u64 = 60;
+
+/// Seconds between the start of the Gregorian calendar and the Unix epoch
+const GREGORIAN_OFFSET: u64 = 12_219_292_800;
+
 pub enum TestStatus {
     Pass,
     Fail
@@ -53,12 +108,24 @@ impl Into<&'static Type> for TestStatus {
     }
 }
 
+/// One execution of a test suite, usually a single result file
+#[derive(Message)]
+#[rtype(Uuid)]
+pub struct NewRun {
+    pub name: String,
+    pub timestamp: u64,
+    /// Who imported the run
+    pub user: String,
+}
+
 #[derive(Message)]
 #[rtype(Uuid)]
 pub struct NewResult {
     pub test_fqn: String,
     pub status: TestStatus,
-    pub properties: Vec<String>
+    pub run: Uuid,
+    pub properties: Vec<String>,
+    pub log: Option<String>,
 }
 
 pub struct GetSetVerts(pub Option<Uuid>);
@@ -75,6 +142,154 @@ pub struct Search(pub String);
 #[rtype(ResultMatrix)]
 pub struct GetResultMatrix(pub Uuid);
 
+#[derive(Message)]
+#[rtype(result = "Vec<FlakyTest>")]
+pub struct GetFlakyTests(pub Uuid);
+
+/// Compare the results in two sets, usually product revisions
+#[derive(Message)]
+#[rtype(RevisionDiff)]
+pub struct GetRevisionDiff {
+    pub from: Uuid,
+    pub to: Uuid,
+}
+
+/// The results of each test in a set, usually a product revision
+#[derive(Message)]
+#[rtype(result = "Vec<TestOutcome>")]
+pub struct GetSetReport(pub Uuid);
+
+#[derive(Message)]
+#[rtype(result = "Option<Uuid>")]
+pub struct GetVertId(pub String);
+
+/// Mark a run as completely imported and add it to the audit trail
+#[derive(Message)]
+pub struct FinishRun {
+    pub run: Uuid,
+    pub results: u32,
+    pub user: String,
+}
+
+/// Look up a run by name, returns it and whether it was completely imported
+#[derive(Message)]
+#[rtype(result = "Option<(Uuid, bool)>")]
+pub struct GetRun(pub String);
+
+/// Write a snapshot to the data directory, if there is one
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Save;
+
+/// Write everything to a backup file, returns how many records it has
+#[derive(Message)]
+#[rtype(result = "Result<u64, String>")]
+pub struct Backup(pub PathBuf);
+
+/// Replace everything with a backup file's contents, returns how many
+/// records it had
+#[derive(Message)]
+#[rtype(result = "Result<u64, String>")]
+pub struct Restore(pub PathBuf);
+
+/// Cluster the failed results of the tests in a set by their logs
+#[derive(Message)]
+#[rtype(result = "Vec<FailureCluster>")]
+pub struct GetFailureClusters(pub Uuid);
+
+/// Link test results to a bug, the bug is created if necessary
+#[derive(Message)]
+#[rtype(usize)]
+pub struct LinkBug {
+    pub bug: String,
+    pub results: Vec<Uuid>,
+    pub user: String,
+}
+
+#[derive(Message)]
+#[rtype(usize)]
+pub struct UnlinkBug {
+    pub bug: String,
+    pub results: Vec<Uuid>,
+    pub user: String,
+}
+
+/// Remove the links which were made automatically, optionally only those
+/// made by a particular rule.
+#[derive(Message)]
+#[rtype(usize)]
+pub struct RevertAutoLinks {
+    pub rule: Option<String>,
+    pub user: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<AuditEntry>")]
+pub struct GetAuditTrail;
+
+/// A test's results in the order they were run
+#[derive(Message)]
+#[rtype(result = "Vec<HistoryEntry>")]
+pub struct GetTestHistory(pub Uuid);
+
+/// A vertex with its name, results and some others do not have one
+pub struct Vert {
+    pub id: Uuid,
+    pub t: Type,
+    pub name: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<Vert>")]
+pub struct GetVerts(pub Vec<Uuid>);
+
+/// The vertices at the other end of a vertex's edges of one type
+#[derive(Message)]
+#[rtype(result = "Vec<Vert>")]
+pub struct GetNeighbours {
+    pub id: Uuid,
+    pub edge: Type,
+    pub outbound: bool,
+    pub limit: u32,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<JsonValue>")]
+pub struct GetMetadata {
+    pub id: Uuid,
+    pub name: &'static str,
+}
+
+/// Delete a run and its results, returns None if there is no such run
+#[derive(Message)]
+#[rtype(result = "Option<u32>")]
+pub struct DeleteRun {
+    pub run: Uuid,
+    pub user: String,
+}
+
+/// How many vertices and edges there are of each type
+#[derive(Clone, Default)]
+pub struct GraphCounts {
+    pub vertices: BTreeMap<String, u64>,
+    pub edges: BTreeMap<String, u64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "GraphCounts")]
+pub struct GetCounts;
+
+/// The whole graph for exporting, or only the vertices within `depth` edges
+/// of `root`
+#[derive(Message)]
+#[rtype(result = "Result<Graph, String>")]
+pub struct GetGraph {
+    pub root: Option<Uuid>,
+    pub depth: u32,
+    /// Include the results' logs
+    pub logs: bool,
+}
+
 #[derive(Default)]
 struct VertNameIndex {
     verts: BTreeMap<String, Uuid>,
@@ -93,6 +308,12 @@ impl VertNameIndex {
         self.names.insert(vert, name);
     }
 
+    fn remove(&mut self, vert: &Uuid) {
+        if let Some(name) = self.names.remove(vert) {
+            self.verts.remove(&name);
+        }
+    }
+
     fn get_all(&self) -> Vec<(String, Uuid)> {
         self.verts.iter().map(|(name, uuid)| (name.clone(), *uuid)).collect()
     }
@@ -109,13 +330,45 @@ impl VertNameIndex {
         self.verts.range(name..).take(10)
             .map(|(name, uuid)| (name.clone(), *uuid)).collect()
     }
+
+    fn with_prefix(&self, prefix: &str) -> Vec<Uuid> {
+        self.verts.range(prefix.to_string()..)
+            .take_while(|(name, _)| name.starts_with(prefix))
+            .map(|(_, uuid)| *uuid).collect()
+    }
+}
+
+/// Stored with each bug link
+#[derive(Serialize, Deserialize)]
+struct LinkInfo {
+    user: String,
+    time: u64,
+    auto: Option<AutoLinkRule>,
 }
 
 pub struct Repo {
     indradb: MemoryDatastore,
     id_indx: VertNameIndex,
+    audit: Vec<AuditEntry>,
+    /// Results added to each set since subscribers were last told
+    new_results: HashMap<Uuid, u32>,
+    /// Where snapshots are saved, everything is lost on exit without it
+    data: Option<PathBuf>,
+    /// Held for as long as the repository uses the data directory
+    lock: Option<store::Lock>,
+    /// The last vertex and edge counts and when they were made
+    counts: Option<(Instant, GraphCounts)>,
 }
 
+/// Upper limit on the results of a single set, e.g. a product revision
+const SET_RESULTS_MAX: u32 = 100_000;
+/// Upper limit on the passes, and separately the failures, of a single
+/// test. They are read in no particular order, so it must be more than a
+/// test ever has or the newest might be missed.
+const TEST_RESULTS_MAX: u32 = 100_000;
+/// How many vertices are read at a time while writing a backup
+const BACKUP_PAGE: u32 = 10_000;
+
 fn new_edge<T: Transaction>(t: &T, egress: &Uuid, etype: &Type, ingress: &Uuid) {
     t.create_edge(
         &EdgeKey::new(*egress, etype.clone(), *ingress)
@@ -126,14 +379,327 @@ fn new_vert<T: Transaction>(t: &T, vtype: &Type) -> Uuid {
     t.create_vertex_from_type(vtype.clone()).unwrap()
 }
 
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn bug_name(bug: &str) -> String {
+    format!("bug:{}", bug)
+}
+
+/// Results are created with V1 UUIDs, so their timestamps give the creation
+/// time in 100ns intervals since the start of the Gregorian calendar.
+fn creation_order(result: &Uuid) -> u64 {
+    result.to_timestamp().map_or(0, |(ts, _)| ts)
+}
+
+/// Pass/fail counts of the results in one result matrix cell
+struct MatrixCell {
+    vinfo: VertInfo,
+    passes: u32,
+    fails: u32,
+    /// Every (run, passed) status in run order, for deciding if the cell is
+    /// flaky
+    statuses: Vec<(Uuid, bool)>,
+}
+
+impl MatrixCell {
+    fn new(vinfo: VertInfo) -> Self {
+        MatrixCell {
+            vinfo,
+            passes: 0,
+            fails: 0,
+            statuses: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, run: Uuid, status: bool) {
+        if status {
+            self.passes += 1;
+        } else {
+            self.fails += 1;
+        }
+        self.statuses.push((run, status));
+    }
+
+    /// Judged on the latest results, like the flaky tests query
+    fn flaky(&self) -> bool {
+        flaky::is_flaky_recently(&self.statuses)
+    }
+}
+
+fn snap_edge(key: &EdgeKey) -> SnapEdge {
+    (key.outbound_id, key.t.0.clone(), key.inbound_id)
+}
+
+fn edge_key((outbound, t, inbound): SnapEdge) -> EdgeKey {
+    EdgeKey::new(outbound, Type(t), inbound)
+}
+
+/// The vertices at most `depth` edges away from `root`, going either way
+/// along the edges
+fn reachable<T: Transaction>(t: &T, root: Uuid, depth: u32) -> IResult<Vec<Uuid>> {
+    let mut seen = HashSet::new();
+    let mut frontier = vec![root];
+
+    seen.insert(root);
+    for _ in 0 .. depth {
+        if frontier.is_empty() {
+            break;
+        }
+
+        let q = VertexQuery::Vertices { ids: frontier };
+        let out = t.get_edges(&q.clone().outbound_edges(None, None, None, u32::max_value()))?;
+        let into = t.get_edges(&q.inbound_edges(None, None, None, u32::max_value()))?;
+
+        frontier = out.iter().map(|e| e.key.inbound_id)
+            .chain(into.iter().map(|e| e.key.outbound_id))
+            .filter(|id| seen.insert(*id))
+            .collect();
+    }
+
+    Ok(seen.into_iter().collect())
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Call `f` with every vertex, BACKUP_PAGE of them at a time in ID order
+fn vertex_pages<T, F>(t: &T, mut f: F) -> io::Result<()>
+where
+    T: Transaction,
+    F: FnMut(Vec<Vertex>) -> io::Result<()>,
+{
+    let mut start_id = None;
+
+    loop {
+        let q = VertexQuery::All { start_id, limit: BACKUP_PAGE };
+        let mut page = t.get_vertices(&q).map_err(other)?;
+        let last = page.len() < BACKUP_PAGE as usize;
+
+        // Each page starts with the last vertex of the one before
+        if start_id.is_some() && page.first().map(|v| v.id) == start_id {
+            page.remove(0);
+        }
+        start_id = page.last().map(|v| v.id);
+        if !page.is_empty() {
+            f(page)?;
+        }
+        if last || start_id.is_none() {
+            return Ok(());
+        }
+    }
+}
+
 impl Repo {
 
+    /// Lock the data directory and start with its snapshot, if there is one.
+    /// A read only repository does not take the lock or save a snapshot, so
+    /// it can be opened while the server is running, but it only sees what
+    /// the server saved when it last stopped.
+    pub fn open(data: Option<PathBuf>, read_only: bool) -> io::Result<Repo> {
+        let mut repo = Repo::default();
+        let snap = match data {
+            Some(ref dir) => {
+                if !read_only {
+                    repo.lock = Some(store::Lock::take(dir)?);
+                }
+                store::read(dir)?
+            },
+            None => None,
+        };
+
+        if let Some(snap) = snap {
+            repo.restore(snap).map_err(other)?;
+            info!("Restored {} names from the snapshot", repo.rebuild_index().unwrap_or(0));
+        }
+        if !read_only {
+            repo.data = data;
+        }
+
+        Ok(repo)
+    }
+
+    fn restore(&mut self, snap: Snapshot) -> IResult<()> {
+        let t = self.indradb.transaction()?;
+
+        for (id, vtype) in snap.vertices {
+            t.create_vertex(&Vertex::with_id(id, Type(vtype)))?;
+        }
+        for edge in snap.edges {
+            t.create_edge(&edge_key(edge))?;
+        }
+        for (name, id, value) in snap.vertex_metadata {
+            t.set_vertex_metadata(&VertexQuery::Vertices { ids: vec![id] }, &name, &value)?;
+        }
+        for (name, edge, value) in snap.edge_metadata {
+            t.set_edge_metadata(&EdgeQuery::Edges { keys: vec![edge_key(edge)] }, &name, &value)?;
+        }
+        self.audit = snap.audit;
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> IResult<Snapshot> {
+        let mut snap = self.partial_snapshot(None, VERTEX_MDS)?;
+
+        snap.audit = self.audit.clone();
+        Ok(snap)
+    }
+
+    /// The vertices within `depth` edges of a root, or all of them, their
+    /// outbound edges and only the vertex metadata named in `mds`. Nothing
+    /// else is read from the datastore.
+    fn partial_snapshot(&self, root: Option<(Uuid, u32)>, mds: &[&str]) -> IResult<Snapshot> {
+        let t = self.indradb.transaction()?;
+        let verts = match root {
+            Some((root, depth)) => VertexQuery::Vertices { ids: reachable(&t, root, depth)? },
+            None => VertexQuery::All { start_id: None, limit: u32::max_value() },
+        };
+        let edges = verts.clone().outbound_edges(None, None, None, u32::max_value());
+        let mut snap = Snapshot::default();
+
+        snap.vertices = t.get_vertices(&verts)?.into_iter().map(|v| (v.id, v.t.0)).collect();
+        snap.edges = t.get_edges(&edges)?.iter().map(|e| snap_edge(&e.key)).collect();
+        for name in mds {
+            for md in t.get_vertex_metadata(&verts, name)? {
+                snap.vertex_metadata.push((name.to_string(), md.id, md.value));
+            }
+        }
+        for md in t.get_edge_metadata(&edges, LINK_MD)? {
+            snap.edge_metadata.push((LINK_MD.to_string(), snap_edge(&md.key), md.value));
+        }
+
+        Ok(snap)
+    }
+
+    /// Write everything to a backup, reading a page of vertices at a time so
+    /// the whole repository is never copied at once
+    fn backup<W: Write>(&self, out: W) -> io::Result<u64> {
+        let t = self.indradb.transaction().map_err(other)?;
+        let mut out = backup::Writer::new(out)?;
+
+        // The vertices go first, so everything which refers to them comes after
+        vertex_pages(&t, |page| {
+            for v in page {
+                out.write(&Record::Vertex(v.id, v.t.0))?;
+            }
+            Ok(())
+        })?;
+        vertex_pages(&t, |page| {
+            let verts = VertexQuery::Vertices { ids: page.into_iter().map(|v| v.id).collect() };
+            let edges = verts.clone().outbound_edges(None, None, None, u32::max_value());
+
+            for e in t.get_edges(&edges).map_err(other)? {
+                out.write(&Record::Edge(snap_edge(&e.key)))?;
+            }
+            for name in VERTEX_MDS {
+                for md in t.get_vertex_metadata(&verts, name).map_err(other)? {
+                    out.write(&Record::VertexMetadata(name.to_string(), md.id, md.value))?;
+                }
+            }
+            for md in t.get_edge_metadata(&edges, LINK_MD).map_err(other)? {
+                out.write(&Record::EdgeMetadata(LINK_MD.to_string(), snap_edge(&md.key),
+                                                md.value))?;
+            }
+            Ok(())
+        })?;
+        for (name, id) in &self.id_indx.verts {
+            out.write(&Record::Name(name.clone(), *id))?;
+        }
+        for entry in &self.audit {
+            out.write(&Record::Audit(entry.clone()))?;
+        }
+
+        out.finish()
+    }
+
+    /// Write a backup file, the file is only replaced once the whole backup
+    /// has been written
+    fn backup_file(&self, path: &Path) -> io::Result<u64> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let records = {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            let records = self.backup(&mut out)?;
+
+            out.get_ref().sync_all()?;
+            records
+        };
+        fs::rename(tmp, path)?;
+        Ok(records)
+    }
+
+    /// Add a backup's contents as each record is read
+    fn read_backup<R: BufRead>(&mut self, input: R) -> io::Result<u64> {
+        let t = self.indradb.transaction().map_err(other)?;
+        let (index, audit) = (&mut self.id_indx, &mut self.audit);
+
+        backup::read(input, |record| {
+            let res = match record {
+                Record::Vertex(id, vtype) => {
+                    t.create_vertex(&Vertex::with_id(id, Type(vtype))).map(|_| ())
+                },
+                Record::Edge(edge) => match t.create_edge(&edge_key(edge.clone())) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        let (outbound, et, inbound) = edge;
+                        return Err(other(format!("The {} edge from {} to {} refers to a vertex \
+                                                  which is not in the backup",
+                                                 et, outbound, inbound)));
+                    },
+                    Err(e) => Err(e),
+                },
+                Record::VertexMetadata(name, id, value) => {
+                    t.set_vertex_metadata(&VertexQuery::Vertices { ids: vec![id] }, &name, &value)
+                },
+                Record::EdgeMetadata(name, edge, value) => {
+                    let q = EdgeQuery::Edges { keys: vec![edge_key(edge)] };
+                    t.set_edge_metadata(&q, &name, &value)
+                },
+                Record::Name(name, id) => {
+                    index.insert(name, id);
+                    Ok(())
+                },
+                Record::Audit(entry) => {
+                    audit.push(entry);
+                    Ok(())
+                },
+            };
+            res.map_err(other)
+        })
+    }
+
+    /// Recreate the name index from the names stored with the vertices, in
+    /// case a panic left it out of step
+    fn rebuild_index(&mut self) -> IResult<usize> {
+        let t = self.indradb.transaction()?;
+        let q = VertexQuery::All { start_id: None, limit: u32::max_value() };
+        let mut indx = VertNameIndex::default();
+
+        for md in t.get_vertex_metadata(&q, NAME_MD)? {
+            if let Some(name) = md.value.as_str() {
+                indx.insert(name, md.id);
+            }
+        }
+
+        let count = indx.names.len();
+        self.id_indx = indx;
+        Ok(count)
+    }
+
     fn intern_name<T>(&mut self, t: &T, name_of: &Type, name: &str) -> Uuid
     where
         T: Transaction
     {
         if !self.id_indx.contains_name(name) {
-            self.id_indx.insert(name, new_vert(t, name_of));
+            let vert = new_vert(t, name_of);
+            let q = VertexQuery::Vertices { ids: vec![vert] };
+
+            t.set_vertex_metadata(&q, NAME_MD, &JsonValue::from(name)).unwrap();
+            self.id_indx.insert(name, vert);
         }
 
         *self.id_indx.get_vert(name).unwrap()
@@ -145,7 +711,7 @@ impl Repo {
     {
         let mut outer_cat: Option<Uuid> = None;
 
-        for (i, c) in name.chars().enumerate() {
+        for (i, c) in name.char_indices() {
             if c == ':' {
                 let category = self.intern_name(t, &SET_VT, &name[0 .. i]);
                 if let Some(ref ocat) = outer_cat {
@@ -175,17 +741,13 @@ impl Repo {
 
     fn get_test_results<T: Transaction>(&self, t: &T, test: Uuid)
                                         -> IResult<Vec<(Uuid, bool)>> {
-        let q = VertexQuery::Vertices { ids: vec![test] };
-
-        let passed = t.get_vertices(&q.outbound_edges(Some(PASS_ET.clone()), None, None, 500)
-                                    .inbound_vertices(500))?;
-        let res = passed.into_iter().map(|v| (v.id, true));
+        let query = |status: &Type| (VertexQuery::Vertices { ids: vec![test] })
+            .outbound_edges(Some(status.clone()), None, None, TEST_RESULTS_MAX)
+            .inbound_vertices(TEST_RESULTS_MAX);
+        let passed = t.get_vertices(&query(&*PASS_ET))?.into_iter().map(|v| (v.id, true));
+        let failed = t.get_vertices(&query(&*FAIL_ET))?.into_iter().map(|v| (v.id, false));
 
-        let q = VertexQuery::Vertices { ids: vec![test] };
-        Ok(res.chain(t.get_vertices(&q.outbound_edges(Some(FAIL_ET.clone()), None, None, 500)
-                                 .inbound_vertices(500))?
-                     .into_iter()
-                     .map(|v| (v.id, false))).collect())
+        Ok(passed.chain(failed).collect())
     }
 
     fn get_test_result_props<T: Transaction>(&self, t: &T, test_result: Uuid)
@@ -201,6 +763,274 @@ impl Repo {
            .collect())
     }
 
+    /// Count a test's results by the properties (sets) each result is in
+    fn group_test_results<T: Transaction>(&self, t: &T, test: &VertInfo)
+                                          -> HashMap<Uuid, MatrixCell> {
+        let VertInfo(ref test_name, test) = *test;
+        let results = self.get_test_results(t, test).unwrap_or_else(|e| {
+            error!("Failed test results: {}", e);
+            Vec::default()
+        });
+
+        if results.len() < 1 {
+            debug!("No test-results found for {} ({})", test_name, test);
+        }
+        let results = self.sort_results(t, results);
+
+        let mut cells = HashMap::<Uuid, MatrixCell>::new();
+        for (result, status, run) in results.into_iter() {
+            let props = self.get_test_result_props(t, result).unwrap_or_else(|e| {
+                error!("Failed to get test result properties/sets: {}", e);
+                Vec::default()
+            });
+
+            if props.len() < 1 {
+                debug!("No test-properties found for {}'s test-result: {}",
+                       test_name, result);
+            }
+
+            for prop in props.into_iter() {
+                cells.entry(prop.1)
+                    .or_insert_with(|| MatrixCell::new(prop))
+                    .add(run, status);
+            }
+        }
+
+        cells
+    }
+
+    /// The run a result belongs to and the time it started
+    fn get_result_run<T: Transaction>(&self, t: &T, test_result: Uuid)
+                                      -> IResult<Option<(Uuid, u64)>> {
+        let q = (VertexQuery::Vertices { ids: vec![test_result] })
+            .outbound_edges(Some(INRUN_ET.clone()), None, None, 1)
+            .inbound_vertices(1);
+
+        Ok(t.get_vertex_metadata(&q, TIMESTAMP_MD)?
+           .into_iter()
+           .next()
+           .map(|md| (md.id, md.value.as_u64().unwrap_or(0))))
+    }
+
+    /// Sort a test's results into the order they were run, as (result,
+    /// passed, run) triples. Results from the same run, or without one, are
+    /// ordered by creation time. A result without a run is its own run.
+    fn sort_results<T: Transaction>(&self, t: &T, results: Vec<(Uuid, bool)>)
+                                    -> Vec<(Uuid, bool, Uuid)> {
+        let mut keyed: Vec<((u64, u64), (Uuid, bool, Uuid))> = results.into_iter()
+            .map(|(result, passed)| {
+                let run = self.get_result_run(t, result).unwrap_or_else(|e| {
+                    error!("Failed to get test result's run: {}", e);
+                    None
+                });
+                let created = creation_order(&result);
+                let created_secs = (created / 10_000_000).saturating_sub(GREGORIAN_OFFSET);
+                let (run, time) = run.unwrap_or((result, created_secs));
+
+                ((time, created), (result, passed, run))
+            }).collect();
+
+        keyed.sort_by_key(|(key, _)| *key);
+        keyed.into_iter().map(|(_, res)| res).collect()
+    }
+
+    /// Tests which have a result in the given set
+    fn get_set_tests<T: Transaction>(&self, t: &T, set: Uuid) -> IResult<Vec<VertInfo>> {
+        let q = (VertexQuery::Vertices { ids: vec![set] })
+            .inbound_edges(Some(ISIN_ET.clone()), None, None, SET_RESULTS_MAX)
+            .outbound_vertices(SET_RESULTS_MAX)
+            .inbound_edges(None, None, None, SET_RESULTS_MAX)
+            .outbound_vertices(SET_RESULTS_MAX);
+        let mut tests: Vec<VertInfo> = t.get_vertices(&q)?
+            .iter()
+            .filter(|v| &v.t == &*TEST_VT)
+            .filter_map(|v| {
+                self.id_indx.get_name(&v.id).and_then(|n| Some(VertInfo(n.clone(), v.id)))
+            })
+            .collect();
+
+        tests.sort_by_key(|VertInfo(_, id)| *id);
+        tests.dedup_by_key(|VertInfo(_, id)| *id);
+        Ok(tests)
+    }
+
+    /// The environment a test result ran in, as far as flakiness is concerned
+    fn get_test_result_env<T: Transaction>(&self, t: &T, test_result: Uuid) -> IResult<String> {
+        let props = self.get_test_result_props(t, test_result)?;
+        let mut env: Vec<&str> = props.iter()
+            .filter_map(|VertInfo(name, _)| flaky::env_name(name))
+            .collect();
+
+        env.sort();
+        Ok(env.join(" "))
+    }
+
+    /// Results from a list of vertices, anything else is dropped
+    fn filter_results<T: Transaction>(&self, t: &T, ids: Vec<Uuid>) -> IResult<Vec<Uuid>> {
+        Ok(t.get_vertices(&VertexQuery::Vertices { ids })?
+           .into_iter()
+           .filter(|v| &v.t == &*TEST_RES_VT)
+           .map(|v| v.id)
+           .collect())
+    }
+
+    /// Link a result to a bug and add it to the audit trail
+    fn link_bug<T: Transaction>(&mut self, t: &T, result: Uuid, bug: Uuid,
+                                user: String, auto: Option<AutoLinkRule>) {
+        let key = EdgeKey::new(result, LINK_ET.clone(), bug);
+        let info = LinkInfo { user, time: now(), auto };
+
+        t.create_edge(&key).unwrap();
+        t.set_edge_metadata(&EdgeQuery::Edges { keys: vec![key] },
+                            LINK_MD,
+                            &serde_json::to_value(&info).unwrap()).unwrap();
+
+        let bug = VertInfo(self.id_indx.get_name(&bug).cloned().unwrap_or_default(), bug);
+        self.audit(AuditEntry {
+            time: info.time,
+            user: info.user,
+            action: AuditAction::Link { result, bug, auto: info.auto },
+        });
+    }
+
+    fn audit(&mut self, entry: AuditEntry) {
+        match entry.action {
+            AuditAction::Link { .. } | AuditAction::Unlink { .. } => {
+                hub::publish(Event::BugLink(entry.clone()));
+            },
+            AuditAction::DeleteRun { .. } | AuditAction::Import { .. } => (),
+        }
+        self.audit.push(entry);
+    }
+
+    /// Delete bug links and add them to the audit trail
+    fn unlink_bugs<T: Transaction>(&mut self, t: &T, links: Vec<EdgeKey>, user: &str)
+                                   -> IResult<usize> {
+        let links: Vec<EdgeKey> = t.get_edges(&EdgeQuery::Edges { keys: links })?
+            .into_iter()
+            .map(|e| e.key)
+            .collect();
+
+        t.delete_edges(&EdgeQuery::Edges { keys: links.clone() })?;
+
+        let time = now();
+        for link in links.iter() {
+            let name = self.id_indx.get_name(&link.inbound_id).cloned().unwrap_or_default();
+            let bug = VertInfo(name, link.inbound_id);
+            self.audit(AuditEntry {
+                time,
+                user: user.to_string(),
+                action: AuditAction::Unlink { result: link.outbound_id, bug },
+            });
+        }
+
+        Ok(links.len())
+    }
+
+    /// Copy the bug links, made by hand, of earlier failures of the same test
+    /// which have the same log signature and a similar environment.
+    fn propagate_links<T: Transaction>(&mut self, t: &T, test: Uuid, result: Uuid,
+                                       signature: &str) -> IResult<()> {
+        let earlier: Vec<Uuid> = self.get_test_results(t, test)?
+            .into_iter()
+            .filter(|&(id, passed)| !passed && id != result)
+            .map(|(id, _)| id)
+            .collect();
+
+        if earlier.len() < 1 {
+            return Ok(());
+        }
+
+        let matching: Vec<Uuid> = t.get_vertex_metadata(&VertexQuery::Vertices { ids: earlier },
+                                                        SIGNATURE_MD)?
+            .into_iter()
+            .filter(|md| md.value.as_str() == Some(signature))
+            .map(|md| md.id)
+            .collect();
+
+        if matching.len() < 1 {
+            return Ok(());
+        }
+
+        let links = (VertexQuery::Vertices { ids: matching })
+            .outbound_edges(Some(LINK_ET.clone()), None, None, LINKS_MAX);
+        let props = self.get_test_result_props(t, result)?;
+        let env = autolink::env_features(&props);
+
+        // The most similar source for each bug
+        let mut rules = HashMap::<Uuid, AutoLinkRule>::new();
+        for md in t.get_edge_metadata(&links, LINK_MD)?.into_iter() {
+            let manual = serde_json::from_value::<LinkInfo>(md.value)
+                .map(|info| info.auto.is_none())
+                .unwrap_or(false);
+
+            if !manual {
+                continue;
+            }
+
+            let source = md.key.outbound_id;
+            let source_props = self.get_test_result_props(t, source)?;
+            let confidence = autolink::confidence(&env, &autolink::env_features(&source_props));
+
+            if confidence < autolink::THRESHOLD ||
+                rules.get(&md.key.inbound_id).map_or(false, |r| r.confidence >= confidence)
+            {
+                continue;
+            }
+
+            rules.insert(md.key.inbound_id, AutoLinkRule {
+                rule: autolink::SAME_SIGNATURE.to_string(),
+                confidence,
+                source,
+            });
+        }
+
+        for (bug, rule) in rules.into_iter() {
+            info!("Automatically linking {} to {} with confidence {:.2}",
+                  result, bug, rule.confidence);
+            self.link_bug(t, result, bug, autolink::AUTO_USER.to_string(), Some(rule));
+        }
+
+        Ok(())
+    }
+
+    fn vert(&self, v: Vertex) -> Vert {
+        Vert {
+            name: self.id_indx.get_name(&v.id).cloned(),
+            id: v.id,
+            t: v.t,
+        }
+    }
+
+    /// The sets which the vertices are in, and the sets those are in...
+    fn get_enclosing_sets<T: Transaction>(&self, t: &T, ids: Vec<Uuid>)
+                                          -> IResult<HashSet<Uuid>> {
+        let mut sets = HashSet::new();
+        let mut ids = ids;
+        let mut depth = 0;
+
+        while ids.len() > 0 && depth < 10 {
+            let q = (VertexQuery::Vertices { ids })
+                .outbound_edges(Some(ISIN_ET.clone()), None, None, 1000)
+                .inbound_vertices(1000);
+            ids = t.get_vertices(&q)?
+                .into_iter()
+                .filter(|v| &v.t == &*SET_VT && sets.insert(v.id))
+                .map(|v| v.id)
+                .collect();
+
+            depth += 1;
+        }
+
+        Ok(sets)
+    }
+
+    fn publish_new_results(&mut self) {
+        for (set, count) in self.new_results.drain() {
+            hub::publish(Event::NewResults { set, count });
+        }
+    }
+
     fn get_outer_sets<T: Transaction>(&self, t: &T, ids: Vec<Uuid>) -> IResult<Vec<Vertex>> {
         let q = VertexQuery::Vertices { ids };
         t.get_vertices(&q.inbound_edges(Some(ISIN_ET.clone()), None, None, 100)
@@ -243,125 +1073,1048 @@ impl Default for Repo {
         Repo {
             indradb: ds,
             id_indx: VertNameIndex::default(),
+            audit: Vec::default(),
+            new_results: HashMap::default(),
+            data: None,
+            lock: None,
+            counts: None,
         }
     }
 }
 
 impl Actor for Repo {
     type Context = Context<Self>;
-}
 
-impl Handler<NewResult> for Repo {
-    type Result = MessageResult<NewResult>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = Duration::from_secs(PUBLISH_INTERVAL_SECS);
 
-    fn handle(&mut self, msg: NewResult, _: &mut Self::Context) -> Self::Result {
-        let t = self.indradb.transaction().unwrap();
-        let test = self.intern_fq_name(&t, &TEST_VT, &msg.test_fqn);
-        let result = new_vert(&t, &TEST_RES_VT);
-        new_edge(&t, &test, msg.status.into(), &result);
+        ctx.run_interval(interval, |act, _| act.publish_new_results());
+    }
+}
 
-        for name in &msg.properties {
-            let prop = self.intern_fq_name(&t, &SET_VT, &name);
-            new_edge(&t, &result, &ISIN_ET, &prop);
+/// The datastore is kept in memory by the actor, so it survives the restart
+/// and only the index, which may have been half updated, is rebuilt. Writes
+/// are not rolled back, whatever the handler did before it panicked stays.
+impl Supervised for Repo {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        match self.rebuild_index() {
+            Ok(n) => info!("Rebuilt the name index with {} names", n),
+            Err(e) => error!("Could not rebuild the name index: {}", e),
         }
-
-        MessageResult(result)
     }
 }
 
-impl Handler<GetSetVerts> for Repo {
-    type Result = MessageResult<GetSetVerts>;
+impl Handler<NewResult> for Repo {
+    type Result = Guarded<NewResult>;
+
+    fn handle(&mut self, msg: NewResult, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "NewResult", move || {
+            let t = self.indradb.transaction().unwrap();
+            let passed = if let TestStatus::Pass = msg.status { true } else { false };
+            let test = self.intern_fq_name(&t, &TEST_VT, &msg.test_fqn);
+            let result = new_vert(&t, &TEST_RES_VT);
+            new_edge(&t, &test, msg.status.into(), &result);
+            new_edge(&t, &result, &INRUN_ET, &msg.run);
+
+            for name in &msg.properties {
+                let prop = self.intern_fq_name(&t, &SET_VT, &name);
+                new_edge(&t, &result, &ISIN_ET, &prop);
+            }
 
-    fn handle(&mut self, msg: GetSetVerts, _: &mut Self::Context) -> Self::Result {
-        MessageResult(
-            if let Some(vert) = msg.0 {
-                self.indradb.transaction()
-                    .and_then(|t| self.get_adjacent(&t, vert))
-                    .unwrap_or_else(|e| {
-                        error!("Could not get vertices: {}", e);
-                        Vec::default()
-                    })
-            } else {
-                self.id_indx.get_all()
+            match self.get_enclosing_sets(&t, vec![result, test]) {
+                Ok(sets) => for set in sets.into_iter() {
+                    *self.new_results.entry(set).or_insert(0) += 1;
+                },
+                Err(e) => error!("Failed to find the sets of {}: {}", result, e),
             }
-        )
-    }
-}
 
-impl Handler<Search> for Repo {
-    type Result = MessageResult<Search>;
+            if let Some(log) = msg.log {
+                let q = VertexQuery::Vertices { ids: vec![result] };
+
+                if !passed {
+                    let signature = cluster::log_signature(&log);
+
+                    t.set_vertex_metadata(&q, SIGNATURE_MD, &JsonValue::from(signature.clone()))
+                        .unwrap();
+                    if let Err(e) = self.propagate_links(&t, test, result, &signature) {
+                        error!("Failed to propagate bug links to {}: {}", result, e);
+                    }
+                }
+
+                t.set_vertex_metadata(&q, LOG_MD, &JsonValue::from(log)).unwrap();
+            }
 
-    fn handle(&mut self, msg: Search, _: &mut Self::Context) -> Self::Result {
-        MessageResult(self.id_indx.search(msg.0))
+            MessageResult(result)
+        })
     }
 }
 
-impl Handler<GetResultMatrix> for Repo {
-    type Result = MessageResult<GetResultMatrix>;
+impl Handler<NewRun> for Repo {
+    type Result = Guarded<NewRun>;
 
-    fn handle(&mut self, msg: GetResultMatrix, _ctx: &mut Self::Context) -> Self::Result {
-        let t = self.indradb.transaction().unwrap();
-        
-        let tests = self.get_inner_tests(&t, vec![msg.0]).unwrap_or_else(|e| {
-            error!("Failed to get inner tests: {}", e);
-            Vec::default()
-        });
+    fn handle(&mut self, msg: NewRun, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "NewRun", move || {
+            let t = self.indradb.transaction().unwrap();
+            let run = self.intern_name(&t, &RUN_VT, &msg.name);
 
-        if tests.len() < 1 {
-            debug!("No inner tests returned for {}", msg.0);
-        }
+            let q = VertexQuery::Vertices { ids: vec![run] };
+            t.set_vertex_metadata(&q, TIMESTAMP_MD, &JsonValue::from(msg.timestamp)).unwrap();
+            t.set_vertex_metadata(&q, IMPORTER_MD, &JsonValue::from(msg.user)).unwrap();
 
-        // Results keyed by build/product/test property
-        let mut results = HashMap::<Uuid, (VertInfo, Vec<ResultInMatrix>)>::new();
+            MessageResult(run)
+        })
+    }
+}
 
-        for (i, VertInfo(test_name, test)) in tests.iter().enumerate() {
-            let results2 = self.get_test_results(&t, *test).unwrap_or_else(|e| {
-                error!("Failed test results: {}", e);
+impl Handler<GetSetVerts> for Repo {
+    type Result = Guarded<GetSetVerts>;
+
+    fn handle(&mut self, msg: GetSetVerts, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetSetVerts", move || {
+            MessageResult(
+                if let Some(vert) = msg.0 {
+                    self.indradb.transaction()
+                        .and_then(|t| self.get_adjacent(&t, vert))
+                        .unwrap_or_else(|e| {
+                            error!("Could not get vertices: {}", e);
+                            Vec::default()
+                        })
+                } else {
+                    self.id_indx.get_all()
+                }
+            )
+        })
+    }
+}
+
+impl Handler<Search> for Repo {
+    type Result = Guarded<Search>;
+
+    fn handle(&mut self, msg: Search, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "Search", move || {
+            MessageResult(self.id_indx.search(msg.0))
+        })
+    }
+}
+
+impl Handler<GetResultMatrix> for Repo {
+    type Result = Guarded<GetResultMatrix>;
+
+    fn handle(&mut self, msg: GetResultMatrix, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetResultMatrix", move || {
+            let t = self.indradb.transaction().unwrap();
+            
+            let tests = self.get_inner_tests(&t, vec![msg.0]).unwrap_or_else(|e| {
+                error!("Failed to get inner tests: {}", e);
                 Vec::default()
             });
 
-            if results2.len() < 1 {
-                debug!("No test-results found for {} ({})", test_name, test);
+            if tests.len() < 1 {
+                debug!("No inner tests returned for {}", msg.0);
             }
 
-            let mut results3 = HashMap::<Uuid, (VertInfo, u32, u32)>::new();
-            for (result2, status) in results2.into_iter() {
-                let props = self.get_test_result_props(&t, result2).unwrap_or_else(|e| {
-                    error!("Failed to get test result properties/sets: {}", e);
+            // Results keyed by build/product/test property
+            let mut results = HashMap::<Uuid, (VertInfo, Vec<ResultInMatrix>)>::new();
+
+            for (i, test) in tests.iter().enumerate() {
+                for (prop, cell) in self.group_test_results(&t, test).into_iter() {
+                    let flaky = cell.flaky();
+                    let MatrixCell { vinfo, passes, fails, .. } = cell;
+                    let result = results.entry(prop).or_insert_with(|| {
+                        (vinfo, Vec::new())
+                    });
+
+                    result.1.push(ResultInMatrix {
+                        test_case: i as u32,
+                        passes,
+                        fails,
+                        flaky,
+                    });
+                }
+            }
+            
+            MessageResult(ResultMatrix {
+                test_cases: tests,
+                results: results.into_iter().map(|(_, v)| (v.0, v.1)).collect(),
+            })
+        })
+    }
+}
+
+impl Handler<GetFlakyTests> for Repo {
+    type Result = Guarded<GetFlakyTests>;
+
+    fn handle(&mut self, msg: GetFlakyTests, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetFlakyTests", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let tests = self.get_inner_tests(&t, vec![msg.0]).unwrap_or_else(|e| {
+                error!("Failed to get inner tests: {}", e);
+                Vec::default()
+            });
+
+            let mut flaky_tests: Vec<FlakyTest> = tests.into_iter().filter_map(|test| {
+                let results = self.get_test_results(&t, test.1).unwrap_or_else(|e| {
+                    error!("Failed test results: {}", e);
                     Vec::default()
                 });
+                let results = self.sort_results(&t, results);
+
+                let results = results.into_iter().map(|(result, status, run)| {
+                    let env = self.get_test_result_env(&t, result).unwrap_or_else(|e| {
+                        error!("Failed to get test result environment: {}", e);
+                        String::new()
+                    });
+                    (env, run, status)
+                }).collect();
 
-                if props.len() < 1 {
-                    debug!("No test-properties found for {}'s test-result: {}",
-                           test_name, result2);
+                flaky::score_test(test, results)
+            }).collect();
+
+            flaky_tests.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+            flaky_tests.truncate(flaky::WORST);
+
+            MessageResult(flaky_tests)
+        })
+    }
+}
+
+impl Handler<GetRevisionDiff> for Repo {
+    type Result = Guarded<GetRevisionDiff>;
+
+    fn handle(&mut self, msg: GetRevisionDiff, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetRevisionDiff", move || {
+            let t = self.indradb.transaction().unwrap();
+            let mut diff = RevisionDiff::default();
+
+            // Tests which are new in `to` can be failing too
+            let mut tests = Vec::new();
+            for set in &[msg.from, msg.to] {
+                tests.extend(self.get_set_tests(&t, *set).unwrap_or_else(|e| {
+                    error!("Failed to get tests in set: {}", e);
+                    Vec::default()
+                }));
+            }
+            tests.sort_by_key(|VertInfo(_, id)| *id);
+            tests.dedup_by_key(|VertInfo(_, id)| *id);
+
+            if tests.len() < 1 {
+                debug!("No tests have results in {} or {}", msg.from, msg.to);
+            }
+
+            for test in tests.into_iter() {
+                let (before, after) = {
+                    let cells = self.group_test_results(&t, &test);
+                    let passing = |set: &Uuid| cells.get(set).map(|c| c.fails == 0);
+
+                    (passing(&msg.from), passing(&msg.to))
+                };
+
+                match (before, after) {
+                    (Some(true), Some(false)) | (None, Some(false)) => {
+                        diff.newly_failing.push(test)
+                    },
+                    (Some(false), Some(true)) => diff.newly_passing.push(test),
+                    (Some(false), Some(false)) => diff.still_failing.push(test),
+                    (Some(_), None) => diff.missing.push(test),
+                    _ => (),
                 }
+            }
+
+            MessageResult(diff)
+        })
+    }
+}
+
+impl Handler<GetSetReport> for Repo {
+    type Result = Guarded<GetSetReport>;
+
+    fn handle(&mut self, msg: GetSetReport, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetSetReport", move || {
+            let t = self.indradb.transaction().unwrap();
+            let tests = self.get_set_tests(&t, msg.0).unwrap_or_else(|e| {
+                error!("Failed to get tests in set: {}", e);
+                Vec::default()
+            });
+
+            let report = tests.into_iter()
+                .filter_map(|test| {
+                    let mut cells = self.group_test_results(&t, &test);
+
+                    cells.remove(&msg.0).map(|cell| TestOutcome {
+                        test,
+                        passes: cell.passes,
+                        fails: cell.fails,
+                        flaky: cell.flaky(),
+                    })
+                })
+                .collect();
+
+            MessageResult(report)
+        })
+    }
+}
+
+impl Handler<GetVertId> for Repo {
+    type Result = Guarded<GetVertId>;
+
+    fn handle(&mut self, msg: GetVertId, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetVertId", move || {
+            MessageResult(self.id_indx.get_vert(&msg.0).cloned())
+        })
+    }
+}
+
+impl Handler<GetTestHistory> for Repo {
+    type Result = Guarded<GetTestHistory>;
 
-                for prop in props.into_iter() {
-                    let result3 = results3.entry(prop.1).or_insert((prop, 0, 0));
-                    if status {
-                        result3.1 += 1;
-                    } else {
-                        result3.2 += 1;
+    fn handle(&mut self, msg: GetTestHistory, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetTestHistory", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let results = self.get_test_results(&t, msg.0).unwrap_or_else(|e| {
+                error!("Failed test results: {}", e);
+                Vec::default()
+            });
+
+            let sorted = self.sort_results(&t, results);
+            let history = sorted.into_iter().filter_map(|(result, passed, _)| {
+                let run = self.get_result_run(&t, result).unwrap_or_else(|e| {
+                    error!("Failed to get test result's run: {}", e);
+                    None
+                });
+                let (run, timestamp) = match run {
+                    Some(run) => run,
+                    None => {
+                        debug!("Test result {} is not part of a run", result);
+                        return None;
                     }
+                };
+                let environment = self.get_test_result_props(&t, result).unwrap_or_else(|e| {
+                    error!("Failed to get test result properties/sets: {}", e);
+                    Vec::default()
+                });
+
+                Some(HistoryEntry {
+                    run: VertInfo(self.id_indx.get_name(&run).cloned().unwrap_or_default(), run),
+                    timestamp,
+                    passed,
+                    environment: environment.into_iter()
+                        .filter(|VertInfo(name, _)| name.starts_with("environment:"))
+                        .collect(),
+                })
+            }).collect();
+
+            MessageResult(history)
+        })
+    }
+}
+
+impl Handler<GetFailureClusters> for Repo {
+    type Result = Guarded<GetFailureClusters>;
+
+    fn handle(&mut self, msg: GetFailureClusters, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetFailureClusters", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let tests = self.get_inner_tests(&t, vec![msg.0]).unwrap_or_else(|e| {
+                error!("Failed to get inner tests: {}", e);
+                Vec::default()
+            });
+
+            let mut failures = Vec::new();
+            for test in tests.into_iter() {
+                let failed: Vec<Uuid> = self.get_test_results(&t, test.1).unwrap_or_else(|e| {
+                    error!("Failed test results: {}", e);
+                    Vec::default()
+                }).into_iter().filter(|(_, passed)| !passed).map(|(result, _)| result).collect();
+
+                if failed.len() < 1 {
+                    continue;
                 }
+
+                let logs = t.get_vertex_metadata(&VertexQuery::Vertices { ids: failed }, LOG_MD)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to get test result logs: {}", e);
+                        Vec::default()
+                    });
+
+                failures.extend(logs.into_iter().filter_map(|md| {
+                    md.value.as_str().map(|log| Failure {
+                        test: test.clone(),
+                        result: md.id,
+                        log: log.to_owned(),
+                    })
+                }));
+            }
+
+            MessageResult(cluster::cluster(failures))
+        })
+    }
+}
+
+impl Handler<LinkBug> for Repo {
+    type Result = Guarded<LinkBug>;
+
+    fn handle(&mut self, msg: LinkBug, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "LinkBug", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let results = self.filter_results(&t, msg.results).unwrap_or_else(|e| {
+                error!("Failed to get test results: {}", e);
+                Vec::default()
+            });
+
+            if results.len() < 1 {
+                return MessageResult(0);
+            }
+
+            // Bug IDs are often URLs, so they are not split into sets
+            let bug = self.intern_name(&t, &BUG_VT, &bug_name(&msg.bug));
+            for result in results.iter() {
+                self.link_bug(&t, *result, bug, msg.user.clone(), None);
             }
 
-            for (prop, (vinfo, passes, fails)) in results3.into_iter() {
-                let result = results.entry(prop).or_insert_with(|| {
-                    (vinfo, Vec::new())
+            MessageResult(results.len())
+        })
+    }
+}
+
+impl Handler<UnlinkBug> for Repo {
+    type Result = Guarded<UnlinkBug>;
+
+    fn handle(&mut self, msg: UnlinkBug, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "UnlinkBug", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let bug = match self.id_indx.get_vert(&bug_name(&msg.bug)) {
+                Some(bug) => *bug,
+                None => return MessageResult(0),
+            };
+            let links = msg.results.iter()
+                .map(|result| EdgeKey::new(*result, LINK_ET.clone(), bug))
+                .collect();
+
+            MessageResult(self.unlink_bugs(&t, links, &msg.user).unwrap_or_else(|e| {
+                error!("Failed to unlink bug {}: {}", msg.bug, e);
+                0
+            }))
+        })
+    }
+}
+
+impl Handler<RevertAutoLinks> for Repo {
+    type Result = Guarded<RevertAutoLinks>;
+
+    fn handle(&mut self, msg: RevertAutoLinks, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "RevertAutoLinks", move || {
+            let t = self.indradb.transaction().unwrap();
+
+            let q = (VertexQuery::Vertices { ids: self.id_indx.with_prefix("bug:") })
+                .inbound_edges(Some(LINK_ET.clone()), None, None, LINKS_MAX);
+            let links = t.get_edge_metadata(&q, LINK_MD).unwrap_or_else(|e| {
+                error!("Failed to get bug links: {}", e);
+                Vec::default()
+            });
+
+            let rule = msg.rule;
+            let auto = links.into_iter().filter(|md| {
+                serde_json::from_value::<LinkInfo>(md.value.clone()).ok()
+                    .and_then(|info| info.auto)
+                    .map_or(false, |auto| rule.as_ref().map_or(true, |r| *r == auto.rule))
+            }).map(|md| md.key).collect();
+
+            MessageResult(self.unlink_bugs(&t, auto, &msg.user).unwrap_or_else(|e| {
+                error!("Failed to revert automatic bug links: {}", e);
+                0
+            }))
+        })
+    }
+}
+
+impl Handler<GetAuditTrail> for Repo {
+    type Result = Guarded<GetAuditTrail>;
+
+    fn handle(&mut self, _msg: GetAuditTrail, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetAuditTrail", move || {
+            let skip = self.audit.len().saturating_sub(AUDIT_TAIL);
+
+            MessageResult(self.audit[skip ..].to_vec())
+        })
+    }
+}
+
+impl Handler<GetVerts> for Repo {
+    type Result = Guarded<GetVerts>;
+
+    fn handle(&mut self, msg: GetVerts, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetVerts", move || {
+            let verts = self.indradb.transaction()
+                .and_then(|t| t.get_vertices(&VertexQuery::Vertices { ids: msg.0 }))
+                .unwrap_or_else(|e| {
+                    error!("Could not get vertices: {}", e);
+                    Vec::default()
+                });
+
+            MessageResult(verts.into_iter().map(|v| self.vert(v)).collect())
+        })
+    }
+}
+
+impl Handler<GetNeighbours> for Repo {
+    type Result = Guarded<GetNeighbours>;
+
+    fn handle(&mut self, msg: GetNeighbours, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetNeighbours", move || {
+            let q = VertexQuery::Vertices { ids: vec![msg.id] };
+            let q = if msg.outbound {
+                q.outbound_edges(Some(msg.edge), None, None, msg.limit).inbound_vertices(msg.limit)
+            } else {
+                q.inbound_edges(Some(msg.edge), None, None, msg.limit).outbound_vertices(msg.limit)
+            };
+            let verts = self.indradb.transaction()
+                .and_then(|t| t.get_vertices(&q))
+                .unwrap_or_else(|e| {
+                    error!("Could not get neighbouring vertices: {}", e);
+                    Vec::default()
                 });
 
-                result.1.push(ResultInMatrix {
-                    test_case: i as u32,
-                    passes,
-                    fails,
+            MessageResult(verts.into_iter().map(|v| self.vert(v)).collect())
+        })
+    }
+}
+
+impl Handler<GetMetadata> for Repo {
+    type Result = Guarded<GetMetadata>;
+
+    fn handle(&mut self, msg: GetMetadata, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetMetadata", move || {
+            let q = VertexQuery::Vertices { ids: vec![msg.id] };
+            let md = self.indradb.transaction()
+                .and_then(|t| t.get_vertex_metadata(&q, msg.name))
+                .unwrap_or_else(|e| {
+                    error!("Could not get {} metadata: {}", msg.name, e);
+                    Vec::default()
                 });
+
+            MessageResult(md.into_iter().next().map(|md| md.value))
+        })
+    }
+}
+
+impl Handler<DeleteRun> for Repo {
+    type Result = Guarded<DeleteRun>;
+
+    fn handle(&mut self, msg: DeleteRun, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "DeleteRun", move || {
+            let t = self.indradb.transaction().unwrap();
+            let run_q = VertexQuery::Vertices { ids: vec![msg.run] };
+
+            let is_run = t.get_vertices(&run_q).unwrap_or_else(|e| {
+                error!("Could not get run {}: {}", msg.run, e);
+                Vec::default()
+            }).iter().any(|v| &v.t == &*RUN_VT);
+            if !is_run {
+                return MessageResult(None);
             }
-        }
-        
-        MessageResult(ResultMatrix {
-            test_cases: tests,
-            results: results.into_iter().map(|(_, v)| (v.0, v.1)).collect(),
+
+            let results_q = run_q.clone()
+                .inbound_edges(Some(INRUN_ET.clone()), None, None, SET_RESULTS_MAX)
+                .outbound_vertices(SET_RESULTS_MAX);
+            let results = t.get_vertices(&results_q)
+                .and_then(|results| {
+                    t.delete_vertices(&results_q)?;
+                    t.delete_vertices(&run_q)?;
+                    Ok(results.len() as u32)
+                });
+            let results = match results {
+                Ok(results) => results,
+                Err(e) => {
+                    error!("Could not delete run {}: {}", msg.run, e);
+                    return MessageResult(None);
+                },
+            };
+
+            let name = self.id_indx.get_name(&msg.run).cloned().unwrap_or_default();
+            let run = VertInfo(name, msg.run);
+            self.id_indx.remove(&msg.run);
+            self.audit(AuditEntry {
+                time: now(),
+                user: msg.user,
+                action: AuditAction::DeleteRun { run, results },
+            });
+
+            MessageResult(Some(results))
+        })
+    }
+}
+
+impl Handler<GetCounts> for Repo {
+    type Result = Guarded<GetCounts>;
+
+    fn handle(&mut self, _msg: GetCounts, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetCounts", move || {
+            let max_age = Duration::from_secs(COUNTS_MAX_AGE_SECS);
+
+            let cached = self.counts.as_ref()
+                .filter(|(made, _)| made.elapsed() < max_age)
+                .map(|(_, counts)| counts.clone());
+            if let Some(counts) = cached {
+                return MessageResult(counts);
+            }
+
+            let mut counts = GraphCounts::default();
+            let all = VertexQuery::All { start_id: None, limit: u32::max_value() };
+            let res = self.indradb.transaction().and_then(|t| {
+                for v in t.get_vertices(&all)? {
+                    *counts.vertices.entry(v.t.0).or_insert(0) += 1;
+                }
+                for e in t.get_edges(&all.outbound_edges(None, None, None, u32::max_value()))? {
+                    *counts.edges.entry(e.key.t.0).or_insert(0) += 1;
+                }
+                Ok(())
+            });
+
+            match res {
+                Ok(()) => self.counts = Some((Instant::now(), counts.clone())),
+                Err(e) => error!("Could not count vertices and edges: {}", e),
+            }
+            MessageResult(counts)
+        })
+    }
+}
+
+impl Handler<GetGraph> for Repo {
+    type Result = Guarded<GetGraph>;
+
+    fn handle(&mut self, msg: GetGraph, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetGraph", move || {
+            // The names come from the index and the logs are most of the data
+            let mds: Vec<&str> = VERTEX_MDS.iter().cloned()
+                .filter(|&md| md != NAME_MD && (msg.logs || md != LOG_MD))
+                .collect();
+            let index = &self.id_indx;
+
+            MessageResult(self.partial_snapshot(msg.root.map(|id| (id, msg.depth)), &mds)
+                          .map(|snap| Graph::new(snap, |id| index.get_name(id).cloned()))
+                          .map_err(|e| e.to_string()))
+        })
+    }
+}
+
+impl Handler<Backup> for Repo {
+    type Result = Guarded<Backup>;
+
+    fn handle(&mut self, msg: Backup, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "Backup", move || {
+            let res = self.backup_file(&msg.0)
+                .map_err(|e| format!("{}: {}", msg.0.display(), e));
+
+            MessageResult(res)
+        })
+    }
+}
+
+impl Handler<Restore> for Repo {
+    type Result = Guarded<Restore>;
+
+    fn handle(&mut self, msg: Restore, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "Restore", move || {
+            // The backup goes into a new repository, so a bad one changes nothing
+            let mut repo = Repo::default();
+            let records = match File::open(&msg.0)
+                .and_then(|file| repo.read_backup(BufReader::new(file)))
+            {
+                Ok(records) => records,
+                Err(e) => return MessageResult(Err(format!("{}: {}", msg.0.display(), e))),
+            };
+
+            repo.data = self.data.take();
+            repo.lock = self.lock.take();
+            *self = repo;
+
+            info!("Restored {} records from {}", records, msg.0.display());
+            MessageResult(Ok(records))
+        })
+    }
+}
+
+impl Handler<FinishRun> for Repo {
+    type Result = Guarded<FinishRun>;
+
+    fn handle(&mut self, msg: FinishRun, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "FinishRun", move || {
+            let q = VertexQuery::Vertices { ids: vec![msg.run] };
+            let res = self.indradb.transaction()
+                .and_then(|t| t.set_vertex_metadata(&q, COMPLETE_MD, &JsonValue::from(true)));
+
+            if let Err(e) = res {
+                error!("Could not mark run {} as complete: {}", msg.run, e);
+            }
+
+            let name = self.id_indx.get_name(&msg.run).cloned().unwrap_or_default();
+            self.audit(AuditEntry {
+                time: now(),
+                user: msg.user,
+                action: AuditAction::Import { run: VertInfo(name, msg.run), results: msg.results },
+            });
+            MessageResult(())
+        })
+    }
+}
+
+impl Handler<GetRun> for Repo {
+    type Result = Guarded<GetRun>;
+
+    fn handle(&mut self, msg: GetRun, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "GetRun", move || {
+            let run = match self.id_indx.get_vert(&msg.0) {
+                Some(run) => *run,
+                None => return MessageResult(None),
+            };
+            let q = VertexQuery::Vertices { ids: vec![run] };
+            let complete = self.indradb.transaction()
+                .and_then(|t| t.get_vertex_metadata(&q, COMPLETE_MD))
+                .map(|md| md.iter().any(|md| md.value == JsonValue::from(true)))
+                .unwrap_or(false);
+
+            MessageResult(Some((run, complete)))
+        })
+    }
+}
+
+impl Handler<Save> for Repo {
+    type Result = Guarded<Save>;
+
+    fn handle(&mut self, _msg: Save, ctx: &mut Self::Context) -> Self::Result {
+        guard(ctx, "repository", "Save", move || {
+            let dir = match self.data {
+                Some(ref dir) => dir.clone(),
+                None => return MessageResult(Ok(())),
+            };
+            let res = self.snapshot()
+                .map_err(|e| e.to_string())
+                .and_then(|snap| store::write(&dir, &snap).map_err(|e| e.to_string()));
+
+            if res.is_ok() {
+                info!("Saved a snapshot to {}", store::snapshot_path(&dir).display());
+            }
+            MessageResult(res)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use actix::SystemRunner;
+
+    use super::*;
+
+    /// A repository actor and the system it runs in
+    struct TestRepo {
+        sys: SystemRunner,
+        repo: Addr<Repo>,
+    }
+
+    impl TestRepo {
+        fn new() -> TestRepo {
+            let sys = System::new("test");
+            let repo = Repo::default().start();
+
+            TestRepo { sys, repo }
+        }
+
+        fn ask<M>(&mut self, msg: M) -> M::Result
+        where
+            Repo: Handler<M>,
+            M: Message + Send + 'static,
+            M::Result: Send,
+        {
+            self.sys.block_on(self.repo.send(msg)).unwrap()
+        }
+
+        /// Import a run of (test, passed, properties, log) results and return
+        /// the run and its results
+        fn import(&mut self, run: &str, timestamp: u64,
+                  results: &[(&str, bool, &[&str], Option<&str>)]) -> (Uuid, Vec<Uuid>) {
+            let run = self.ask(NewRun {
+                name: run.to_string(),
+                timestamp,
+                user: "tester".to_string(),
+            });
+            let ids = results.iter().map(|&(test, passed, props, log)| self.ask(NewResult {
+                test_fqn: test.to_string(),
+                status: if passed { TestStatus::Pass } else { TestStatus::Fail },
+                run,
+                properties: props.iter().map(|p| p.to_string()).collect(),
+                log: log.map(|l| l.to_string()),
+            })).collect::<Vec<_>>();
+
+            self.ask(FinishRun { run, results: ids.len() as u32, user: "tester".to_string() });
+            (run, ids)
+        }
+
+        fn vert(&mut self, name: &str) -> Uuid {
+            self.ask(GetVertId(name.to_string())).unwrap()
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("bug-graph-{}-{}", name, process::id()))
+    }
+
+    /// Run name, timestamp and whether it passed, for each of a test's results
+    fn history(repo: &mut TestRepo, test: &str) -> Vec<(String, u64, bool)> {
+        let test = repo.vert(test);
+
+        repo.ask(GetTestHistory(test)).into_iter()
+            .map(|h| (h.run.0, h.timestamp, h.passed))
+            .collect()
+    }
+
+    #[test]
+    fn restores_what_was_backed_up() {
+        let path = temp_path("backup");
+        let mut repo = TestRepo::new();
+
+        let (_, results) = repo.import("run:1", 100, &[
+            ("test:a", true, &["environment:x"], None),
+            ("test:b", false, &["environment:x"], Some("Oops at line 3")),
+        ]);
+        repo.import("run:2", 200, &[("test:a", false, &["environment:y"], Some("Crashed"))]);
+        repo.ask(LinkBug {
+            bug: "bug:1".to_string(),
+            results: vec![results[1]],
+            user: "tester".to_string(),
+        });
+
+        let written = repo.ask(Backup(path.clone())).unwrap();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+
+        let mut restored = TestRepo::new();
+        assert_eq!(restored.ask(Restore(path.clone())).unwrap(), written);
+        fs::remove_file(&path).unwrap();
+
+        let (before, after) = (repo.ask(GetCounts), restored.ask(GetCounts));
+        assert_eq!(before.vertices, after.vertices);
+        assert_eq!(before.edges, after.edges);
+        for name in &["test:a", "test:b", "run:1", "run:2", "bug:1", "environment:x"] {
+            assert_eq!(repo.vert(name), restored.vert(name));
+        }
+        assert_eq!(history(&mut repo, "test:a"), history(&mut restored, "test:a"));
+        assert_eq!(history(&mut repo, "test:b"), history(&mut restored, "test:b"));
+        assert_eq!(repo.ask(GetAuditTrail).len(), restored.ask(GetAuditTrail).len());
+    }
+
+    #[test]
+    fn truncated_backups_change_nothing() {
+        let path = temp_path("truncated");
+        let mut repo = TestRepo::new();
+
+        repo.import("run:1", 100, &[("test:a", true, &[], None), ("test:b", true, &[], None)]);
+        repo.ask(Backup(path.clone())).unwrap();
+
+        let backup = fs::read(&path).unwrap();
+        let end = backup[..backup.len() - 1].iter().rposition(|&c| c == b'\n').unwrap() + 1;
+        fs::write(&path, &backup[..end]).unwrap();
+
+        let mut other = TestRepo::new();
+        other.import("run:9", 900, &[("test:z", true, &[], None)]);
+        assert!(other.ask(Restore(path.clone())).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert!(other.ask(GetVertId("test:z".to_string())).is_some());
+        assert!(other.ask(GetVertId("test:a".to_string())).is_none());
+    }
+
+    /// The automatic links made so far as (result, bug, source, confidence)
+    fn auto_links(repo: &mut TestRepo) -> Vec<(Uuid, String, Uuid, f32)> {
+        repo.ask(GetAuditTrail).into_iter().filter_map(|entry| match entry.action {
+            AuditAction::Link { result, bug, auto: Some(rule) } => {
+                assert_eq!(rule.rule, autolink::SAME_SIGNATURE);
+                Some((result, bug.0, rule.source, rule.confidence))
+            },
+            _ => None,
+        }).collect()
+    }
+
+    fn unlinked(repo: &mut TestRepo) -> Vec<Uuid> {
+        repo.ask(GetAuditTrail).into_iter().filter_map(|entry| match entry.action {
+            AuditAction::Unlink { result, .. } => Some(result),
+            _ => None,
+        }).collect()
+    }
+
+    fn fail(repo: &mut TestRepo, run: &str, timestamp: u64, env: &[&str], log: &str) -> Uuid {
+        repo.import(run, timestamp, &[("test:a", false, env, Some(log))]).1[0]
+    }
+
+    fn link(repo: &mut TestRepo, bug: &str, result: Uuid) {
+        repo.ask(LinkBug {
+            bug: bug.to_string(),
+            results: vec![result],
+            user: "tester".to_string(),
+        });
+    }
+
+    const X86_SLE15: &[&str] = &["environment:arch:x86_64", "environment:product:sle:15"];
+    const X86: &[&str] = &["environment:arch:x86_64"];
+    const S390_SLE12: &[&str] = &["environment:arch:s390x", "environment:product:sle:12"];
+
+    #[test]
+    fn copies_manual_links_to_similar_failures() {
+        let mut repo = TestRepo::new();
+
+        let manual = fail(&mut repo, "run:1", 100, X86_SLE15, "Oops at line 3");
+        link(&mut repo, "bug:1", manual);
+
+        // Half the environment is the same, which is just enough
+        let half = fail(&mut repo, "run:2", 200, X86, "Oops at line 3");
+        // A third of it is not
+        fail(&mut repo, "run:3", 300, S390_SLE12, "Oops at line 3");
+        // Nor is a different log
+        fail(&mut repo, "run:4", 400, X86, "Timed out");
+        // This matches the automatic link better, but only manual ones are copied
+        let copy = fail(&mut repo, "run:5", 500, X86, "Oops at line 3");
+
+        let links = auto_links(&mut repo);
+        assert_eq!(links.len(), 2);
+        for (link, &expected) in links.into_iter().zip(&[half, copy]) {
+            let (result, bug, source, confidence) = link;
+
+            assert_eq!(result, expected);
+            assert_eq!(bug, "bug:1");
+            assert_eq!(source, manual);
+            assert!((confidence - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn reverts_only_automatic_links() {
+        let mut repo = TestRepo::new();
+        let revert = |repo: &mut TestRepo, rule: Option<&str>| repo.ask(RevertAutoLinks {
+            rule: rule.map(|r| r.to_string()),
+            user: "tester".to_string(),
+        });
+
+        let manual = fail(&mut repo, "run:1", 100, X86, "Oops at line 3");
+        link(&mut repo, "bug:1", manual);
+        let auto = vec![fail(&mut repo, "run:2", 200, X86, "Oops at line 3"),
+                        fail(&mut repo, "run:3", 300, X86, "Oops at line 3")];
+        assert_eq!(auto_links(&mut repo).len(), 2);
+
+        assert_eq!(revert(&mut repo, Some("no-such-rule")), 0);
+        assert_eq!(revert(&mut repo, Some(autolink::SAME_SIGNATURE)), 2);
+        let mut reverted = unlinked(&mut repo);
+        reverted.sort();
+        let mut expected = auto.clone();
+        expected.sort();
+        assert_eq!(reverted, expected);
+        assert_eq!(revert(&mut repo, Some(autolink::SAME_SIGNATURE)), 0);
+
+        let again = fail(&mut repo, "run:4", 400, X86, "Oops at line 3");
+        assert_eq!(revert(&mut repo, None), 1);
+        assert_eq!(unlinked(&mut repo).last(), Some(&again));
+        assert!(!unlinked(&mut repo).contains(&manual));
+    }
+
+    fn names(tests: &[VertInfo]) -> Vec<&str> {
+        let mut names: Vec<&str> = tests.iter().map(|VertInfo(name, _)| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn diffs_revisions() {
+        let mut repo = TestRepo::new();
+        let (old, new) = (&["environment:product:sle:1"][..], &["environment:product:sle:2"][..]);
+
+        repo.import("run:1", 100, &[("test:breaks", true, old, None),
+                                    ("test:fixed", false, old, None),
+                                    ("test:broken", false, old, None),
+                                    ("test:gone", true, old, None),
+                                    ("test:fine", true, old, None)]);
+        repo.import("run:2", 200, &[("test:breaks", false, new, None),
+                                    ("test:fixed", true, new, None),
+                                    ("test:broken", false, new, None),
+                                    ("test:fine", true, new, None),
+                                    ("test:new-failure", false, new, None),
+                                    ("test:new-pass", true, new, None)]);
+
+        let (from, to) = (repo.vert("environment:product:sle:1"),
+                          repo.vert("environment:product:sle:2"));
+        let diff = repo.ask(GetRevisionDiff { from, to });
+
+        assert_eq!(names(&diff.newly_failing), vec!["test:breaks", "test:new-failure"]);
+        assert_eq!(names(&diff.newly_passing), vec!["test:fixed"]);
+        assert_eq!(names(&diff.still_failing), vec!["test:broken"]);
+        assert_eq!(names(&diff.missing), vec!["test:gone"]);
+    }
+
+    #[test]
+    fn history_has_every_result() {
+        let mut repo = TestRepo::new();
+        let many: Vec<(&str, bool, &[&str], Option<&str>)> =
+            (0 .. 600).map(|_| ("test:a", true, &[][..], None)).collect();
+
+        repo.import("run:2", 200, &many);
+        // Created last, but run first
+        repo.import("run:1", 100, &[("test:a", false, &[], None)]);
+        repo.import("run:3", 300, &[("test:a", true, &[], None)]);
+
+        let history = history(&mut repo, "test:a");
+        assert_eq!(history.len(), 602);
+        assert_eq!(history[0], ("run:1".to_string(), 100, false));
+        assert_eq!(history[601], ("run:3".to_string(), 300, true));
+    }
+
+    #[test]
+    fn flakiness_is_judged_on_runs() {
+        let mut repo = TestRepo::new();
+        let env = &["environment:arch:x86_64"][..];
+
+        // It flips in the first ten runs, then passes twice in each run
+        for i in 0 .. flaky::WINDOW as u64 / 2 {
+            repo.import(&format!("run:{}", i), i, &[("test:a", i % 2 == 1, env, None)]);
+        }
+        for i in flaky::WINDOW as u64 / 2 .. flaky::WINDOW as u64 {
+            repo.import(&format!("run:{}", i), i, &[("test:a", true, env, None),
+                                                    ("test:a", true, env, None)]);
+        }
+
+        let set = repo.vert("test");
+        let flaky = repo.ask(GetFlakyTests(set));
+        assert_eq!(flaky.len(), 1);
+        assert_eq!(flaky[0].test.0, "test:a");
+        assert_eq!(flaky[0].envs[0].runs as usize, flaky::WINDOW / 2 * 3);
+    }
+
+    #[test]
+    fn history_is_in_run_order() {
+        let mut repo = TestRepo::new();
+        let props = &["environment:arch:x86_64", "kernel:4.12"][..];
+
+        // Imported in a different order to the one they ran in
+        repo.import("run:b", 200, &[("test:a", false, props, None),
+                                    ("test:a", true, props, None)]);
+        repo.import("run:c", 300, &[("test:a", true, props, None)]);
+        repo.import("run:a", 100, &[("test:a", true, props, None)]);
+
+        assert_eq!(history(&mut repo, "test:a"),
+                   vec![("run:a".to_string(), 100, true),
+                        ("run:b".to_string(), 200, false),
+                        ("run:b".to_string(), 200, true),
+                        ("run:c".to_string(), 300, true)]);
+
+        let test = repo.vert("test:a");
+        for entry in repo.ask(GetTestHistory(test)) {
+            let env: Vec<&str> = entry.environment.iter().map(|v| v.0.as_str()).collect();
+            assert_eq!(env, vec!["environment:arch:x86_64"]);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Result matrices as CSV or XLSX spreadsheets, laid out like the client's
+//! Compare tab: a column for each test and a row for each property.
+
+use std::io::{self, Cursor, Write};
+use std::str::FromStr;
+
+use zip::ZipWriter;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+
+use protocol::{ResultMatrix, VertInfo};
+use export::xml_escape;
+
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+<Default Extension="xml" ContentType="application/xml"/>
+<Override PartName="/xl/workbook.xml"
+ ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>
+<Override PartName="/xl/worksheets/sheet1.xml"
+ ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>
+</Types>"#;
+
+const RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+<Relationship Id="rId1" Target="xl/workbook.xml"
+ Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument"/>
+</Relationships>"#;
+
+const WORKBOOK: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"
+ xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">
+<sheets><sheet name="Results" sheetId="1" r:id="rId1"/></sheets>
+</workbook>"#;
+
+const WORKBOOK_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+<Relationship Id="rId1" Target="worksheets/sheet1.xml"
+ Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet"/>
+</Relationships>"#;
+
+#[derive(Clone, Copy)]
+pub enum SheetFormat {
+    Csv,
+    Xlsx,
+}
+
+impl FromStr for SheetFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SheetFormat, String> {
+        match s {
+            "csv" => Ok(SheetFormat::Csv),
+            "xlsx" => Ok(SheetFormat::Xlsx),
+            _ => Err(format!("Unknown spreadsheet format: {}", s)),
+        }
+    }
+}
+
+impl SheetFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            SheetFormat::Csv => "text/csv",
+            SheetFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            },
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SheetFormat::Csv => "csv",
+            SheetFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// Quote a CSV field if it needs to be. Fields which a spreadsheet would
+/// take for a formula get a `'` in front, so opening a CSV made from test
+/// names can not run anything.
+pub fn csv_field(c: &str) -> String {
+    let c = if c.starts_with(|ch: char| "=+-@\t\r".contains(ch)) {
+        format!("'{}", c)
+    } else {
+        c.to_string()
+    };
+
+    if c.contains(|ch: char| ch == ',' || ch == '"' || ch == '\n' || ch == '\r') {
+        format!("\"{}\"", c.replace('"', "\"\""))
+    } else {
+        c
+    }
+}
+
+/// The counts are numbers, rather than text like 1/2 which spreadsheets
+/// take for a date, so they can be summed
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Cell {
+    Empty,
+    Text(String),
+    Number(u32),
+}
+
+/// The header row, then a row for each property. Each test has a column for
+/// its passes and one for its fails, and a third to mark the flaky ones if
+/// it is flaky anywhere.
+fn rows(matrix: &ResultMatrix) -> Vec<Vec<Cell>> {
+    let mut flaky = vec![false; matrix.test_cases.len()];
+    for r in matrix.results.iter().flat_map(|(_, results)| results) {
+        if let Some(f) = flaky.get_mut(r.test_case as usize) {
+            *f |= r.flaky;
+        }
+    }
+
+    // Where each test's columns start
+    let mut first = Vec::with_capacity(flaky.len());
+    let mut width = 1;
+    for &f in &flaky {
+        first.push(width);
+        width += if f { 3 } else { 2 };
+    }
+
+    let mut header = vec![Cell::Empty; width];
+    for (i, VertInfo(name, _)) in matrix.test_cases.iter().enumerate() {
+        header[first[i]] = Cell::Text(format!("{} passes", name));
+        header[first[i] + 1] = Cell::Text(format!("{} fails", name));
+        if flaky[i] {
+            header[first[i] + 2] = Cell::Text(format!("{} flaky", name));
+        }
+    }
+
+    let mut rows: Vec<Vec<Cell>> = matrix.results.iter()
+        .map(|(VertInfo(prop, _), results)| {
+            let mut row = vec![Cell::Empty; width];
+
+            row[0] = Cell::Text(prop.clone());
+            for r in results {
+                if let Some(&col) = first.get(r.test_case as usize) {
+                    row[col] = Cell::Number(r.passes);
+                    row[col + 1] = Cell::Number(r.fails);
+                    if r.flaky {
+                        row[col + 2] = Cell::Text("flaky".to_string());
+                    }
+                }
+            }
+            row
+        })
+        .collect();
+
+    rows.sort();
+    rows.insert(0, header);
+    rows
+}
+
+fn csv(rows: &[Vec<Cell>]) -> Vec<u8> {
+    let mut out = String::new();
+
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|c| match *c {
+            Cell::Empty => String::new(),
+            Cell::Text(ref text) => csv_field(text),
+            Cell::Number(n) => n.to_string(),
+        }).collect();
+
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+
+    out.into_bytes()
+}
+
+/// A spreadsheet column's letters, 0 is A and 26 is AA
+fn column(mut i: usize) -> String {
+    let mut letters = Vec::new();
+
+    loop {
+        letters.push(b'A' + (i % 26) as u8);
+        if i < 26 {
+            break;
+        }
+        i = i / 26 - 1;
+    }
+    letters.reverse();
+
+    String::from_utf8(letters).unwrap()
+}
+
+fn worksheet(rows: &[Vec<Cell>]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+                                <worksheet xmlns=\"http://schemas.openxmlformats.org/\
+                                spreadsheetml/2006/main\"><sheetData>");
+
+    for (i, row) in rows.iter().enumerate() {
+        xml.push_str(&format!("<row r=\"{}\">", i + 1));
+        for (j, cell) in row.iter().enumerate() {
+            match *cell {
+                Cell::Empty => (),
+                Cell::Text(ref text) => {
+                    xml.push_str(&format!("<c r=\"{}{}\" t=\"inlineStr\"><is><t>{}</t></is></c>",
+                                          column(j), i + 1, xml_escape(text)))
+                },
+                Cell::Number(n) => {
+                    xml.push_str(&format!("<c r=\"{}{}\"><v>{}</v></c>", column(j), i + 1, n))
+                },
+            }
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData></worksheet>");
+
+    xml
+}
+
+fn xlsx(rows: &[Vec<Cell>]) -> io::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let parts = [("[Content_Types].xml", CONTENT_TYPES.to_string()),
+                 ("_rels/.rels", RELS.to_string()),
+                 ("xl/workbook.xml", WORKBOOK.to_string()),
+                 ("xl/_rels/workbook.xml.rels", WORKBOOK_RELS.to_string()),
+                 ("xl/worksheets/sheet1.xml", worksheet(rows))];
+    let zip_err = |e: ZipError| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+    for (name, body) in parts.iter() {
+        zip.start_file(*name, FileOptions::default()).map_err(zip_err)?;
+        zip.write_all(body.as_bytes())?;
+    }
+
+    Ok(zip.finish().map_err(zip_err)?.into_inner())
+}
+
+/// The matrix as a file in `format`
+pub fn write(matrix: &ResultMatrix, format: SheetFormat) -> io::Result<Vec<u8>> {
+    let cells = rows(matrix);
+
+    match format {
+        SheetFormat::Csv => Ok(csv(&cells)),
+        SheetFormat::Xlsx => xlsx(&cells),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use protocol::ResultInMatrix;
+
+    use super::*;
+
+    fn matrix() -> ResultMatrix {
+        let vert = |name: &str| VertInfo(name.to_string(), Uuid::nil());
+        let result = |test_case, passes, fails, flaky| {
+            ResultInMatrix { test_case, passes, fails, flaky }
+        };
+
+        ResultMatrix {
+            test_cases: vec![vert("test:a"), vert("test:b")],
+            results: vec![(vert("sle:15:2"), vec![result(1, 1, 2, true)]),
+                          (vert("sle:15:1"), vec![result(0, 3, 0, false),
+                                                  result(1, 2, 1, false)])],
+        }
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\rbreak"), "\"line\rbreak\"");
+    }
+
+    #[test]
+    fn csv_fields_are_not_formulas() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-x"), "'-x");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+
+    #[test]
+    fn counts_are_separate_numbers() {
+        let csv = String::from_utf8(write(&matrix(), SheetFormat::Csv).unwrap()).unwrap();
+
+        assert_eq!(csv, ",test:a passes,test:a fails,test:b passes,test:b fails,test:b flaky\r\n\
+                         sle:15:1,3,0,2,1,\r\n\
+                         sle:15:2,,,1,2,flaky\r\n");
+    }
+
+    #[test]
+    fn worksheet_counts_are_numbers() {
+        let xml = worksheet(&rows(&matrix()));
+
+        assert!(xml.contains("<c r=\"B2\"><v>3</v></c>"));
+        assert!(xml.contains("<c r=\"F3\" t=\"inlineStr\"><is><t>flaky</t></is></c>"));
+        assert!(!xml.contains("<c r=\"B3\""));
+    }
+
+    #[test]
+    fn names_columns() {
+        assert_eq!(column(0), "A");
+        assert_eq!(column(25), "Z");
+        assert_eq!(column(26), "AA");
+        assert_eq!(column(27 * 26), "AAA");
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::{future, Future};
+use futures::future::Either;
+use actix::prelude::*;
+use actix::actors::signal::{self, ProcessSignals, SignalType};
+use actix_web::server::{Server, StopServer};
+
+use repo::{Repo, Save};
+use imp::{self, Importer, Drain};
+use hub::{Hub, CloseAll};
+
+/// The actors which have to be stopped in order
+#[derive(Message)]
+pub struct Watch {
+    pub repo: Addr<Repo>,
+    pub importer: Addr<Importer>,
+}
+
+#[derive(Message)]
+pub struct WebServer(pub Addr<Server>);
+
+/// Stops the server on SIGINT, SIGTERM or SIGQUIT. Websockets are closed and
+/// HTTP requests finished, then the importer finishes its current file and
+/// the repository is saved. A second signal exits straight away.
+#[derive(Default)]
+pub struct Shutdown {
+    repo: Option<Addr<Repo>>,
+    importer: Option<Addr<Importer>>,
+    server: Option<Addr<Server>>,
+    stopping: bool,
+}
+
+impl Shutdown {
+    fn shut_down(&mut self) {
+        info!("Shutting down");
+        imp::stop();
+        System::current().registry().get::<Hub>().do_send(CloseAll);
+
+        let server = match self.server.take() {
+            Some(srv) => Either::A(srv.send(StopServer { graceful: true }).map(|_| ())),
+            None => Either::B(future::ok(())),
+        };
+        let importer = self.importer.clone();
+        let repo = self.repo.clone();
+
+        Arbiter::spawn(
+            server
+                .and_then(move |_| match importer {
+                    Some(imp) => Either::A(imp.send(Drain)),
+                    None => Either::B(future::ok(())),
+                })
+                .and_then(move |_| match repo {
+                    Some(repo) => Either::A(repo.send(Save)),
+                    None => Either::B(future::ok(Ok(()))),
+                })
+                .then(|res| {
+                    let code = match res {
+                        Ok(Ok(())) => 0,
+                        Ok(Err(e)) => { error!("Could not save the repository: {}", e); 1 },
+                        Err(e) => { error!("Could not stop cleanly: {}", e); 1 },
+                    };
+
+                    info!("Stopped");
+                    System::current().stop_with_code(code);
+                    Ok(())
+                })
+        );
+    }
+}
+
+impl Actor for Shutdown {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        System::current().registry().get::<ProcessSignals>()
+            .do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Supervised for Shutdown {}
+impl SystemService for Shutdown {}
+
+impl Handler<Watch> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _ctx: &mut Self::Context) {
+        self.repo = Some(msg.repo);
+        self.importer = Some(msg.importer);
+    }
+}
+
+impl Handler<WebServer> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: WebServer, _ctx: &mut Self::Context) {
+        self.server = Some(msg.0);
+    }
+}
+
+impl Handler<signal::Signal> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, sig: signal::Signal, _ctx: &mut Self::Context) {
+        match sig.0 {
+            SignalType::Int | SignalType::Term | SignalType::Quit => if self.stopping {
+                warn!("Exiting without waiting for the shut down to finish");
+                System::current().stop_with_code(1);
+            } else {
+                self.stopping = true;
+                self.shut_down();
+            },
+            _ => (),
+        }
+    }
+}
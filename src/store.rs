@@ -0,0 +1,135 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The in-memory datastore is written to a data directory on shutdown and
+//! read back on start up.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use fs2::{self, FileExt};
+use serde_json::{self, Value as JsonValue};
+use uuid::Uuid;
+
+use protocol::AuditEntry;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const LOCK_FILE: &str = "lock";
+
+/// An edge's egress vertex, type and ingress vertex
+pub type SnapEdge = (Uuid, String, Uuid);
+
+/// Everything needed to recreate the repository
+#[derive(Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Vertex IDs and types
+    pub vertices: Vec<(Uuid, String)>,
+    pub edges: Vec<SnapEdge>,
+    /// Metadata name, vertex and value
+    pub vertex_metadata: Vec<(String, Uuid, JsonValue)>,
+    /// Metadata name, edge and value
+    pub edge_metadata: Vec<(String, SnapEdge, JsonValue)>,
+    pub audit: Vec<AuditEntry>,
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+pub fn snapshot_path(dir: &Path) -> PathBuf {
+    dir.join(SNAPSHOT_FILE)
+}
+
+/// An exclusive lock on a data directory, so two processes can not both
+/// write their snapshots to it. The operating system releases it when the
+/// file is closed, even if the process is killed.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Lock the directory, creating it if needed, or fail at once if another
+    /// process has it
+    pub fn take(dir: &Path) -> io::Result<Lock> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(io::Error::new(e.kind(), format!(
+                    "{} is in use by another bug-graph process, PID {}",
+                    dir.display(), pid.trim()
+                )));
+            },
+            Err(e) => return Err(e),
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        Ok(Lock { _file: file })
+    }
+}
+
+/// Read the data directory's snapshot, None if there is not one yet
+pub fn read(dir: &Path) -> io::Result<Option<Snapshot>> {
+    let file = match File::open(snapshot_path(dir)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    serde_json::from_reader(BufReader::new(file)).map(Some).map_err(invalid)
+}
+
+/// Replace the data directory's snapshot, the old one is kept until the new
+/// one has been completely written
+pub fn write(dir: &Path, snap: &Snapshot) -> io::Result<()> {
+    let path = snapshot_path(dir);
+    let tmp = path.with_extension("json.tmp");
+
+    fs::create_dir_all(dir)?;
+    {
+        let mut out = BufWriter::new(File::create(&tmp)?);
+
+        serde_json::to_writer(&mut out, snap).map_err(invalid)?;
+        out.flush()?;
+        out.get_ref().sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn only_one_lock_at_a_time() {
+        let dir = env::temp_dir().join(format!("bug-graph-lock-{}", process::id()));
+        let lock = Lock::take(&dir).unwrap();
+
+        assert!(Lock::take(&dir).is_err());
+        drop(lock);
+        assert!(Lock::take(&dir).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
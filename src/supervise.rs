@@ -0,0 +1,146 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A panic in a handler would take down the actor's whole arbiter, so
+//! handlers run inside `guard` instead. It stops the actor, which its
+//! `Supervisor` then restarts, and the sender gets `MailboxError::Closed`.
+//! The actor keeps its state, including anything the handler changed before
+//! it panicked.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use actix::prelude::*;
+use actix::dev::{MessageResponse, ResponseChannel};
+
+use protocol::Event;
+use hub;
+
+/// A handler's answer, or nothing if it panicked
+pub struct Guarded<M: Message>(Option<M::Result>);
+
+impl<A, M> MessageResponse<A, M> for Guarded<M>
+where
+    A: Actor,
+    M: Message,
+{
+    fn handle<R: ResponseChannel<M>>(self, _ctx: &mut A::Context, tx: Option<R>) {
+        // Dropping tx without sending tells the sender the actor has gone
+        if let (Some(tx), Some(res)) = (tx, self.0) {
+            tx.send(res);
+        }
+    }
+}
+
+fn panic_reason(e: &(Any + Send)) -> String {
+    e.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| e.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown reason".to_string())
+}
+
+/// Run a handler's body, `actor` and `what` say what crashed if it panics
+pub fn guard<A, M, F>(ctx: &mut Context<A>, actor: &str, what: &str, f: F) -> Guarded<M>
+where
+    A: Actor<Context = Context<A>>,
+    M: Message,
+    F: FnOnce() -> MessageResult<M>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(MessageResult(res)) => Guarded(Some(res)),
+        Err(e) => {
+            let reason = format!("{} panicked: {}", what, panic_reason(&*e));
+
+            error!("Restarting the {}, {}", actor, reason);
+            hub::publish(Event::Restart { actor: actor.to_string(), reason });
+            ctx.stop();
+            Guarded(None)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        count: u32,
+        restarts: u32,
+    }
+
+    impl Actor for Counter {
+        type Context = Context<Self>;
+    }
+
+    impl Supervised for Counter {
+        fn restarting(&mut self, _ctx: &mut Self::Context) {
+            self.restarts += 1;
+        }
+    }
+
+    /// Adds one then divides by the number, so zero panics
+    #[derive(Message)]
+    #[rtype(u32)]
+    struct Add(u32);
+
+    #[derive(Message)]
+    #[rtype(result = "(u32, u32)")]
+    struct Get;
+
+    impl Handler<Add> for Counter {
+        type Result = Guarded<Add>;
+
+        fn handle(&mut self, msg: Add, ctx: &mut Self::Context) -> Self::Result {
+            guard(ctx, "counter", "Add", move || {
+                self.count += 1;
+                MessageResult(self.count / msg.0)
+            })
+        }
+    }
+
+    impl Handler<Get> for Counter {
+        type Result = Guarded<Get>;
+
+        fn handle(&mut self, _msg: Get, ctx: &mut Self::Context) -> Self::Result {
+            guard(ctx, "counter", "Get", move || MessageResult((self.count, self.restarts)))
+        }
+    }
+
+    #[test]
+    fn restarts_after_a_panic() {
+        let mut sys = System::new("test");
+        let counter = Supervisor::start(|_| Counter::default());
+
+        assert_eq!(sys.block_on(counter.send(Add(1))).unwrap(), 1);
+        match sys.block_on(counter.send(Add(0))) {
+            Err(MailboxError::Closed) => (),
+            Err(e) => panic!("Expected the mailbox to close, got {}", e),
+            Ok(n) => panic!("Expected the handler to panic, got {}", n),
+        }
+
+        // The panicking handler's write was kept
+        assert_eq!(sys.block_on(counter.send(Get)).unwrap(), (2, 1));
+        assert_eq!(sys.block_on(counter.send(Add(3))).unwrap(), 1);
+    }
+
+    #[test]
+    fn says_why_it_panicked() {
+        let reason = |f: fn()| panic_reason(&*panic::catch_unwind(f).unwrap_err());
+
+        assert_eq!(reason(|| panic!("static")), "static");
+        assert_eq!(reason(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(reason(|| panic::resume_unwind(Box::new(1))), "unknown reason");
+    }
+}
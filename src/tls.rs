@@ -0,0 +1,125 @@
+// Copyright (C) 2018 Richard Palethorpe <richiejp@f-m.fm>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+
+use actix::prelude::*;
+use actix::actors::signal::{self, ProcessSignals, SignalType};
+use rustls::{ServerConfig, NoClientAuth, ResolvesServerCert, SignatureScheme, Certificate,
+             PrivateKey};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use webpki::DNSNameRef;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_pem<T, F>(path: &str, parse: F) -> io::Result<Vec<T>>
+    where F: FnOnce(&mut BufReader<File>) -> Result<Vec<T>, ()>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+
+    parse(&mut reader).map_err(|_| invalid(format!("Could not parse PEM file {}", path)))
+}
+
+/// Read a PKCS8 or RSA private key
+fn read_key(path: &str) -> io::Result<PrivateKey> {
+    let mut keys = read_pem(path, pemfile::pkcs8_private_keys)?;
+
+    if keys.is_empty() {
+        keys = read_pem(path, pemfile::rsa_private_keys)?;
+    }
+
+    keys.into_iter().next().ok_or_else(|| invalid(format!("No private key in {}", path)))
+}
+
+fn read_cert(cert: &str, key: &str) -> io::Result<CertifiedKey> {
+    let chain: Vec<Certificate> = read_pem(cert, pemfile::certs)?;
+    if chain.is_empty() {
+        return Err(invalid(format!("No certificates in {}", cert)));
+    }
+
+    let key = sign::any_supported_type(&read_key(key)?)
+        .map_err(|_| invalid(format!("Unsupported private key type in {}", key)))?;
+
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// Hands out the current certificate, which may be replaced while the
+/// server is running
+pub struct CertResolver {
+    cert: String,
+    key: String,
+    current: RwLock<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn load(cert: String, key: String) -> io::Result<CertResolver> {
+        let current = RwLock::new(read_cert(&cert, &key)?);
+
+        Ok(CertResolver { cert, key, current })
+    }
+
+    /// Read the certificate and key files again, the old certificate is kept
+    /// if they are broken
+    pub fn reload(&self) -> io::Result<()> {
+        let new = read_cert(&self.cert, &self.key)?;
+
+        *self.current.write().unwrap() = new;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _name: Option<DNSNameRef>, _schemes: &[SignatureScheme])
+               -> Option<CertifiedKey> {
+        self.current.read().ok().map(|c| c.clone())
+    }
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+
+    config.cert_resolver = resolver;
+    config
+}
+
+/// Reloads the certificate when the process receives SIGHUP
+pub struct Reloader(pub Arc<CertResolver>);
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        System::current().registry().get::<ProcessSignals>()
+            .do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, sig: signal::Signal, _ctx: &mut Self::Context) {
+        if let SignalType::Hup = sig.0 {
+            match self.0.reload() {
+                Ok(_) => info!("Reloaded TLS certificate from {}", self.0.cert),
+                Err(e) => error!("Keeping the old TLS certificate, reload failed: {}", e),
+            }
+        }
+    }
+}
@@ -13,27 +13,80 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Instant;
+
 use serde_json;
 use actix::prelude::*;
-use actix::fut::{ok, err};
+use actix::dev::ToEnvelope;
+use actix::fut::ok;
 use actix_web::*;
-use actix_web::fs::NamedFile;
-use actix_web::http::Method;
-use failure::Error;
+use actix_web::http::{header, Method};
+use actix_web::middleware::identity::{CookieIdentityPolicy, IdentityService, RequestIdentity};
+use cookie::SameSite;
 
-use repo::{Repo, GetSetVerts, Search, GetResultMatrix};
-use protocol::{ClientServer, ServerClient};
+use repo::{Repo, GetSetVerts, Search, GetResultMatrix, GetFlakyTests, GetRevisionDiff,
+           GetTestHistory, GetFailureClusters, LinkBug, UnlinkBug, RevertAutoLinks,
+           GetAuditTrail, DeleteRun};
+use protocol::{PROTOCOL_VERSION, ClientServer, ServerClient, ClientMsg, ServerMsg, RequestId,
+               ErrorKind, Notice};
+use protocol::wire::Wire;
+use imp::Importer;
+use assets::Assets;
+use api;
+use auth::{self, Auth, RequireUser, User};
+use hub::{self, Hub, Publish, Close};
+use journal::{Journal, GetJournal};
+use metrics::{self, Answered, Connections};
+use graphql::{self, GraphQLExecutor};
 
 pub struct AppState {
-    repo: Addr<Repo>,
+    pub repo: Addr<Repo>,
+    pub graphql: Addr<GraphQLExecutor>,
+    pub importer: Addr<Importer>,
+    pub auth: Arc<Auth>,
+    pub assets: Arc<Assets>,
+}
+
+/// Login form fields
+#[derive(Deserialize)]
+struct Login {
+    user: String,
+    password: String,
 }
 
 struct Ws {
     repo: Addr<Repo>,
+    /// Identifies this connection's subscriptions
+    session: usize,
+    /// Who changes made through this connection are recorded against
+    user: User,
+    /// The protocol version the client said hello with
+    version: Option<u32>,
+    /// How replies are encoded, chosen by the client when it connects
+    wire: Wire,
+    /// The kind of request being handled, which its latency is recorded under
+    query_name: &'static str,
+}
+
+/// Sent to clients which are out of date, usually because the browser cached
+/// an old version of the page.
+fn reload_notice() -> Notice {
+    Notice::error("This page is out of date, please reload it")
 }
 
 impl Ws {
-    fn repo_query<Q, F>(&self, query: Q, err_msg: &'static str,
+    /// Send a reply, or a message the client did not ask for if id is None
+    fn send(&self, ctx: &mut <Self as Actor>::Context, id: Option<RequestId>,
+            reply: ServerClient) {
+        match self.wire.encode(&ServerMsg { id, reply }) {
+            Ok(bin) => ctx.binary(bin),
+            Err(e) => error!("Could not encode websocket reply: {}", e),
+        }
+    }
+
+    fn repo_query<Q, F>(&self, id: RequestId, query: Q, err_msg: &'static str,
                         ctx: &mut <Self as Actor>::Context, resp_fn: F)
     where
         Q: Message + Send + 'static,
@@ -41,47 +94,167 @@ impl Ws {
         Repo: Handler<Q>,
         F: 'static + FnOnce(Q::Result) -> ServerClient
     {
-        let fut = self.repo.send(query).into_actor(self).from_err::<Error>();
+        let repo = self.repo.clone();
 
-        ctx.spawn(fut.and_then(|res, _, ctx| {
-            match serde_json::to_vec(&resp_fn(res)) {
-                Ok(json) => ok(ctx.binary(json)),
-                Err(e) => err(e.into()),
-            }
-        }).then(move |res, _, ctx| {
-            if let Err(e) = res {
-                error!("{}: {}", err_msg, e);
-                ctx.stop();
-            }
+        self.query(&repo, id, query, err_msg, ctx, resp_fn);
+    }
+
+    /// Ask another actor and send the client its answer
+    fn query<A, Q, F>(&self, addr: &Addr<A>, id: RequestId, query: Q, err_msg: &'static str,
+                      ctx: &mut <Self as Actor>::Context, resp_fn: F)
+    where
+        A: Handler<Q>,
+        A::Context: ToEnvelope<A, Q>,
+        Q: Message + Send + 'static,
+        Q::Result: Send,
+        F: 'static + FnOnce(Q::Result) -> ServerClient
+    {
+        let fut = addr.send(query).into_actor(self);
+        let (query_name, start) = (self.query_name, Instant::now());
+
+        ctx.spawn(fut.then(move |res, act, ctx| {
+            metrics::record(Answered { query: query_name, took: start.elapsed() });
+            let reply = match res {
+                Ok(res) => resp_fn(res),
+                Err(e) => {
+                    error!("{}: {}", err_msg, e);
+                    ServerClient::Error {
+                        id: Some(id),
+                        kind: ErrorKind::Internal,
+                        msg: err_msg.to_string(),
+                    }
+                },
+            };
+            act.send(ctx, Some(id), reply);
             ok(())
         }));
     }
 
-    fn handle_client_msg(&self,
-                         msg: Result<ClientServer, serde_json::Error>,
-                         ctx: &mut <Self as Actor>::Context)
-                         -> Result<(), Error>
-    {
-        match msg? {
+    fn handle_client_msg(&mut self, msg: ClientMsg, ctx: &mut <Self as Actor>::Context) {
+        let ClientMsg { id, query } = msg;
+        let role = query.required_role();
+
+        self.query_name = query.name();
+        if !self.user.may(role) {
+            warn!("{} tried {} without the {} role", self.user.name, query, role);
+            let msg = format!("You need the {} role for this", role);
+            let kind = ErrorKind::Forbidden;
+            self.send(ctx, Some(id), ServerClient::Error { id: Some(id), kind, msg });
+            return;
+        }
+
+        match query {
+            ClientServer::Hello { version } => {
+                self.version = Some(version);
+                if version != PROTOCOL_VERSION {
+                    warn!("Client has protocol version {}, we have {}", version, PROTOCOL_VERSION);
+                    self.send(ctx, Some(id), ServerClient::Notify(reload_notice()));
+                }
+            },
             ClientServer::SetQuery(uuid) => {
                 let err = "Could not send set list";
-                self.repo_query(GetSetVerts(uuid), err, ctx, ServerClient::SetList);
+                self.repo_query(id, GetSetVerts(uuid), err, ctx, ServerClient::SetList);
             },
             ClientServer::Search(term) => {
                 let err = "Search failed";
                 let term2 = term.clone();
-                self.repo_query(Search(term), err, ctx, move |res| {
+                self.repo_query(id, Search(term), err, ctx, move |res| {
                     ServerClient::Search(term2, res)
                 });
             },
             ClientServer::ResultMatrix(uuid) => {
                 let err = "Failed to create result matrix";
-                self.repo_query(GetResultMatrix(uuid), err, ctx, move |res| {
+                self.repo_query(id, GetResultMatrix(uuid), err, ctx, move |res| {
                     ServerClient::ResultMatrix(uuid, res)
                 });
             },
+            ClientServer::FlakyTests { scope } => {
+                let err = "Failed to find flaky tests";
+                self.repo_query(id, GetFlakyTests(scope), err, ctx, move |tests| {
+                    ServerClient::FlakyTests { scope, tests }
+                });
+            },
+            ClientServer::RevisionDiff(from, to) => {
+                let err = "Failed to compare revisions";
+                self.repo_query(id, GetRevisionDiff { from, to }, err, ctx, move |res| {
+                    ServerClient::RevisionDiff(from, to, res)
+                });
+            },
+            ClientServer::TestHistory(uuid) => {
+                let err = "Failed to get test history";
+                self.repo_query(id, GetTestHistory(uuid), err, ctx, move |res| {
+                    ServerClient::TestHistory(uuid, res)
+                });
+            },
+            ClientServer::FailureClusters(uuid) => {
+                let err = "Failed to cluster failures";
+                self.repo_query(id, GetFailureClusters(uuid), err, ctx, move |res| {
+                    ServerClient::FailureClusters(uuid, res)
+                });
+            },
+            ClientServer::LinkBug { bug, results } => {
+                let err = "Failed to link bug";
+                let query = LinkBug { bug: bug.clone(), results, user: self.user.name.clone() };
+                self.repo_query(id, query, err, ctx, move |n| {
+                    ServerClient::Notify(Notice::succ(format!("Linked {} results to {}", n, bug)))
+                });
+            },
+            ClientServer::UnlinkBug { bug, results } => {
+                let err = "Failed to unlink bug";
+                let query = UnlinkBug { bug: bug.clone(), results, user: self.user.name.clone() };
+                self.repo_query(id, query, err, ctx, move |n| {
+                    let msg = format!("Unlinked {} results from {}", n, bug);
+                    ServerClient::Notify(Notice::succ(msg))
+                });
+            },
+            ClientServer::RevertAutoLinks { rule } => {
+                let err = "Failed to revert automatic bug links";
+                let query = RevertAutoLinks { rule, user: self.user.name.clone() };
+                self.repo_query(id, query, err, ctx, |n| {
+                    ServerClient::Notify(Notice::succ(format!("Reverted {} automatic links", n)))
+                });
+            },
+            ClientServer::AuditTrail => {
+                let err = "Failed to get audit trail";
+                self.repo_query(id, GetAuditTrail, err, ctx, ServerClient::AuditTrail);
+            },
+            ClientServer::Subscribe(topic) => {
+                System::current().registry().get::<Hub>().do_send(hub::Subscribe {
+                    session: self.session,
+                    topic: topic.clone(),
+                    client: ctx.address().recipient(),
+                });
+                self.send(ctx, Some(id), ServerClient::Subscribed(topic));
+            },
+            ClientServer::DeleteRun(run) => {
+                let err = "Failed to delete run";
+                let query = DeleteRun { run, user: self.user.name.clone() };
+                self.repo_query(id, query, err, ctx, move |n| match n {
+                    Some(n) => {
+                        let msg = format!("Deleted the run and its {} results", n);
+                        ServerClient::Notify(Notice::succ(msg))
+                    },
+                    None => ServerClient::Error {
+                        id: Some(id),
+                        kind: ErrorKind::BadRequest,
+                        msg: format!("No such run: {}", run),
+                    },
+                });
+            },
+            ClientServer::GetJournal { since, level } => {
+                let err = "Failed to get the server's log";
+                let journal = System::current().registry().get::<Journal>();
+                self.query(&journal, id, GetJournal { since, level }, err, ctx,
+                           ServerClient::Journal);
+            },
+            ClientServer::Unsubscribe(topic) => {
+                System::current().registry().get::<Hub>().do_send(hub::Unsubscribe {
+                    session: self.session,
+                    topic: topic.clone(),
+                });
+                self.send(ctx, Some(id), ServerClient::Unsubscribed(topic));
+            },
         }
-        Ok(())
     }
 }
 
@@ -89,16 +262,50 @@ impl Actor for Ws {
     type Context = ws::WebsocketContext<Self, AppState>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let hello = serde_json::to_vec(
-            &ServerClient::info_notice("Hello from WS server!")
-        ).expect("Creating static hello message");
-        ctx.binary(hello);
+        let hello = ServerClient::Hello {
+            version: PROTOCOL_VERSION,
+            user: self.user.name.clone(),
+            role: self.user.role,
+        };
+
+        self.send(ctx, None, hello);
+        metrics::record(Connections(1));
+        System::current().registry().get::<Hub>().do_send(hub::Connect {
+            session: self.session,
+            client: ctx.address().recipient(),
+        });
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        metrics::record(Connections(-1));
+        System::current().registry().get::<Hub>().do_send(hub::Disconnect(self.session));
+        Running::Stop
+    }
+}
+
+impl Handler<Publish> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, ctx: &mut Self::Context) {
+        self.send(ctx, None, ServerClient::Event(msg.0));
+    }
+}
+
+impl Handler<Close> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Close, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("The server is shutting down".to_string()),
+        }));
+        ctx.stop();
     }
 }
 
 impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
-        let msg: Result<ClientServer, _> = match msg {
+        let msg: Result<ClientMsg, _> = match msg {
             ws::Message::Text(text) => {
                 info!("Websocket received: {}", text);
                 serde_json::from_str(&text)
@@ -113,31 +320,119 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
                 return;
             }
         };
-        if let Err(e) = self.handle_client_msg(msg, ctx) {
-            error!("Could not handle client websocket request: {}", e);
-            ctx.stop();
+
+        match msg {
+            Ok(msg) => self.handle_client_msg(msg, ctx),
+            Err(e) => {
+                warn!("Could not parse client websocket request: {}", e);
+                if self.version != Some(PROTOCOL_VERSION) {
+                    self.send(ctx, None, ServerClient::Notify(reload_notice()));
+                }
+                self.send(ctx, None, ServerClient::Error {
+                    id: None,
+                    kind: ErrorKind::BadRequest,
+                    msg: format!("Could not parse request: {}", e),
+                });
+            },
         }
     }
 }
 
-fn index(_req: &HttpRequest<AppState>) -> Result<NamedFile> {
-    Ok(NamedFile::open("res/static/index.html")?)
+fn asset(req: &HttpRequest<AppState>, name: &str) -> Result<HttpResponse> {
+    let assets = &req.state().assets;
+    let asset = assets.get(name)?.ok_or_else(|| error::ErrorNotFound(name.to_string()))?;
+    let fresh = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag == asset.etag
+        }));
+
+    if fresh {
+        return Ok(HttpResponse::NotModified()
+                  .header(header::ETAG, asset.etag)
+                  .header(header::CACHE_CONTROL, assets.cache_control())
+                  .finish());
+    }
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(asset.content_type)
+        .header(header::ETAG, asset.etag)
+        .header(header::CACHE_CONTROL, assets.cache_control());
+
+    Ok(match asset.body {
+        Cow::Borrowed(body) => resp.body(body),
+        Cow::Owned(body) => resp.body(body),
+    })
+}
+
+fn index(req: &HttpRequest<AppState>) -> Result<HttpResponse> {
+    asset(req, "index.html")
 }
 
 fn ws_index(req: &HttpRequest<AppState>) -> Result<HttpResponse> {
+    if !auth::same_origin(req) {
+        warn!("Refused a websocket from another site: {:?}", req.headers().get(header::ORIGIN));
+        return Err(error::ErrorForbidden("Websockets may only be opened by our own pages"));
+    }
+
     let repo = req.state().repo.clone();
-    ws::start(&req, Ws { repo })
+    let wire = Wire::from_query(req.query_string()).map_err(error::ErrorBadRequest)?;
+    let user = auth::user(req);
+
+    info!("Websocket connection for {} ({}) with {} encoding, deflate {}",
+          user.name, user.role, wire.encoding.name(), wire.deflate);
+    let session = hub::new_session();
+
+    ws::start(&req, Ws { repo, session, user, version: None, wire, query_name: "" })
 }
 
-fn static_file(file: Path<String>) -> Result<NamedFile> {
-    Ok(NamedFile::open(format!("res/static/{}", *file))?)
+fn login_page(req: &HttpRequest<AppState>) -> Result<HttpResponse> {
+    asset(req, "login.html")
 }
 
-pub fn new(repo: Addr<Repo>) -> App<AppState>
+fn redirect(to: &str) -> HttpResponse {
+    HttpResponse::SeeOther().header(header::LOCATION, to).finish()
+}
+
+fn login((form, req): (Form<Login>, HttpRequest<AppState>)) -> HttpResponse {
+    if req.state().auth.login(&form.user, &form.password) {
+        info!("{} logged in", form.user);
+        req.remember(form.user.clone());
+        redirect("/")
+    } else {
+        warn!("Failed login attempt for {}", form.user);
+        redirect("/login.html?failed")
+    }
+}
+
+fn logout(req: &HttpRequest<AppState>) -> HttpResponse {
+    req.forget();
+    redirect("/login.html")
+}
+
+fn static_file((file, req): (Path<String>, HttpRequest<AppState>)) -> Result<HttpResponse> {
+    asset(&req, &file)
+}
+
+/// Set `secure` when serving HTTPS so the login cookie is never sent in the clear
+pub fn new(repo: Addr<Repo>, graphql: Addr<GraphQLExecutor>, importer: Addr<Importer>,
+           auth: Arc<Auth>, assets: Arc<Assets>, secure: bool) -> App<AppState>
 {
-    App::with_state(AppState{ repo })
+    let identity = CookieIdentityPolicy::new(auth.cookie_key())
+        .name("bug-graph-auth")
+        .same_site(SameSite::Strict)
+        .secure(secure);
+    let app = App::with_state(AppState{ repo, graphql, importer, auth, assets })
+        .middleware(IdentityService::new(identity))
+        .middleware(RequireUser);
+
+    metrics::register(graphql::register(api::register(app)))
         .resource("/", |r| r.method(Method::GET).f(index))
         .resource("/ws/", |r| r.f(ws_index))
+        .resource("/login.html", |r| r.method(Method::GET).f(login_page))
+        .resource("/login", |r| r.method(Method::POST).with(login))
+        .resource("/logout", |r| r.f(logout))
         // For now non capture groups (?: ...) confuse the actix-web parser
         // and numbered capture groups confuse the router because they produce
         // surplus matches